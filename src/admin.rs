@@ -0,0 +1,187 @@
+use crate::settings::Settings;
+use crate::{print_event, SnoopContext};
+use hyper::{Body, Request, Response};
+use serde_json::Value;
+use tokio::sync::watch;
+
+type CallResult = Result<Value, (i32, String)>;
+
+// Holds new requests while the proxy is paused from the admin api
+#[derive(Debug)]
+pub struct Pause {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Pause {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self { sender, receiver }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    fn set(&self, paused: bool) {
+        // the receiver kept in `self` means sending can't fail
+        let _ = self.sender.send(paused);
+    }
+
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while *receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn string_param<'a>(
+    params: &'a [Value],
+    index: usize,
+    name: &str,
+) -> Result<&'a str, (i32, String)> {
+    params.get(index).and_then(Value::as_str).ok_or_else(|| {
+        (
+            -32602,
+            format!("Expected {} as string parameter {}", name, index),
+        )
+    })
+}
+
+fn u32_param(params: &[Value], index: usize, name: &str) -> Result<u32, (i32, String)> {
+    params
+        .get(index)
+        .and_then(Value::as_u64)
+        .and_then(|value| u32::try_from(value).ok())
+        .ok_or_else(|| {
+            (
+                -32602,
+                format!("Expected {} as numeric parameter {}", name, index),
+            )
+        })
+}
+
+// Applies `change` to a copy of the live settings and swaps it in, printing
+// what changed
+fn update<T>(
+    method: &str,
+    context: &SnoopContext,
+    change: impl FnOnce(&mut Settings) -> Result<T, String>,
+) -> Result<T, (i32, String)> {
+    let (result, changes) = context.update_settings(change).map_err(|e| (-32602, e))?;
+    if !changes.is_empty() {
+        print_event(
+            &format!("ADMIN {}\n{}", method, changes.join("\n")),
+            context,
+        );
+    }
+    Ok(result)
+}
+
+fn call(method: &str, params: &[Value], context: &SnoopContext) -> CallResult {
+    match method {
+        "snoop_status" => Ok(serde_json::json!({
+            "paused": context.inner.pause.is_paused(),
+            "settings": context.settings().to_json(),
+        })),
+        "snoop_addSuppression" => {
+            let kind = string_param(params, 0, "\"method\" or \"path\"")?;
+            let rule = string_param(params, 1, "rule")?;
+            update(method, context, |settings| {
+                settings.add_suppression(kind, rule)
+            })?;
+            Ok(Value::Bool(true))
+        }
+        "snoop_removeSuppression" => {
+            let kind = string_param(params, 0, "\"method\" or \"path\"")?;
+            let rule = string_param(params, 1, "rule")?;
+            let removed = update(method, context, |settings| {
+                settings.remove_suppression(kind, rule)
+            })?;
+            Ok(Value::from(removed))
+        }
+        "snoop_setDropRate" => {
+            let direction = string_param(params, 0, "\"request\" or \"response\"")?;
+            let rate = u32_param(params, 1, "rate")?;
+            update(method, context, |settings| {
+                settings.set_drop_rate(direction, rate)
+            })?;
+            Ok(Value::Bool(true))
+        }
+        "snoop_pause" | "snoop_resume" => {
+            let paused = method == "snoop_pause";
+            if context.inner.pause.is_paused() != paused {
+                context.inner.pause.set(paused);
+                print_event(
+                    if paused {
+                        "ADMIN paused, holding new requests"
+                    } else {
+                        "ADMIN resumed"
+                    },
+                    context,
+                );
+            }
+            Ok(Value::Bool(true))
+        }
+        _ => Err((-32601, format!("Method {} not found", method))),
+    }
+}
+
+fn handle_call(request: &Value, context: &SnoopContext) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let result = match request.get("method").and_then(Value::as_str) {
+        Some(method) => {
+            let params = match request.get("params") {
+                Some(Value::Array(params)) => params.as_slice(),
+                _ => &[],
+            };
+            call(method, params, context)
+        }
+        None => Err((-32600, "Invalid request".to_string())),
+    };
+    match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+pub async fn handle_request(
+    context: SnoopContext,
+    request: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let response = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Array(requests)) => requests
+                .iter()
+                .map(|request| handle_call(request, &context))
+                .collect(),
+            Ok(request) => handle_call(&request, &context),
+            Err(e) => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": format!("Parse error: {}", e) },
+            }),
+        },
+        Err(e) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": -32603, "message": format!("Unable to read request: {}", e) },
+        }),
+    };
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(response.to_string()))
+}
@@ -0,0 +1,456 @@
+use crate::utils::{self, Pattern, RpcBody, RpcRequest};
+use hyper::{Body, Response, StatusCode};
+use rand::Rng;
+use std::time::Duration;
+
+// Which exchanges a chaos rule applies to
+#[derive(Clone, Debug)]
+pub enum Target {
+    Any,
+    Method(Pattern),
+    Path(Pattern),
+}
+
+impl Target {
+    // paths start with `/`, anything else is a method
+    fn parse(s: &str) -> Result<Self, String> {
+        let pattern = utils::parse_pattern(s)?;
+        if s.starts_with('/') || s.starts_with("re:/") || s.starts_with("re:^/") {
+            Ok(Target::Path(pattern))
+        } else {
+            Ok(Target::Method(pattern))
+        }
+    }
+
+    // a batch matches when any of its calls does
+    pub fn matches(&self, rpc_body: &RpcBody, request_path: &str) -> bool {
+        match rpc_body {
+            RpcBody::Single(call) => self.matches_call(call, request_path),
+            RpcBody::Batch(calls) => calls
+                .iter()
+                .any(|(_, call)| self.matches_call(call, request_path)),
+        }
+    }
+
+    // elements that aren't calls have no method to match
+    pub fn matches_call(&self, call: &Option<RpcRequest>, request_path: &str) -> bool {
+        match self {
+            Target::Any => true,
+            Target::Method(pattern) => call
+                .as_ref()
+                .is_some_and(|call| pattern.matches(&call.method)),
+            Target::Path(pattern) => {
+                pattern.matches(request_path.split('?').next().unwrap_or_default())
+            }
+        }
+    }
+}
+
+// Splits `[TARGET=]VALUE`. The value never contains `=` but a regex target
+// may, so split at the last one.
+fn split_target(arg: &str) -> Result<(Target, &str), String> {
+    match arg.rsplit_once('=') {
+        Some((target, value)) => Ok((Target::parse(target)?, value)),
+        None => Ok((Target::Any, arg)),
+    }
+}
+
+// `250ms`, `1.5s` or a plain number of milliseconds
+fn parse_ms(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let (number, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 1.0)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1000.0)
+    } else {
+        (s, 1.0)
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)
+        .map(|n| n * scale)
+        .ok_or_else(|| format!("Unable to parse '{}' as a duration", s))
+}
+
+const MAX_DELAY_MS: f64 = 60.0 * 60.0 * 1000.0;
+
+// How long an injected delay lasts, in milliseconds
+#[derive(Clone, Debug)]
+pub enum Distribution {
+    Fixed(f64),
+    Uniform(f64, f64),
+    Normal { mean: f64, std_dev: f64 },
+    // long tailed: at least `scale`, with a smaller `shape` making very long
+    // delays more likely
+    Pareto { scale: f64, shape: f64 },
+}
+
+impl Distribution {
+    fn parse(s: &str) -> Result<Self, String> {
+        let args = |name: &str| -> Option<(&str, &str)> {
+            s.strip_prefix(name)?
+                .strip_prefix('(')?
+                .strip_suffix(')')?
+                .split_once(',')
+        };
+        if let Some((mean, std_dev)) = args("normal") {
+            Ok(Distribution::Normal {
+                mean: parse_ms(mean)?,
+                std_dev: parse_ms(std_dev)?,
+            })
+        } else if let Some((scale, shape)) = args("pareto") {
+            let shape = shape
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|shape| *shape > 0.0)
+                .ok_or_else(|| format!("Unable to parse '{}' as a pareto shape", shape))?;
+            Ok(Distribution::Pareto {
+                scale: parse_ms(scale)?,
+                shape,
+            })
+        } else if let Some((low, high)) = s.split_once("..") {
+            let (low, high) = (parse_ms(low)?, parse_ms(high)?);
+            if low > high {
+                return Err(format!("Empty delay range '{}'", s));
+            }
+            Ok(Distribution::Uniform(low, high))
+        } else {
+            parse_ms(s).map(Distribution::Fixed)
+        }
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        let ms = match *self {
+            Distribution::Fixed(ms) => ms,
+            Distribution::Uniform(low, high) => rng.gen_range(low..=high),
+            Distribution::Normal { mean, std_dev } => {
+                // Box-Muller, with u1 in (0, 1] so its log is finite
+                let u1 = 1.0 - rng.gen::<f64>();
+                let u2 = rng.gen::<f64>();
+                mean + std_dev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            }
+            Distribution::Pareto { scale, shape } => {
+                scale / (1.0 - rng.gen::<f64>()).powf(1.0 / shape)
+            }
+        };
+        // a long tail can reach absurd (even infinite) values
+        Duration::from_secs_f64(ms.clamp(0.0, MAX_DELAY_MS) / 1000.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DelayRule {
+    pub target: Target,
+    pub distribution: Distribution,
+}
+
+pub fn parse_delay(arg: &str) -> Result<DelayRule, String> {
+    let (target, distribution) = split_target(arg)?;
+    Ok(DelayRule {
+        target,
+        distribution: Distribution::parse(distribution)?,
+    })
+}
+
+// the first rule that applies, like suppression rules
+pub fn delay_rule<'a>(
+    rules: &'a Option<Vec<DelayRule>>,
+    rpc_body: &RpcBody,
+    request_path: &str,
+) -> Option<&'a DelayRule> {
+    rules
+        .iter()
+        .flatten()
+        .find(|rule| rule.target.matches(rpc_body, request_path))
+}
+
+// What an `--inject-error` rule answers with instead of forwarding
+#[derive(Clone, Debug)]
+pub enum Fault {
+    RpcError { code: i64, message: String },
+    Status { status: StatusCode, body: String },
+}
+
+impl Fault {
+    // A JSON-RPC error carries the id of every call it answers, so clients
+    // can match it up like a real one. Notifications get no answer at all.
+    pub fn response(&self, rpc_body: &RpcBody) -> (Response<Body>, String) {
+        let (status, content_type, body) = match self {
+            Fault::RpcError { .. } if !rpc_body.expects_response() => {
+                (StatusCode::OK, "application/json", String::new())
+            }
+            Fault::RpcError { .. } => {
+                let json = match rpc_body {
+                    RpcBody::Batch(calls) => calls
+                        .iter()
+                        .filter(|(_, call)| !matches!(call, Some(call) if call.is_notification()))
+                        .map(|element| self.call_response(element))
+                        .collect(),
+                    RpcBody::Single(_) => self.error(serde_json::json!(rpc_body.id())),
+                };
+                let body = serde_json::to_string_pretty(&json).unwrap_or_else(|_| json.to_string());
+                (StatusCode::OK, "application/json", body)
+            }
+            Fault::Status { status, body } => (*status, "text/plain", body.clone()),
+        };
+        let response = Response::builder()
+            .status(status)
+            .header("content-type", content_type)
+            .body(Body::from(body.clone()))
+            .unwrap();
+        (response, body)
+    }
+
+    // The answer to one call of a batch. The batch as a whole keeps its http
+    // status, so a status fault becomes a JSON-RPC error too.
+    pub fn call_response(
+        &self,
+        element: &(serde_json::Value, Option<RpcRequest>),
+    ) -> serde_json::Value {
+        self.error(element.0.get("id").cloned().unwrap_or_default())
+    }
+
+    fn error(&self, id: serde_json::Value) -> serde_json::Value {
+        let (code, message) = match self {
+            Fault::RpcError { code, message } => (*code, message.clone()),
+            Fault::Status { status, .. } => (-32603, format!("injected status {}", status)),
+        };
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ErrorRule {
+    pub target: Target,
+    // odds of firing, in [0..1]
+    pub rate: f64,
+    pub fault: Fault,
+}
+
+// Splits `[TARGET:]RATE%:REST` into the target, the rate in [0..1] and the
+// colon separated parts of REST
+pub fn split_rate(arg: &str) -> Result<(Target, f64, Vec<&str>), String> {
+    let parts = arg.split(':').collect::<Vec<_>>();
+    // a regex target may contain colons itself, so look for the rate
+    let position = parts
+        .iter()
+        .position(|part| part.ends_with('%'))
+        .ok_or_else(|| format!("No RATE% in '{}'", arg))?;
+    let target = match parts[..position].join(":") {
+        target if target.is_empty() => Target::Any,
+        target => Target::parse(&target)?,
+    };
+    let rate = parts[position]
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=100.0).contains(rate))
+        .ok_or_else(|| format!("Unable to parse '{}' as a rate [0..100]%", parts[position]))?;
+    Ok((target, rate / 100.0, parts[position + 1..].to_vec()))
+}
+
+// `[TARGET:]RATE%:CODE[:MESSAGE]`, where a CODE from 400 to 599 is answered
+// as that http status and anything else as a JSON-RPC error
+pub fn parse_error(arg: &str) -> Result<ErrorRule, String> {
+    let usage = || {
+        format!(
+            "Unable to parse '{}' as '[TARGET:]RATE%:CODE[:MESSAGE]'",
+            arg
+        )
+    };
+    let (target, rate, parts) = split_rate(arg)?;
+    let code = parts
+        .first()
+        .ok_or_else(usage)?
+        .parse::<i64>()
+        .map_err(|_| usage())?;
+    let message = Some(parts[1..].join(":")).filter(|message| !message.is_empty());
+
+    let fault = match code {
+        400..=599 => {
+            let status = StatusCode::from_u16(code as u16).map_err(|e| e.to_string())?;
+            Fault::Status {
+                status,
+                body: message
+                    .or_else(|| status.canonical_reason().map(str::to_string))
+                    .unwrap_or_default(),
+            }
+        }
+        code => Fault::RpcError {
+            code,
+            message: message.unwrap_or_else(|| "injected error".to_string()),
+        },
+    };
+    Ok(ErrorRule {
+        target,
+        rate,
+        fault,
+    })
+}
+
+// Rolls every rule that applies, in order, and returns the first one that
+// fires. Its fault only answers the calls its target matches.
+pub fn injected_fault<'a, R: Rng>(
+    rules: &'a [ErrorRule],
+    rpc_body: &RpcBody,
+    request_path: &str,
+    rng: &mut R,
+) -> Option<&'a ErrorRule> {
+    rules
+        .iter()
+        .filter(|rule| rule.target.matches(rpc_body, request_path))
+        .find(|rule| rng.gen::<f64>() < rule.rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn distribution(arg: &str) -> Distribution {
+        parse_delay(arg).unwrap().distribution
+    }
+
+    #[test]
+    fn parses_fixed_delays() {
+        assert!(matches!(distribution("250ms"), Distribution::Fixed(ms) if ms == 250.0));
+        assert!(matches!(distribution("1.5s"), Distribution::Fixed(ms) if ms == 1500.0));
+        assert!(matches!(distribution("40"), Distribution::Fixed(ms) if ms == 40.0));
+    }
+
+    #[test]
+    fn parses_delay_distributions() {
+        assert!(matches!(
+            distribution("100ms..2s"),
+            Distribution::Uniform(low, high) if low == 100.0 && high == 2000.0
+        ));
+        assert!(matches!(
+            distribution("normal(500ms,100ms)"),
+            Distribution::Normal { mean, std_dev } if mean == 500.0 && std_dev == 100.0
+        ));
+        assert!(matches!(
+            distribution("pareto(100ms,1.5)"),
+            Distribution::Pareto { scale, shape } if scale == 100.0 && shape == 1.5
+        ));
+    }
+
+    #[test]
+    fn parses_delay_targets() {
+        let rule = parse_delay("eth_getLogs=100ms..2s").unwrap();
+        assert!(matches!(rule.target, Target::Method(_)));
+        assert!(matches!(rule.distribution, Distribution::Uniform(..)));
+        assert!(matches!(
+            parse_delay("/ws=1s").unwrap().target,
+            Target::Path(_)
+        ));
+        assert!(matches!(parse_delay("1s").unwrap().target, Target::Any));
+    }
+
+    #[test]
+    fn rejects_bad_delays() {
+        assert!(parse_delay("2s..100ms").is_err());
+        assert!(parse_delay("100ms..abc").is_err());
+        assert!(parse_delay("pareto(100ms,0)").is_err());
+        assert!(parse_delay("normal(500ms)").is_err());
+        assert!(parse_delay("-5ms").is_err());
+        assert!(parse_delay("abc").is_err());
+    }
+
+    #[test]
+    fn splits_rates() {
+        let (target, rate, rest) = split_rate("eth_call:5%:-32000:a:b").unwrap();
+        assert!(matches!(target, Target::Method(_)));
+        assert_eq!(rate, 0.05);
+        assert_eq!(rest, vec!["-32000", "a", "b"]);
+        let (target, rate, rest) = split_rate("100%:503").unwrap();
+        assert!(matches!(target, Target::Any));
+        assert_eq!(rate, 1.0);
+        assert_eq!(rest, vec!["503"]);
+        // a regex target may contain colons of its own
+        let (target, _, rest) = split_rate("re:^eth_(call|get.*)$:1%:-32000").unwrap();
+        assert!(matches!(target, Target::Method(_)));
+        assert_eq!(rest, vec!["-32000"]);
+        assert!(matches!(
+            split_rate("/eth/v1/*:2%:503").unwrap().0,
+            Target::Path(_)
+        ));
+        assert!(split_rate("eth_call:-32000").is_err());
+        assert!(split_rate("101%:503").is_err());
+        assert!(split_rate("x%:503").is_err());
+    }
+
+    #[test]
+    fn parses_errors() {
+        let rule = parse_error("eth_call:5%:-32000:header not found: 0x1").unwrap();
+        assert!(matches!(
+            rule.fault,
+            Fault::RpcError { code: -32000, message } if message == "header not found: 0x1"
+        ));
+        let rule = parse_error("1%:-32000").unwrap();
+        assert!(matches!(
+            rule.fault,
+            Fault::RpcError { message, .. } if message == "injected error"
+        ));
+        let rule = parse_error("1%:503").unwrap();
+        assert!(matches!(
+            rule.fault,
+            Fault::Status { status: StatusCode::SERVICE_UNAVAILABLE, body } if body == "Service Unavailable"
+        ));
+        let rule = parse_error("1%:429:slow down").unwrap();
+        assert!(matches!(
+            rule.fault,
+            Fault::Status { status: StatusCode::TOO_MANY_REQUESTS, body } if body == "slow down"
+        ));
+        assert!(parse_error("1%").is_err());
+        assert!(parse_error("1%:abc").is_err());
+    }
+
+    #[test]
+    fn matches_calls_by_method() {
+        let batch = RpcBody::parse(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"foo"},{"jsonrpc":"2.0","id":2,"method":"bar"}]"#,
+        );
+        let RpcBody::Batch(calls) = &batch else {
+            panic!("expected a batch");
+        };
+        let target = Target::parse("foo").unwrap();
+        assert!(target.matches(&batch, "/"));
+        assert!(target.matches_call(&calls[0].1, "/"));
+        assert!(!target.matches_call(&calls[1].1, "/"));
+        assert!(!Target::parse("baz").unwrap().matches(&batch, "/"));
+    }
+
+    #[test]
+    fn answers_notifications_with_nothing() {
+        let fault = parse_error("100%:-32000").unwrap().fault;
+        let (_, body) = fault.response(&RpcBody::parse(r#"{"jsonrpc":"2.0","method":"foo"}"#));
+        assert_eq!(body, "");
+        let (_, body) = fault.response(&RpcBody::parse(r#"[{"jsonrpc":"2.0","method":"foo"}]"#));
+        assert_eq!(body, "");
+        let (_, body) = fault.response(&RpcBody::parse(
+            r#"[{"jsonrpc":"2.0","method":"foo"},{"jsonrpc":"2.0","id":7,"method":"foo"}]"#,
+        ));
+        let json = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(json.as_array().map(Vec::len), Some(1));
+        assert_eq!(json[0]["id"], 7);
+        assert_eq!(json[0]["error"]["code"], -32000);
+    }
+
+    #[test]
+    fn samples_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..1000 {
+            let uniform = distribution("100ms..2s").sample(&mut rng);
+            assert!(uniform >= Duration::from_millis(100) && uniform <= Duration::from_secs(2));
+            let pareto = distribution("pareto(100ms,1.5)").sample(&mut rng);
+            assert!(pareto >= Duration::from_millis(100));
+            assert!(pareto <= Duration::from_secs(60 * 60));
+        }
+    }
+}
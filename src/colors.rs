@@ -2,11 +2,13 @@ use lazy_static::lazy_static;
 use termion::color;
 
 lazy_static! {
+    pub static ref RESET: String = color::Fg(color::Reset).to_string();
     pub static ref RESET_NEWLINE: String = format!("{}\n", color::Fg(color::Reset));
     pub static ref CYAN: String = color::Fg(color::Cyan).to_string();
     pub static ref RED: String = color::Fg(color::Red).to_string();
     pub static ref GREEN: String = color::Fg(color::Green).to_string();
     pub static ref WHITE: String = color::Fg(color::White).to_string();
+    pub static ref YELLOW: String = color::Fg(color::Yellow).to_string();
     pub static ref EMPTY: String = String::new();
 }
 
@@ -16,6 +18,8 @@ pub struct Colors {
     pub cyan: &'static str,
     pub green: &'static str,
     pub white: &'static str,
+    pub yellow: &'static str,
+    pub reset: &'static str,
 }
 
 impl Colors {
@@ -26,6 +30,8 @@ impl Colors {
                 cyan: (*EMPTY).as_str(),
                 green: (*EMPTY).as_str(),
                 white: (*EMPTY).as_str(),
+                yellow: (*EMPTY).as_str(),
+                reset: (*EMPTY).as_str(),
             }
         } else {
             Self {
@@ -33,6 +39,8 @@ impl Colors {
                 cyan: (*CYAN).as_str(),
                 green: (*GREEN).as_str(),
                 white: (*WHITE).as_str(),
+                yellow: (*YELLOW).as_str(),
+                reset: (*RESET).as_str(),
             }
         }
     }
@@ -49,7 +57,7 @@ pub fn color_treat(multi_line_string: String, color: &str) -> String {
     }
     let mut result = String::new();
     for line in multi_line_string.split("\n") {
-        result.push_str(&color);
+        result.push_str(color);
         result.push_str(line);
         result.push_str((*RESET_NEWLINE).as_str());
     }
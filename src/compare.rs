@@ -0,0 +1,444 @@
+use crate::upstream::Upstream;
+use crate::utils::{glob_match, RpcBody, SnoopError};
+use crate::{
+    copy_request, get_response, is_rpc_modules_request, print_event, SnoopContext, SourceRequest,
+};
+use hyper::StatusCode;
+use serde_json::Value;
+
+// A second endpoint every request is mirrored to so its answers can be
+// checked against the primary's
+#[derive(Debug)]
+pub struct Comparison {
+    pub upstream: Upstream,
+    // object keys (with `*` and `?` wildcards) left out of the diff
+    ignore: Vec<String>,
+}
+
+// One place where the two responses disagree, `None` meaning the value is
+// missing on that side
+struct Difference {
+    path: String,
+    primary: Option<Value>,
+    comparison: Option<Value>,
+}
+
+fn parse(response_json: &str) -> Value {
+    serde_json::from_str(response_json).unwrap_or_else(|_| Value::String(response_json.to_string()))
+}
+
+// Leaves out the answers to the calls of a batch that `--rpc-modules-override`
+// answered locally, the comparison endpoint's own answers to them aren't news
+fn without_overridden(rpc_body: &RpcBody, response: Value, overridden: bool) -> Value {
+    let calls = match rpc_body {
+        RpcBody::Batch(calls) if overridden => calls,
+        _ => return response,
+    };
+    let ids = calls
+        .iter()
+        .filter(|(_, call)| is_rpc_modules_request(call))
+        .filter_map(|(element, _)| element.get("id"))
+        .collect::<Vec<_>>();
+    match response {
+        Value::Array(elements) => Value::Array(
+            elements
+                .into_iter()
+                .filter(|element| !matches!(element.get("id"), Some(id) if ids.contains(&id)))
+                .collect(),
+        ),
+        response => response,
+    }
+}
+
+fn id_label(element: &Value) -> String {
+    format!("[id {}]", element.get("id").unwrap_or(&Value::Null))
+}
+
+impl Comparison {
+    pub fn new(upstream: Upstream, ignore: Vec<String>) -> Self {
+        Self { upstream, ignore }
+    }
+
+    fn is_ignored(&self, key: &str) -> bool {
+        self.ignore.iter().any(|pattern| glob_match(pattern, key))
+    }
+
+    fn diff(&self, path: &str, primary: &Value, comparison: &Value, diffs: &mut Vec<Difference>) {
+        match (primary, comparison) {
+            (Value::Object(primary), Value::Object(comparison)) => {
+                let keys = primary
+                    .keys()
+                    .chain(comparison.keys().filter(|key| !primary.contains_key(*key)));
+                for key in keys.filter(|key| !self.is_ignored(key)) {
+                    let path = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    match (primary.get(key), comparison.get(key)) {
+                        (Some(primary), Some(comparison)) => {
+                            self.diff(&path, primary, comparison, diffs)
+                        }
+                        (primary, comparison) => diffs.push(Difference {
+                            path,
+                            primary: primary.cloned(),
+                            comparison: comparison.cloned(),
+                        }),
+                    }
+                }
+            }
+            (Value::Array(primary), Value::Array(comparison)) => {
+                for i in 0..primary.len().max(comparison.len()) {
+                    let path = format!("{}[{}]", path, i);
+                    match (primary.get(i), comparison.get(i)) {
+                        (Some(primary), Some(comparison)) => {
+                            self.diff(&path, primary, comparison, diffs)
+                        }
+                        (primary, comparison) => diffs.push(Difference {
+                            path,
+                            primary: primary.cloned(),
+                            comparison: comparison.cloned(),
+                        }),
+                    }
+                }
+            }
+            (primary, comparison) if primary != comparison => diffs.push(Difference {
+                path: path.to_string(),
+                primary: Some(primary.clone()),
+                comparison: Some(comparison.clone()),
+            }),
+            _ => {}
+        }
+    }
+
+    // Batch responses may come back in any order, so their elements are
+    // paired up by id rather than by position
+    fn diff_responses(
+        &self,
+        rpc_body: &RpcBody,
+        primary: &Value,
+        comparison: &Value,
+    ) -> Vec<Difference> {
+        let mut diffs = vec![];
+        match (rpc_body, primary, comparison) {
+            (RpcBody::Batch(_), Value::Array(primary), Value::Array(comparison)) => {
+                for element in primary {
+                    let other = comparison
+                        .iter()
+                        .find(|other| other.get("id") == element.get("id"));
+                    match other {
+                        Some(other) => self.diff(&id_label(element), element, other, &mut diffs),
+                        None => diffs.push(Difference {
+                            path: id_label(element),
+                            primary: Some(element.clone()),
+                            comparison: None,
+                        }),
+                    }
+                }
+                for other in comparison {
+                    if !primary
+                        .iter()
+                        .any(|element| element.get("id") == other.get("id"))
+                    {
+                        diffs.push(Difference {
+                            path: id_label(other),
+                            primary: None,
+                            comparison: Some(other.clone()),
+                        });
+                    }
+                }
+            }
+            (_, primary, comparison) => self.diff("", primary, comparison, &mut diffs),
+        }
+        diffs
+    }
+
+    async fn send(
+        &self,
+        source_request: &SourceRequest,
+        context: &SnoopContext,
+    ) -> Result<(StatusCode, String), SnoopError> {
+        let dest_request = copy_request(source_request, &self.upstream, context)?;
+        let (response, response_json) = get_response(dest_request, &self.upstream).await?;
+        Ok((response.status(), response_json))
+    }
+}
+
+// Waits for the comparison endpoint's answer to a request that was mirrored to
+// it and prints where it differs from the primary response. Only called once
+// the primary response has been passed on, so a slow comparison endpoint
+// never holds up the client.
+pub async fn report(
+    comparison: tokio::task::JoinHandle<Result<(StatusCode, String), SnoopError>>,
+    rpc_body: RpcBody,
+    primary_status: StatusCode,
+    primary_json: String,
+    primary_upstream: Option<String>,
+    context: SnoopContext,
+) {
+    let settings = match &context.inner.comparison {
+        Some(settings) => settings,
+        None => return,
+    };
+    let methods = rpc_body.methods().join(", ");
+    let primary_name = primary_upstream.unwrap_or_else(|| "primary".to_string());
+    let comparison_name = settings.upstream.name();
+
+    let (status, response_json) = match comparison.await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            print_event(
+                &format!("DIFF {} {} failed: {:?}", methods, comparison_name, e),
+                &context,
+            );
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let overridden = context.settings().override_rpc.is_some();
+    let mut diffs = settings.diff_responses(
+        &rpc_body,
+        &without_overridden(&rpc_body, parse(&primary_json), overridden),
+        &without_overridden(&rpc_body, parse(&response_json), overridden),
+    );
+    if status != primary_status {
+        diffs.insert(
+            0,
+            Difference {
+                path: "(status)".to_string(),
+                primary: Some(Value::from(primary_status.as_u16())),
+                comparison: Some(Value::from(status.as_u16())),
+            },
+        );
+    }
+    if diffs.is_empty() {
+        return;
+    }
+
+    let colors = &context.inner.colors;
+    let mut lines = vec![format!(
+        "DIFF {} {}(-{}) {}(+{}) {} difference(s)",
+        methods,
+        colors.red,
+        primary_name,
+        colors.green,
+        comparison_name,
+        diffs.len()
+    )];
+    let show = |value: &Option<Value>| match value {
+        Some(value) => value.to_string(),
+        None => "(missing)".to_string(),
+    };
+    for diff in diffs {
+        lines.push(format!(
+            "{}- {}: {}",
+            colors.red,
+            diff.path,
+            show(&diff.primary)
+        ));
+        lines.push(format!(
+            "{}+ {}: {}",
+            colors.green,
+            diff.path,
+            show(&diff.comparison)
+        ));
+    }
+    print_event(&lines.join("\n"), &context);
+}
+
+// Mirrors the request to the comparison endpoint in the background
+pub fn mirror(
+    source_request: &SourceRequest,
+    context: &SnoopContext,
+) -> Option<tokio::task::JoinHandle<Result<(StatusCode, String), SnoopError>>> {
+    context.inner.comparison.as_ref()?;
+    let source_request = source_request.clone();
+    let context = context.clone();
+    Some(tokio::spawn(async move {
+        let comparison = context.inner.comparison.as_ref().expect("checked above");
+        comparison.send(&source_request, &context).await
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn comparison(ignore: &[&str]) -> Comparison {
+        let endpoint = crate::utils::parse_endpoint("http://localhost:8555").unwrap();
+        Comparison::new(
+            Upstream::new(&endpoint),
+            ignore.iter().map(|field| field.to_string()).collect(),
+        )
+    }
+
+    // the differences between two responses to `request`, as `path: -primary +comparison`
+    fn diffs(comparison: &Comparison, request: Value, primary: Value, other: Value) -> Vec<String> {
+        let show = |value: Option<Value>| value.map_or("(missing)".to_string(), |v| v.to_string());
+        comparison
+            .diff_responses(&RpcBody::parse(&request.to_string()), &primary, &other)
+            .into_iter()
+            .map(|diff| {
+                format!(
+                    "{}: -{} +{}",
+                    diff.path,
+                    show(diff.primary),
+                    show(diff.comparison)
+                )
+            })
+            .collect()
+    }
+
+    fn call(id: u64, method: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "method": method})
+    }
+
+    fn result(id: u64, result: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "result": result})
+    }
+
+    #[test]
+    fn finds_no_differences_in_equal_results() {
+        let block = json!({"number": "0x1", "transactions": ["0xa", "0xb"], "uncles": []});
+        assert!(diffs(
+            &comparison(&[]),
+            call(1, "m"),
+            result(1, block.clone()),
+            result(1, block)
+        )
+        .is_empty());
+        // key order doesn't matter
+        assert!(diffs(
+            &comparison(&[]),
+            call(1, "m"),
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"}),
+            json!({"result": "0x1", "id": 1, "jsonrpc": "2.0"}),
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn finds_nested_differences() {
+        assert_eq!(
+            diffs(
+                &comparison(&[]),
+                call(1, "m"),
+                result(1, json!({"block": {"number": "0x1", "hash": "0xa"}})),
+                result(
+                    1,
+                    json!({"block": {"number": "0x2", "hash": "0xa", "extra": true}})
+                ),
+            ),
+            [
+                "result.block.number: -\"0x1\" +\"0x2\"",
+                "result.block.extra: -(missing) +true",
+            ]
+        );
+        assert_eq!(
+            diffs(
+                &comparison(&[]),
+                call(1, "m"),
+                result(1, json!("0x1")),
+                json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "x"}}),
+            ),
+            [
+                "result: -\"0x1\" +(missing)",
+                "error: -(missing) +{\"code\":-32000,\"message\":\"x\"}",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_array_length_mismatches() {
+        assert_eq!(
+            diffs(
+                &comparison(&[]),
+                call(1, "m"),
+                result(1, json!(["0xa", "0xb", "0xc"])),
+                result(1, json!(["0xa", "0xd"])),
+            ),
+            [
+                "result[1]: -\"0xb\" +\"0xd\"",
+                "result[2]: -\"0xc\" +(missing)",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_ignored_fields() {
+        let primary = result(
+            1,
+            json!({"timestamp": "0x1", "logs": [{"blockHash": "0xa", "data": "0x"}]}),
+        );
+        let other = result(
+            2,
+            json!({"timestamp": "0x2", "logs": [{"blockHash": "0xb", "data": "0x"}]}),
+        );
+        assert_eq!(
+            diffs(
+                &comparison(&[]),
+                call(1, "m"),
+                primary.clone(),
+                other.clone()
+            )
+            .len(),
+            3
+        );
+        // ignored keys are left out however deeply they are nested, ids included
+        assert!(diffs(
+            &comparison(&["timestamp", "*Hash", "id"]),
+            call(1, "m"),
+            primary,
+            other,
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn pairs_batch_responses_by_id() {
+        let request = json!([call(1, "a"), call(2, "b"), call(3, "c")]);
+        assert_eq!(
+            diffs(
+                &comparison(&[]),
+                request,
+                json!([
+                    result(1, json!("0x1")),
+                    result(2, json!("0x2")),
+                    result(3, json!("0x3"))
+                ]),
+                json!([
+                    result(3, json!("0x3")),
+                    result(1, json!("0x9")),
+                    result(4, json!("0x4"))
+                ]),
+            ),
+            [
+                "[id 1].result: -\"0x1\" +\"0x9\"",
+                format!("[id 2]: -{} +(missing)", result(2, json!("0x2"))).as_str(),
+                format!("[id 4]: -(missing) +{}", result(4, json!("0x4"))).as_str(),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_overridden_calls() {
+        let request =
+            RpcBody::parse(&json!([call(1, "rpc_modules"), call(2, "eth_chainId")]).to_string());
+        let response = json!([result(2, json!("0x1")), result(1, json!({"eth": "1.0"}))]);
+        assert_eq!(
+            without_overridden(&request, response.clone(), true),
+            json!([result(2, json!("0x1"))])
+        );
+        assert_eq!(
+            without_overridden(&request, response.clone(), false),
+            response
+        );
+        let single = RpcBody::parse(&call(1, "rpc_modules").to_string());
+        assert_eq!(
+            without_overridden(&single, result(1, json!({})), true),
+            result(1, json!({}))
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use clap::parser::ValueSource;
+use clap::App;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+// the config key that fills the RPC_ENDPOINT positional
+const ENDPOINT_KEY: &str = "endpoint";
+const PROFILES_KEY: &str = "profiles";
+
+// What was passed on the command line: the ids of the options given, the
+// values of the options that decide which config to load, and whether any
+// endpoint was given.
+#[derive(Debug, Default)]
+struct CommandLine {
+    present: HashSet<String>,
+    config: Option<PathBuf>,
+    profile: Option<String>,
+    endpoint: bool,
+}
+
+// Parses the command line on its own, so the config file can be located
+// before clap sees the arguments it provides. Errors are left for the full
+// parse to report: a missing RPC_ENDPOINT, for one, may be in the config.
+fn scan(app: &App, args: &[OsString]) -> CommandLine {
+    let mut app = app.clone().ignore_errors(true);
+    let matches = app.try_get_matches_from_mut(args).unwrap_or_default();
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    CommandLine {
+        present: app
+            .get_arguments()
+            .map(|arg| arg.get_id())
+            .filter(|id| given(id))
+            .map(str::to_string)
+            .collect(),
+        config: matches.get_one::<PathBuf>("config").cloned(),
+        profile: matches.get_one::<String>("profile").cloned(),
+        endpoint: given("RPC_ENDPOINT"),
+    }
+}
+
+fn scalar(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!(
+            "Unsupported value for '{}': expected a string, number or boolean",
+            key
+        )),
+    }
+}
+
+// Turns one config entry into the arguments clap would have received for it
+fn to_args(app: &App, key: &str, value: &toml::Value) -> Result<(String, Vec<String>), String> {
+    let values = match value {
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| scalar(key, value))
+            .collect::<Result<Vec<_>, _>>()?,
+        value => vec![scalar(key, value)?],
+    };
+
+    let name = key.replace('_', "-");
+    if name == ENDPOINT_KEY {
+        return Ok(("RPC_ENDPOINT".to_string(), values));
+    }
+    let arg = app
+        .get_arguments()
+        .find(|a| {
+            a.get_long() == Some(name.as_str()) && !matches!(name.as_str(), "config" | "profile")
+        })
+        .ok_or_else(|| format!("Unknown option '{}'", key))?;
+    let flag = format!("--{}", name);
+    let mut args = vec![];
+    if arg.is_takes_value_set() {
+        for value in values {
+            args.push(format!("{}={}", flag, value));
+        }
+    } else {
+        match value {
+            toml::Value::Boolean(true) => args.push(flag),
+            toml::Value::Boolean(false) => {}
+            _ => return Err(format!("'{}' is a flag and takes true or false", key)),
+        }
+    }
+    Ok((arg.get_id().to_string(), args))
+}
+
+fn load(path: &Path, profile: Option<&str>) -> Result<toml::value::Table, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config {}: {}", path.display(), e))?;
+    parse(&contents, path, profile)
+}
+
+fn parse(contents: &str, path: &Path, profile: Option<&str>) -> Result<toml::value::Table, String> {
+    let mut table = toml::from_str::<toml::value::Table>(contents)
+        .map_err(|e| format!("Unable to parse config {}: {}", path.display(), e))?;
+
+    let mut profiles = match table.remove(PROFILES_KEY) {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(format!("'{}' must be a table of profiles", PROFILES_KEY)),
+        None => toml::value::Table::new(),
+    };
+    if let Some(name) = profile {
+        match profiles.remove(name) {
+            // a profile's settings replace the top level ones
+            Some(toml::Value::Table(overrides)) => table.extend(overrides),
+            Some(_) => return Err(format!("Profile '{}' must be a table", name)),
+            None => {
+                let names = profiles.keys().cloned().collect::<Vec<_>>();
+                return Err(format!(
+                    "No profile '{}' in {} (available: {})",
+                    name,
+                    path.display(),
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                ));
+            }
+        }
+    }
+    Ok(table)
+}
+
+// Returns the arguments to hand to clap: the settings from --config (and
+// --profile) expressed as command line arguments, followed by the real command
+// line. Options given on the command line replace the file's value for that
+// option entirely, including options that can be given more than once.
+pub fn with_config_args(app: &App, args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let cmdline = scan(app, &args);
+    let path = match (&cmdline.config, &cmdline.profile) {
+        (Some(path), _) => path,
+        (None, Some(_)) => return Err("--profile requires --config".to_string()),
+        (None, None) => return Ok(args),
+    };
+    let table = load(path, cmdline.profile.as_deref())?;
+
+    let mut from_file = vec![];
+    let mut endpoints = vec![];
+    for (key, value) in table.iter() {
+        let (id, values) =
+            to_args(app, key, value).map_err(|e| format!("{} in {}", e, path.display()))?;
+        if id == "RPC_ENDPOINT" {
+            endpoints = values;
+        } else if !cmdline.present.contains(&id) {
+            from_file.extend(values);
+        }
+    }
+
+    let separated = args.iter().skip(1).any(|arg| arg == "--");
+    let mut args = args.into_iter();
+    let mut result = args.next().into_iter().collect::<Vec<_>>();
+    result.extend(from_file.into_iter().map(OsString::from));
+    result.extend(args);
+    // the endpoints go last so they can't be mistaken for an option's value
+    if !cmdline.endpoint && !endpoints.is_empty() {
+        if !separated {
+            result.push(OsString::from("--"));
+        }
+        result.extend(endpoints.into_iter().map(OsString::from));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn app() -> App<'static> {
+        App::new("test")
+            .arg(
+                Arg::with_name("port")
+                    .short('p')
+                    .long("port")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("suppress-method")
+                    .short('s')
+                    .long("suppress-method")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("log-headers")
+                    .short('l')
+                    .long("log-headers")
+                    .takes_value(false),
+            )
+            .arg(Arg::with_name("replay").long("replay").takes_value(true))
+            .arg(
+                Arg::with_name("RPC_ENDPOINT")
+                    .multiple_values(true)
+                    .required_unless_present("replay")
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .requires("config")
+                    .takes_value(true),
+            )
+    }
+
+    fn args(line: &str) -> Vec<OsString> {
+        std::iter::once("json_rpc_snoop")
+            .chain(line.split_whitespace())
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn scans_the_command_line() {
+        let cmdline = scan(&app(), &args("--config snoop.toml -s eth_call -lp 8000"));
+        assert_eq!(cmdline.config, Some(PathBuf::from("snoop.toml")));
+        assert_eq!(cmdline.profile, None);
+        assert!(!cmdline.endpoint);
+        for id in ["config", "suppress-method", "log-headers", "port"] {
+            assert!(cmdline.present.contains(id), "{} should be present", id);
+        }
+        assert!(!cmdline.present.contains("replay"));
+
+        // option values aren't endpoints, whichever way they're given
+        let cmdline = scan(&app(), &args("--profile=ci -s eth_call --port 8000"));
+        assert_eq!(cmdline.profile.as_deref(), Some("ci"));
+        assert!(!cmdline.endpoint);
+
+        let cmdline = scan(&app(), &args("--config snoop.toml http://localhost:8545"));
+        assert!(cmdline.endpoint);
+        let cmdline = scan(
+            &app(),
+            &args("--config snoop.toml -- http://localhost:8545"),
+        );
+        assert!(cmdline.endpoint);
+    }
+
+    #[test]
+    fn converts_entries_to_args() {
+        let to_args = |toml: &str| {
+            let table = toml::from_str::<toml::value::Table>(toml).unwrap();
+            let (key, value) = table.iter().next().unwrap();
+            to_args(&app(), key, value)
+        };
+        assert_eq!(
+            to_args("port = 8000"),
+            Ok(("port".to_string(), vec!["--port=8000".to_string()]))
+        );
+        assert_eq!(
+            to_args(r#"suppress_method = ["eth_call", "eth_getLogs:2"]"#),
+            Ok((
+                "suppress-method".to_string(),
+                vec![
+                    "--suppress-method=eth_call".to_string(),
+                    "--suppress-method=eth_getLogs:2".to_string()
+                ]
+            ))
+        );
+        assert_eq!(
+            to_args("log_headers = true"),
+            Ok(("log-headers".to_string(), vec!["--log-headers".to_string()]))
+        );
+        assert_eq!(
+            to_args("log_headers = false"),
+            Ok(("log-headers".to_string(), vec![]))
+        );
+        assert_eq!(
+            to_args(r#"endpoint = ["http://a:8545", "http://b:8545"]"#),
+            Ok((
+                "RPC_ENDPOINT".to_string(),
+                vec!["http://a:8545".to_string(), "http://b:8545".to_string()]
+            ))
+        );
+
+        assert!(to_args("log_headers = 1").is_err());
+        assert!(to_args("no_such_option = 1").is_err());
+        assert!(to_args(r#"config = "other.toml""#).is_err());
+        assert!(to_args("port = { number = 8000 }").is_err());
+    }
+
+    #[test]
+    fn merges_profiles() {
+        let contents = r#"
+            port = 8000
+            suppress_method = ["eth_call"]
+
+            [profiles.ci]
+            suppress_method = ["eth_getLogs"]
+            log_headers = true
+        "#;
+        let path = Path::new("snoop.toml");
+        let table = parse(contents, path, None).unwrap();
+        assert_eq!(
+            table.keys().collect::<Vec<_>>(),
+            ["port", "suppress_method"]
+        );
+
+        let table = parse(contents, path, Some("ci")).unwrap();
+        assert_eq!(table["port"], toml::Value::Integer(8000));
+        assert_eq!(
+            table["suppress_method"],
+            toml::Value::Array(vec![toml::Value::from("eth_getLogs")])
+        );
+        assert_eq!(table["log_headers"], toml::Value::Boolean(true));
+
+        let missing = parse(contents, path, Some("prod")).unwrap_err();
+        assert!(missing.contains("available: ci"), "{}", missing);
+        assert!(parse("profiles = 1", path, None).is_err());
+    }
+
+    #[test]
+    fn puts_the_config_before_the_command_line() {
+        let path = std::env::temp_dir().join(format!("json_rpc_snoop_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "endpoint = \"http://a:8545\"\nport = 8000\nsuppress_method = [\"eth_call\"]\n",
+        )
+        .unwrap();
+        let with_config = |line: &str| {
+            with_config_args(
+                &app(),
+                args(&format!("--config {} {}", path.display(), line)),
+            )
+            .map(|args| {
+                args.into_iter()
+                    .skip(1)
+                    .map(|arg| arg.into_string().unwrap())
+                    .filter(|arg| arg != "--config" && !arg.ends_with(".toml"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+        };
+        // the command line's options and endpoint replace the file's
+        assert_eq!(
+            with_config("-s eth_getLogs"),
+            Ok("--port=8000 -s eth_getLogs -- http://a:8545".to_string())
+        );
+        assert_eq!(
+            with_config("-p 9000 http://b:8545"),
+            Ok("--suppress-method=eth_call -p 9000 http://b:8545".to_string())
+        );
+        assert!(with_config("--profile ci").is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(with_config_args(&app(), args("--profile ci")).is_err());
+    }
+}
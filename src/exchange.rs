@@ -0,0 +1,102 @@
+use chrono::{DateTime, Local};
+use hyper::http::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
+use std::time::Duration;
+
+// `http_method` of the frames relayed over a websocket connection
+pub const WEBSOCKET_METHOD: &str = "WS";
+
+// Everything known about one proxied request/response pair, handed to the
+// recorders (e.g. `--log-file`) once the exchange is complete
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub timestamp: DateTime<Local>,
+    pub client: String,
+    pub http_method: String,
+    pub path: String,
+    // JSON-RPC methods called, in request order (empty for non JSON-RPC bodies)
+    pub methods: Vec<String>,
+    pub request_headers: Vec<(HeaderName, HeaderValue)>,
+    pub request_json: String,
+    // size of the body as received, `request_json` is pretty printed
+    pub request_bytes: usize,
+    // `None` when the request was dropped before being forwarded
+    pub status: Option<StatusCode>,
+    pub response_headers: Vec<(HeaderName, HeaderValue)>,
+    pub response_json: Option<String>,
+    pub response_bytes: Option<usize>,
+    pub upstream: Option<String>,
+    pub latency: Option<Duration>,
+    // time until the upstream started answering, `None` if nothing was sent
+    pub ttfb: Option<Duration>,
+    pub request_dropped: bool,
+    pub response_dropped: bool,
+    // answered by `--inject-error` instead of the upstream
+    pub injected: bool,
+    // what `--mutate-response` changed, `response_json` is what was sent
+    pub mutation: Option<String>,
+}
+
+#[cfg(test)]
+impl Exchange {
+    // A JSON-RPC POST to `/` answered with a 200, for the recorders' tests
+    pub fn answered(request_json: &str, response_json: &str) -> Self {
+        Self {
+            timestamp: chrono::offset::Local::now(),
+            client: "127.0.0.1:40000".to_string(),
+            http_method: "POST".to_string(),
+            path: "/".to_string(),
+            methods: crate::utils::RpcBody::parse(request_json).methods(),
+            request_headers: vec![],
+            request_json: request_json.to_string(),
+            request_bytes: request_json.len(),
+            status: Some(StatusCode::OK),
+            response_headers: vec![],
+            response_json: Some(response_json.to_string()),
+            response_bytes: Some(response_json.len()),
+            upstream: Some("http://localhost:8545".to_string()),
+            latency: Some(Duration::from_millis(12)),
+            ttfb: Some(Duration::from_millis(10)),
+            request_dropped: false,
+            response_dropped: false,
+            injected: false,
+            mutation: None,
+        }
+    }
+}
+
+// Credentials such as Engine API bearer tokens stay out of the recordings,
+// which are meant to be handed around
+const REDACTED_HEADERS: [&str; 2] = ["authorization", "proxy-authorization"];
+
+// A header's value as the recorders write it
+pub fn header_text(name: &HeaderName, value: &HeaderValue) -> String {
+    if REDACTED_HEADERS.contains(&name.as_str()) {
+        "[redacted]".to_string()
+    } else {
+        String::from_utf8_lossy(value.as_bytes()).to_string()
+    }
+}
+
+// Collapses headers into a JSON object, joining repeated headers with ", "
+pub fn headers_to_json(headers: &[(HeaderName, HeaderValue)]) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (key, value) in headers {
+        let value = header_text(key, value);
+        match map.get_mut(key.as_str()) {
+            Some(serde_json::Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            _ => {
+                map.insert(key.to_string(), serde_json::Value::String(value));
+            }
+        }
+    }
+    serde_json::Value::Object(map)
+}
+
+// Embeds a logged body as JSON when it parses, otherwise as a plain string
+pub fn body_to_json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap_or_else(|_| serde_json::Value::String(body.to_string()))
+}
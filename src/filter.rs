@@ -0,0 +1,558 @@
+use crate::exchange::Exchange;
+use crate::utils::RpcBody;
+use serde_json::Value;
+
+// A `--filter` expression, e.g.
+//
+//     method == "eth_call" && !error && latency < 200ms
+//
+// evaluated once per call against the request, its response, the http status
+// and timings. Calls for which it doesn't hold aren't shown.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    // a field such as `params[0].address`, the first segment names the root
+    Field(String, Vec<Segment>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, Op, Box<Expr>),
+    Match(Box<Expr>, regex::Regex),
+}
+
+const FIELDS: [&str; 14] = [
+    "method",
+    "params",
+    "id",
+    "request",
+    "response",
+    "result",
+    "error",
+    "status",
+    "latency",
+    "ttfb",
+    "path",
+    "http_method",
+    "upstream",
+    "batch",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Str(String),
+    Num(f64),
+    Ident(String),
+    Op(&'static str),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".to_string()),
+                    Some('\\') => {
+                        s.extend(chars.get(i + 1));
+                        i += 2;
+                    }
+                    Some(q) if *q == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(other) => {
+                        s.push(*other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            let literal = chars[start..i].iter().collect::<String>();
+            tokens.push(Token::Num(parse_number(&literal)?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let two = chars[i..chars.len().min(i + 2)].iter().collect::<String>();
+            let op = ["==", "!=", "<=", ">=", "=~", "&&", "||"]
+                .into_iter()
+                .find(|op| two == *op)
+                .or_else(|| {
+                    ["<", ">", "!", "(", ")", "[", "]", ".", "-"]
+                        .into_iter()
+                        .find(|op| op.starts_with(c))
+                })
+                .ok_or_else(|| format!("unexpected '{}'", c))?;
+            i += op.len();
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+// Plain numbers, `0x` hex quantities and durations (`250ms`, `2s`), which
+// are compared as milliseconds
+fn parse_number(literal: &str) -> Result<f64, String> {
+    if let Some(hex) = literal.strip_prefix("0x") {
+        return u128::from_str_radix(hex, 16)
+            .map(|n| n as f64)
+            .map_err(|_| format!("invalid number '{}'", literal));
+    }
+    let (number, scale) = if let Some(ms) = literal.strip_suffix("ms") {
+        (ms, 1.0)
+    } else if let Some(s) = literal.strip_suffix('s') {
+        (s, 1000.0)
+    } else {
+        (literal, 1.0)
+    };
+    number
+        .parse::<f64>()
+        .map(|n| n * scale)
+        .map_err(|_| format!("invalid number '{}'", literal))
+}
+
+fn number(n: f64) -> Expr {
+    Expr::Literal(
+        serde_json::Number::from_f64(n)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+    )
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(next)) if *next == op) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", op))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.unary()?;
+        let op = match self.peek() {
+            Some(Token::Op("==")) => Op::Eq,
+            Some(Token::Op("!=")) => Op::Ne,
+            Some(Token::Op("<")) => Op::Lt,
+            Some(Token::Op("<=")) => Op::Le,
+            Some(Token::Op(">")) => Op::Gt,
+            Some(Token::Op(">=")) => Op::Ge,
+            Some(Token::Op("=~")) => {
+                self.position += 1;
+                return match self.next() {
+                    Some(Token::Str(pattern)) => regex::Regex::new(&pattern)
+                        .map(|regex| Expr::Match(Box::new(left), regex))
+                        .map_err(|e| format!("invalid regex '{}': {}", pattern, e)),
+                    _ => Err("expected a string regex after '=~'".to_string()),
+                };
+            }
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Expr::Compare(Box::new(left), op, Box::new(self.unary()?)))
+    }
+
+    // `!` binds tighter than comparisons, `!a < b` is `(!a) < b`
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op("(")) => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Str(s)) => Ok(Expr::Literal(Value::String(s))),
+            Some(Token::Num(n)) => Ok(number(n)),
+            Some(Token::Op("-")) => match self.next() {
+                Some(Token::Num(n)) => Ok(number(-n)),
+                _ => Err("expected a number after '-'".to_string()),
+            },
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                field if FIELDS.contains(&field) => {
+                    let mut segments = vec![];
+                    loop {
+                        if self.eat(".") {
+                            match self.next() {
+                                Some(Token::Ident(key)) => segments.push(Segment::Key(key)),
+                                _ => {
+                                    return Err(format!("expected a field name after '{}.'", field))
+                                }
+                            }
+                        } else if self.eat("[") {
+                            match self.next() {
+                                Some(Token::Num(n)) if n >= 0.0 && n.fract() == 0.0 => {
+                                    segments.push(Segment::Index(n as usize))
+                                }
+                                Some(Token::Str(key)) => segments.push(Segment::Key(key)),
+                                _ => {
+                                    return Err("expected an index or a string in '[]'".to_string())
+                                }
+                            }
+                            self.expect("]")?;
+                        } else {
+                            break;
+                        }
+                    }
+                    Ok(Expr::Field(ident, segments))
+                }
+                field => Err(format!(
+                    "unknown field '{}', expected one of {}",
+                    field,
+                    FIELDS.join(", ")
+                )),
+            },
+            Some(Token::Op(op)) => Err(format!("unexpected '{}'", op)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+pub fn parse_filter(arg: &str) -> Result<Filter, String> {
+    tokenize(arg)
+        .and_then(|tokens| {
+            let mut parser = Parser {
+                tokens,
+                position: 0,
+            };
+            let expr = parser.or()?;
+            match parser.peek() {
+                None => Ok(Filter { expr }),
+                Some(_) => Err("unexpected input after the expression".to_string()),
+            }
+        })
+        .map_err(|e| format!("Unable to parse filter '{}': {}", arg, e))
+}
+
+// Everything a filter can look at for one call
+struct Call<'a> {
+    request: Value,
+    response: Option<Value>,
+    batch: bool,
+    exchange: &'a Exchange,
+}
+
+fn millis(duration: Option<std::time::Duration>) -> Value {
+    duration
+        .and_then(|duration| serde_json::Number::from_f64(duration.as_secs_f64() * 1000.0))
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+// Numbers, including `0x` quantities as used throughout the Ethereum JSON-RPC
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s
+            .strip_prefix("0x")
+            .and_then(|hex| u128::from_str_radix(hex, 16).ok())
+            .map(|n| n as f64),
+        _ => None,
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+impl Expr {
+    fn value(&self, call: &Call) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Field(root, segments) => {
+                let exchange = call.exchange;
+                let response = call.response.clone().unwrap_or(Value::Null);
+                let mut value = match root.as_str() {
+                    "method" | "params" | "id" => call.request.get(root).cloned(),
+                    "request" => Some(call.request.clone()),
+                    "response" => Some(response),
+                    "result" | "error" => response.get(root).cloned(),
+                    "status" => exchange.status.map(|status| Value::from(status.as_u16())),
+                    "latency" => Some(millis(exchange.latency)),
+                    "ttfb" => Some(millis(exchange.ttfb)),
+                    "path" => Some(Value::String(exchange.path.clone())),
+                    "http_method" => Some(Value::String(exchange.http_method.clone())),
+                    "upstream" => exchange.upstream.clone().map(Value::String),
+                    "batch" => Some(Value::Bool(call.batch)),
+                    _ => None,
+                }
+                .unwrap_or(Value::Null);
+                for segment in segments {
+                    value = match segment {
+                        Segment::Key(key) => value.get(key),
+                        Segment::Index(i) => value.get(i),
+                    }
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                }
+                value
+            }
+            expr => Value::Bool(expr.holds(call)),
+        }
+    }
+
+    fn holds(&self, call: &Call) -> bool {
+        match self {
+            Expr::Not(expr) => !expr.holds(call),
+            Expr::And(left, right) => left.holds(call) && right.holds(call),
+            Expr::Or(left, right) => left.holds(call) || right.holds(call),
+            Expr::Match(expr, regex) => match expr.value(call) {
+                Value::String(s) => regex.is_match(&s),
+                Value::Null => false,
+                value => regex.is_match(&value.to_string()),
+            },
+            Expr::Compare(left, op, right) => {
+                let (left, right) = (left.value(call), right.value(call));
+                // compare numerically unless both sides are plain strings
+                let numbers = match (&left, &right) {
+                    (Value::String(_), Value::String(_)) => None,
+                    _ => as_number(&left).zip(as_number(&right)),
+                };
+                let ordering = match (&numbers, &left, &right) {
+                    (Some((left, right)), _, _) => left.partial_cmp(right),
+                    (None, Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+                    _ => None,
+                };
+                match op {
+                    Op::Eq => numbers.map(|(l, r)| l == r).unwrap_or(left == right),
+                    Op::Ne => !numbers.map(|(l, r)| l == r).unwrap_or(left == right),
+                    Op::Lt => ordering.map(|o| o.is_lt()).unwrap_or(false),
+                    Op::Le => ordering.map(|o| o.is_le()).unwrap_or(false),
+                    Op::Gt => ordering.map(|o| o.is_gt()).unwrap_or(false),
+                    Op::Ge => ordering.map(|o| o.is_ge()).unwrap_or(false),
+                }
+            }
+            expr => truthy(&expr.value(call)),
+        }
+    }
+}
+
+// Whether each call in the exchange fails any of `filters`, in request order
+pub fn hidden_calls(filters: &[Filter], rpc_body: &RpcBody, exchange: &Exchange) -> Vec<bool> {
+    let parse = |json: &str| serde_json::from_str::<Value>(json).unwrap_or(Value::Null);
+    let response = exchange.response_json.as_deref().map(parse);
+    let calls = match rpc_body {
+        RpcBody::Single(_) => vec![Call {
+            request: parse(&exchange.request_json),
+            response,
+            batch: false,
+            exchange,
+        }],
+        RpcBody::Batch(calls) => calls
+            .iter()
+            .map(|(element, _)| Call {
+                request: element.clone(),
+                response: response
+                    .as_ref()
+                    .and_then(|response| {
+                        response.as_array()?.iter().find(|response| {
+                            element.get("id").is_some() && response.get("id") == element.get("id")
+                        })
+                    })
+                    .cloned(),
+                batch: true,
+                exchange,
+            })
+            .collect(),
+    };
+    calls
+        .iter()
+        .map(|call| !filters.iter().all(|filter| filter.expr.holds(call)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use hyper::StatusCode;
+    use std::time::Duration;
+
+    fn exchange(request_json: &str, response_json: &str) -> Exchange {
+        Exchange {
+            timestamp: Local::now(),
+            client: "127.0.0.1:1234".to_string(),
+            http_method: "POST".to_string(),
+            path: "/".to_string(),
+            methods: vec![],
+            request_headers: vec![],
+            request_json: request_json.to_string(),
+            request_bytes: request_json.len(),
+            status: Some(StatusCode::OK),
+            response_headers: vec![],
+            response_json: Some(response_json.to_string()),
+            response_bytes: Some(response_json.len()),
+            upstream: None,
+            latency: Some(Duration::from_millis(150)),
+            ttfb: None,
+            request_dropped: false,
+            response_dropped: false,
+            injected: false,
+            mutation: None,
+        }
+    }
+
+    // Whether the filter holds for a single call answered with `response_json`
+    fn holds(filter: &str, response_json: &str) -> bool {
+        let request_json =
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_call","params":[{"to":"0xabc"},"0x10"]}"#;
+        let exchange = exchange(request_json, response_json);
+        let filter = parse_filter(filter).unwrap();
+        hidden_calls(&[filter], &RpcBody::parse(request_json), &exchange) == vec![false]
+    }
+
+    const RESULT: &str = r#"{"jsonrpc":"2.0","id":1,"result":"0x2a"}"#;
+    const ERROR: &str =
+        r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"execution reverted"}}"#;
+
+    #[test]
+    fn compares_fields() {
+        assert!(holds(r#"method == "eth_call""#, RESULT));
+        assert!(holds(r#"params[0].to == "0xabc""#, RESULT));
+        assert!(holds(r#"method =~ "^eth_""#, RESULT));
+        assert!(holds(
+            "status == 200 && latency > 100ms && latency < 0.2s",
+            RESULT
+        ));
+        assert!(!holds("latency >= 1s", RESULT));
+    }
+
+    #[test]
+    fn compares_hex_and_decimal() {
+        assert!(holds("result == 42", RESULT));
+        assert!(holds("result == 0x2a", RESULT));
+        assert!(holds("params[1] > 15 && params[1] < 0x11", RESULT));
+        // two strings compare as strings
+        assert!(!holds(r#"result == "42""#, RESULT));
+    }
+
+    #[test]
+    fn treats_missing_fields_as_false() {
+        assert!(!holds("error", RESULT));
+        assert!(holds("!error", RESULT));
+        assert!(!holds("error.code < 0", RESULT));
+        assert!(!holds("params[5].to", RESULT));
+        assert!(holds("error", ERROR));
+        assert!(holds("params[5] == null", RESULT));
+    }
+
+    #[test]
+    fn accepts_negative_numbers() {
+        assert!(holds("error.code == -32000", ERROR));
+        assert!(holds("error.code < -1 && error.code > -0x8000", ERROR));
+        assert!(holds("error.code != - 1", ERROR));
+        assert!(parse_filter("error.code == -").is_err());
+        assert!(parse_filter("error.code == -method").is_err());
+    }
+
+    #[test]
+    fn binds_not_tighter_than_comparisons() {
+        // `(!error) == true`, `!(error == true)` would hold for both
+        assert!(holds("!error == true", RESULT));
+        assert!(!holds("!error == true", ERROR));
+        assert!(holds("!(error == true)", ERROR));
+        // `&&` binds tighter than `||`
+        assert!(holds(
+            r#"error || method == "eth_call" && status == 200"#,
+            RESULT
+        ));
+        assert!(!holds(
+            r#"(error || method == "eth_call") && status == 500"#,
+            RESULT
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        assert!(parse_filter("nonsense == 1").is_err());
+        assert!(parse_filter("method ==").is_err());
+        assert!(parse_filter(r#"method == "eth_call"#).is_err());
+        assert!(parse_filter("(method").is_err());
+        assert!(parse_filter(r#"method =~ "(""#).is_err());
+        assert!(parse_filter("method method").is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use crate::exchange::{header_text, Exchange};
+use hyper::http::header::{HeaderName, HeaderValue};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::sync::mpsc;
+
+const TRAILER: &[u8] = b"\n]}}\n";
+
+#[derive(Debug)]
+struct HarFile {
+    file: File,
+    // where the next entry goes, i.e. the start of the trailer
+    offset: u64,
+    entries: usize,
+}
+
+impl HarFile {
+    fn append(&mut self, entry: &str) -> Result<(), std::io::Error> {
+        let record = match self.entries {
+            0 => format!("\n{}", entry),
+            _ => format!(",\n{}", entry),
+        };
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        self.file.write_all(record.as_bytes())?;
+        self.file.write_all(TRAILER)?;
+        self.file.flush()?;
+        self.offset += record.len() as u64;
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+// Records exchanges into a HAR 1.2 file. Every entry is written in place of
+// the closing brackets, which are then written again, so the file is a valid
+// HAR document after each exchange and not only after shutdown. Like the
+// traffic log, entries are written by a thread of their own.
+#[derive(Debug)]
+pub struct HarLog {
+    // `None` once the log is finished
+    sender: Mutex<Option<mpsc::UnboundedSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    // the last write the thread failed, reported by the next call to `write`
+    error: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+fn har_headers(headers: &[(HeaderName, HeaderValue)]) -> serde_json::Value {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            serde_json::json!({
+                "name": name.as_str(),
+                "value": header_text(name, value),
+            })
+        })
+        .collect()
+}
+
+fn header_value<'a>(headers: &'a [(HeaderName, HeaderValue)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.as_str().eq(name))
+        .and_then(|(_, value)| value.to_str().ok())
+}
+
+fn query_string(path: &str) -> serde_json::Value {
+    path.split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            serde_json::json!({ "name": name, "value": value })
+        })
+        .collect()
+}
+
+fn entry(exchange: &Exchange) -> serde_json::Value {
+    let host = header_value(&exchange.request_headers, "host").unwrap_or("localhost");
+    let request_mime =
+        header_value(&exchange.request_headers, "content-type").unwrap_or("application/json");
+    let response_mime =
+        header_value(&exchange.response_headers, "content-type").unwrap_or("application/json");
+    let response_text = exchange.response_json.clone().unwrap_or_default();
+    let time = exchange
+        .latency
+        .map(|latency| latency.as_secs_f64() * 1000.0)
+        .unwrap_or(0.0);
+    let wait = exchange
+        .ttfb
+        .map(|ttfb| ttfb.as_secs_f64() * 1000.0)
+        .unwrap_or(time)
+        .min(time);
+
+    let mut comments = vec![];
+    if let Some(upstream) = &exchange.upstream {
+        comments.push(format!("upstream {}", upstream));
+    }
+    if exchange.request_dropped {
+        comments.push("request dropped".to_string());
+    }
+    if exchange.response_dropped {
+        comments.push("response dropped".to_string());
+    }
+    if exchange.injected {
+        comments.push("injected error".to_string());
+    }
+    if let Some(mutation) = &exchange.mutation {
+        comments.push(format!("mutated: {}", mutation));
+    }
+
+    serde_json::json!({
+        "startedDateTime": exchange.timestamp.to_rfc3339(),
+        "time": time,
+        "request": {
+            "method": exchange.http_method,
+            "url": format!("http://{}{}", host, exchange.path),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": har_headers(&exchange.request_headers),
+            "queryString": query_string(&exchange.path),
+            "postData": {
+                "mimeType": request_mime,
+                "text": exchange.request_json,
+            },
+            "headersSize": -1,
+            "bodySize": exchange.request_bytes,
+        },
+        "response": {
+            // browsers record aborted requests with status 0 too
+            "status": exchange.status.map(|status| status.as_u16()).unwrap_or(0),
+            "statusText": exchange
+                .status
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or(""),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": har_headers(&exchange.response_headers),
+            "content": {
+                "size": response_text.len(),
+                "mimeType": response_mime,
+                "text": response_text,
+            },
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": exchange.response_bytes.unwrap_or(0),
+        },
+        "cache": {},
+        "timings": {
+            "send": 0,
+            "wait": wait,
+            "receive": time - wait,
+        },
+        "comment": comments.join(", "),
+    })
+}
+
+impl HarLog {
+    pub fn create(path: &Path) -> Result<Self, std::io::Error> {
+        let mut file = File::create(path)?;
+        let header = serde_json::json!({
+            "version": "1.2",
+            "creator": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        })
+        .to_string();
+        // splice the entries array into the log object by hand so entries can
+        // be appended without rewriting what came before
+        let opening = format!("{{\"log\":{},\"entries\":[", &header[..header.len() - 1]);
+        file.write_all(opening.as_bytes())?;
+        let offset = opening.len() as u64;
+        file.write_all(TRAILER)?;
+        file.flush()?;
+
+        let mut har_file = HarFile {
+            file,
+            offset,
+            entries: 0,
+        };
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        let error = Arc::new(Mutex::new(None));
+        let writer_error = error.clone();
+        let writer = std::thread::spawn(move || {
+            while let Some(entry) = receiver.blocking_recv() {
+                if let Err(e) = har_file.append(&entry) {
+                    *writer_error.lock().unwrap() = Some(e);
+                }
+            }
+            if let Err(e) = har_file.file.sync_all() {
+                eprintln!("Unable to finalise har file: {}", e);
+            }
+        });
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            error,
+        })
+    }
+
+    pub fn write(&self, exchange: &Exchange) -> Result<(), std::io::Error> {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send(entry(exchange).to_string());
+        }
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Waits for the entries still queued to be written
+    pub fn finish(&self) {
+        self.sender.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    #[test]
+    fn writes_a_valid_har_file() {
+        let path = std::env::temp_dir().join(format!("json_rpc_snoop_{}.har", std::process::id()));
+        let har_log = HarLog::create(&path).unwrap();
+        let mut call = Exchange::answered(
+            r#"{"jsonrpc":"2.0","id":1,"method":"engine_forkchoiceUpdatedV1"}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":null}"#,
+        );
+        call.request_headers = vec![
+            (
+                HeaderName::from_static("host"),
+                HeaderValue::from_static("localhost:8551"),
+            ),
+            (
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_static("Bearer secret.token"),
+            ),
+        ];
+        har_log.write(&call).unwrap();
+        let mut rest = Exchange::answered("", r#"{"data":{"version":"x"}}"#);
+        rest.http_method = "GET".to_string();
+        rest.path = "/eth/v1/node/version?a=1&b".to_string();
+        rest.status = Some(StatusCode::SERVICE_UNAVAILABLE);
+        har_log.write(&rest).unwrap();
+        har_log.finish();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!contents.contains("secret.token"));
+        let har = serde_json::from_str::<serde_json::Value>(&contents).unwrap();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["creator"]["name"], env!("CARGO_PKG_NAME"));
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            for field in [
+                "startedDateTime",
+                "time",
+                "request",
+                "response",
+                "cache",
+                "timings",
+            ] {
+                assert!(entry.get(field).is_some(), "missing {}", field);
+            }
+            assert_eq!(entry["timings"]["wait"], 10.0);
+            assert_eq!(entry["timings"]["receive"], 2.0);
+        }
+
+        let request = &entries[0]["request"];
+        assert_eq!(request["method"], "POST");
+        assert_eq!(request["url"], "http://localhost:8551/");
+        assert_eq!(
+            request["headers"][1],
+            serde_json::json!({"name": "authorization", "value": "[redacted]"})
+        );
+        assert_eq!(request["postData"]["text"], call.request_json);
+        assert_eq!(entries[0]["response"]["status"], 200);
+        assert_eq!(
+            entries[0]["response"]["content"]["text"],
+            call.response_json.unwrap()
+        );
+
+        let request = &entries[1]["request"];
+        assert_eq!(request["url"], "http://localhost/eth/v1/node/version?a=1&b");
+        assert_eq!(
+            request["queryString"],
+            serde_json::json!([{"name": "a", "value": "1"}, {"name": "b", "value": ""}])
+        );
+        assert_eq!(entries[1]["response"]["status"], 503);
+        assert_eq!(entries[1]["response"]["statusText"], "Service Unavailable");
+    }
+}
@@ -0,0 +1,355 @@
+use crate::upstream::Timing;
+use crate::utils::{ClientAddr, RpcBody, SnoopError};
+use crate::{format_response_json, handle_request, print_event, SnoopContext};
+use hyper::{Body, Request, Response};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration, Instant};
+
+// how long an ipc upstream gets to answer before the call fails, so a node
+// that accepts connections but never answers can't hold requests forever
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Reads from the socket until `buffer` holds one complete JSON value, which is
+// drained from the buffer and returned. IPC peers stream values back to back
+// without any length prefix, so the only framing is the JSON itself (geth
+// additionally terminates each value with a newline). Returns `None` if the
+// peer closes the socket between values.
+async fn read_json_value<R>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, SnoopError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 8192];
+    loop {
+        let start = buffer
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(buffer.len());
+        buffer.drain(..start);
+        if !buffer.is_empty() {
+            let mut values =
+                serde_json::Deserializer::from_slice(buffer).into_iter::<serde::de::IgnoredAny>();
+            match values.next() {
+                Some(Ok(_)) => {
+                    let end = values.byte_offset();
+                    return Ok(Some(buffer.drain(..end).collect()));
+                }
+                Some(Err(e)) if e.is_eof() => {}
+                None => {}
+                // not JSON at all, hand back whatever the peer sent
+                Some(Err(_)) => return Ok(Some(std::mem::take(buffer))),
+            }
+        }
+
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return if buffer.is_empty() {
+                Ok(None)
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "ipc socket closed in the middle of a message",
+                )
+                .into())
+            };
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+pub async fn get_response(
+    ipc_path: &Path,
+    dest_request: Request<Body>,
+) -> Result<(Response<Body>, String), SnoopError> {
+    let request_bytes = hyper::body::to_bytes(dest_request.into_body()).await?;
+    let expects_response = RpcBody::parse(std::str::from_utf8(&request_bytes)?).expects_response();
+
+    let start = Instant::now();
+    let exchange = async {
+        let mut stream = UnixStream::connect(ipc_path).await?;
+        stream.write_all(&request_bytes).await?;
+        stream.write_all(b"\n").await?;
+        stream.flush().await?;
+
+        if !expects_response {
+            return Ok((start.elapsed(), vec![]));
+        }
+        stream.readable().await?;
+        let ttfb = start.elapsed();
+        let response_bytes = read_json_value(&mut stream, &mut vec![])
+            .await?
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "ipc socket closed before a response was received",
+                )
+            })?;
+        Ok::<_, SnoopError>((ttfb, response_bytes))
+    };
+    let (ttfb, response_bytes) = timeout(RESPONSE_TIMEOUT, exchange).await.map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "ipc upstream didn't answer within {}s",
+                RESPONSE_TIMEOUT.as_secs()
+            ),
+        )
+    })??;
+    let timing = Timing {
+        ttfb,
+        total: start.elapsed(),
+    };
+    let response_json = format_response_json(&response_bytes);
+
+    let mut source_response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(response_bytes))?;
+    source_response.extensions_mut().insert(timing);
+
+    Ok((source_response, response_json))
+}
+
+// Binds the listening socket, replacing a stale socket file left behind by a
+// previous run but refusing to touch a socket that is still being served.
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener, std::io::Error> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "socket is already being served by another process",
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // The socket is created with the umask's permissions, so create it in a
+    // directory only we can enter and move it into place once its mode is set
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let staging = parent.join(format!(".json_rpc_snoop.{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("ipc");
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    result
+}
+
+pub async fn serve(listener: UnixListener, context: SnoopContext) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_connection(stream, context.clone()));
+            }
+            Err(e) => print_event(&format!("ipc accept error: {}", e), &context),
+        }
+    }
+}
+
+// Each message is wrapped in an http request so it goes through exactly the
+// same logging and forwarding as requests received over tcp. Messages are
+// handled concurrently and responses written back as they complete, which is
+// fine since JSON-RPC clients match responses by id.
+async fn serve_connection(stream: UnixStream, context: SnoopContext) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<hyper::body::Bytes>();
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(response_bytes) = receiver.recv().await {
+            if writer.write_all(&response_bytes).await.is_err()
+                || writer.write_all(b"\n").await.is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut buffer = Vec::new();
+    while let Ok(Some(message)) = read_json_value(&mut reader, &mut buffer).await {
+        let expects_response = std::str::from_utf8(&message)
+            .map(|json| RpcBody::parse(json).expects_response())
+            .unwrap_or(true);
+        let source_request = match Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("content-type", "application/json")
+            .body(Body::from(message))
+        {
+            Ok(source_request) => source_request,
+            Err(_) => continue,
+        };
+        let context = context.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            // dropped requests and responses are simply never answered
+            if let Ok(response) = handle_request(context, ClientAddr::Ipc, source_request).await {
+                match hyper::body::to_bytes(response.into_body()).await {
+                    Ok(response_bytes) if expects_response && !response_bytes.is_empty() => {
+                        let _ = sender.send(response_bytes);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    drop(sender);
+    let _ = writer_task.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads values until the peer closes the socket
+    async fn read_all(stream: &mut UnixStream) -> Vec<String> {
+        let mut buffer = vec![];
+        let mut values = vec![];
+        while let Some(value) = read_json_value(stream, &mut buffer).await.unwrap() {
+            values.push(String::from_utf8(value).unwrap());
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn reads_several_values_from_one_read() {
+        let (mut reader, mut writer) = UnixStream::pair().unwrap();
+        writer
+            .write_all(b"{\"id\":1}{\"id\":2}\n [3,\n4]\n")
+            .await
+            .unwrap();
+        drop(writer);
+        assert_eq!(
+            read_all(&mut reader).await,
+            ["{\"id\":1}", "{\"id\":2}", "[3,\n4]"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_value_split_across_reads() {
+        let (mut reader, mut writer) = UnixStream::pair().unwrap();
+        let written = tokio::spawn(async move {
+            writer.write_all(b"{\"result\":\"0x").await.unwrap();
+            writer.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.write_all(b"1\"}\n{\"id\"").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.write_all(b":2}").await.unwrap();
+        });
+        assert_eq!(
+            read_all(&mut reader).await,
+            ["{\"result\":\"0x1\"}", "{\"id\":2}"]
+        );
+        written.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_nothing_behind_after_a_trailing_newline() {
+        let (mut reader, mut writer) = UnixStream::pair().unwrap();
+        writer.write_all(b"{\"id\":1}\n\n").await.unwrap();
+        let mut buffer = vec![];
+        let value = read_json_value(&mut reader, &mut buffer).await.unwrap();
+        assert_eq!(value.as_deref(), Some(&b"{\"id\":1}"[..]));
+        drop(writer);
+        assert!(read_json_value(&mut reader, &mut buffer)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_value_cut_short() {
+        let (mut reader, mut writer) = UnixStream::pair().unwrap();
+        writer.write_all(b"{\"id\":1,\"res").await.unwrap();
+        drop(writer);
+        assert!(read_json_value(&mut reader, &mut vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hands_back_what_isnt_json() {
+        let (mut reader, mut writer) = UnixStream::pair().unwrap();
+        writer.write_all(b"hello\n").await.unwrap();
+        drop(writer);
+        assert_eq!(read_all(&mut reader).await, ["hello\n"]);
+    }
+
+    // A fresh directory per test, as `bind` stages the socket next to it
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "json_rpc_snoop_{}_ipc_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("snoop.ipc")
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn binds_with_the_given_mode() {
+        let path = socket_path("mode");
+        for requested in [0o600, 0o666] {
+            let listener = bind(&path, requested).unwrap();
+            assert_eq!(mode(&path), requested);
+            drop(listener);
+            std::fs::remove_file(&path).unwrap();
+        }
+        // the staging directory is cleaned up
+        let parent = path.parent().unwrap();
+        assert_eq!(std::fs::read_dir(parent).unwrap().count(), 0);
+        std::fs::remove_dir(parent).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaces_a_stale_socket() {
+        let path = socket_path("stale");
+        // the listener goes away but its socket file stays
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = bind(&path, 0o600).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        let client = UnixStream::connect(&path).await.unwrap();
+        assert!(listener.accept().await.is_ok());
+        drop(client);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_live_sockets_and_other_files_alone() {
+        let path = socket_path("in_use");
+        let served = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let error = bind(&path, 0o600).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+        drop(served);
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::write(&path, "not a socket").unwrap();
+        let error = bind(&path, 0o600).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
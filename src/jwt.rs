@@ -0,0 +1,171 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The Engine API spec requires tokens to be issued within 60 seconds of now
+const MAX_IAT_DRIFT_SECS: u64 = 60;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    typ: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Claims {
+    iat: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn parse_secret(contents: &str) -> Option<Vec<u8>> {
+    let hex = contents.trim();
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    // checked up front since from_str_radix also accepts a leading `+`
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Reads a hex encoded 32 byte secret (optionally 0x prefixed) in the same
+// format as the `jwtsecret` files used by execution and consensus clients
+pub fn read_secret(path: &Path) -> Result<Vec<u8>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read jwt secret {}: {}", path.display(), e))?;
+    parse_secret(&contents).ok_or_else(|| {
+        format!(
+            "jwt secret {} must contain exactly 32 hex encoded bytes",
+            path.display()
+        )
+    })
+}
+
+fn sign(secret: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Mints an HS256 token issued now
+pub fn mint(secret: &[u8]) -> String {
+    mint_at(secret, now())
+}
+
+fn mint_at(secret: &[u8], iat: u64) -> String {
+    let header = Header {
+        alg: "HS256".to_string(),
+        typ: Some("JWT".to_string()),
+    };
+    let claims = Claims { iat };
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).expect("should serialize header")),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("should serialize claims")),
+    );
+    let signature = URL_SAFE_NO_PAD.encode(sign(secret, &message));
+    format!("{}.{}", message, signature)
+}
+
+// Checks an `Authorization` header value of the form `Bearer <token>`
+pub fn validate(secret: &[u8], authorization: Option<&str>) -> Result<(), String> {
+    let token = authorization
+        .ok_or_else(|| "missing token".to_string())?
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| "authorization is not a bearer token".to_string())?
+        .trim();
+
+    let (message, signature) = token
+        .rsplit_once('.')
+        .ok_or_else(|| "malformed token".to_string())?;
+    let (header, claims) = message
+        .split_once('.')
+        .ok_or_else(|| "malformed token".to_string())?;
+
+    let decode = |segment: &str| {
+        URL_SAFE_NO_PAD
+            .decode(segment)
+            .map_err(|e| format!("malformed token: {}", e))
+    };
+    let header = serde_json::from_slice::<Header>(&decode(header)?)
+        .map_err(|e| format!("malformed token header: {}", e))?;
+    if header.alg != "HS256" {
+        return Err(format!("unsupported algorithm {}", header.alg));
+    }
+
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac.verify_slice(&decode(signature)?)
+        .map_err(|_| "signature verification failed".to_string())?;
+
+    let claims = serde_json::from_slice::<Claims>(&decode(claims)?)
+        .map_err(|e| format!("malformed token claims: {}", e))?;
+    if now().abs_diff(claims.iat) > MAX_IAT_DRIFT_SECS {
+        return Err(format!("stale token (iat {})", claims.iat));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0x3a1b5fbc6e8fbcd5e0e7e0a5b7a9c0d4f8a3c2b1e0f9d8c7b6a5f4e3d2c1b0a9";
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
+    }
+
+    #[test]
+    fn parses_secrets() {
+        let secret = parse_secret(&format!("{}\n", SECRET)).unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(secret[0], 0x3a);
+        assert_eq!(parse_secret(&SECRET[2..]), Some(secret));
+    }
+
+    #[test]
+    fn rejects_malformed_secrets() {
+        assert_eq!(parse_secret(&SECRET[..64]), None);
+        assert_eq!(parse_secret(&format!("+f{}", &SECRET[4..])), None);
+        assert_eq!(parse_secret(&SECRET.replace('a', "g")), None);
+    }
+
+    #[test]
+    fn validates_minted_tokens() {
+        let secret = parse_secret(SECRET).unwrap();
+        assert_eq!(validate(&secret, Some(&bearer(&mint(&secret)))), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        let secret = parse_secret(SECRET).unwrap();
+        let other = vec![0u8; 32];
+        assert_eq!(validate(&secret, None), Err("missing token".to_string()));
+        assert!(validate(&secret, Some(&mint(&secret))).is_err());
+        assert_eq!(
+            validate(&secret, Some(&bearer(&mint(&other)))),
+            Err("signature verification failed".to_string())
+        );
+        let stale = mint_at(&secret, now() - 2 * MAX_IAT_DRIFT_SECS);
+        assert!(validate(&secret, Some(&bearer(&stale)))
+            .unwrap_err()
+            .starts_with("stale token"));
+        assert!(validate(&secret, Some(&bearer("not.a.token"))).is_err());
+    }
+}
@@ -1,36 +1,78 @@
-use async_mutex::Mutex;
-use chrono;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use hyper::body::{Bytes, HttpBody};
 use hyper::http::header::{HeaderMap, HeaderName, HeaderValue};
 use hyper::http::uri::Scheme;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri, Version};
 use hyper_tls::HttpsConnector;
-use rand::{Rng, SeedableRng};
-use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::time::{sleep, Duration, Instant};
 
+mod admin;
+use admin::Pause;
 mod utils;
-use utils::{PacketType, RpcErrorResponse, RpcRequest, SnoopError, SuppressType};
+use utils::{
+    ClientAddr, Endpoint, PacketType, Pattern, RpcBody, RpcError, RpcErrorResponse, RpcId,
+    RpcRequest, SnoopError, SuppressType,
+};
+mod chaos;
 mod colors;
+mod compare;
+mod config;
 use colors::{color_treat, Colors};
+use compare::Comparison;
+mod exchange;
+use exchange::{Exchange, WEBSOCKET_METHOD};
+mod filter;
+mod har;
+use har::HarLog;
+mod ipc;
+mod jwt;
+mod metrics;
+mod mutate;
+use metrics::Metrics;
+mod replay;
+use replay::Replay;
+mod settings;
+use settings::Settings;
+mod traffic_log;
+use traffic_log::TrafficLog;
+mod tui;
+use tui::Tui;
+mod upstream;
+use upstream::{Routes, Strategy, Timing, Upstream, Upstreams};
+mod websocket;
 
 #[derive(Debug)]
 struct Inner {
-    dest_uri: Uri,
-    rng: Mutex<rand::rngs::StdRng>,
-    suppress_method: Option<HashMap<String, (i32, SuppressType)>>,
-    suppress_path: Option<HashMap<String, (i32, SuppressType)>>,
-    override_rpc: Option<Vec<String>>,
+    upstreams: Upstreams,
+    routes: Routes,
+    // 5xx responses may come from calls that were already carried out, so
+    // only connection errors fail over unless asked to
+    failover_on_server_error: bool,
+    jwt_secret: Option<Vec<u8>>,
+    inbound_jwt_secret: Option<Vec<u8>>,
+    // every random decision about an exchange comes from an rng seeded with
+    // `seed` and the exchange's sequence number, see `exchange_rng`
+    seed: u64,
+    sequence: AtomicU64,
+    settings: RwLock<Arc<Settings>>,
+    pause: Pause,
     colors: Colors,
-    drop_request_rate: f32,
-    drop_response_rate: f32,
-    log_headers: bool,
+    traffic_log: Option<TrafficLog>,
+    har_log: Option<HarLog>,
+    metrics: Option<Metrics>,
+    tui: Option<Tui>,
+    replay: Option<Replay>,
+    comparison: Option<Comparison>,
 }
 
 #[derive(Clone, Debug)]
@@ -38,9 +80,60 @@ struct SnoopContext {
     inner: Arc<Inner>,
 }
 
-fn is_rpc_modules_request(request_json: &str) -> bool {
-    serde_json::from_str::<RpcRequest>(request_json)
-        .map(|rpc_request| rpc_request.method == "rpc_modules")
+impl SnoopContext {
+    // the settings in force right now, a reload may replace them at any time
+    fn settings(&self) -> Arc<Settings> {
+        self.inner.settings.read().unwrap().clone()
+    }
+
+    // The sequence number of the next exchange. It is taken once a request has
+    // been authenticated and let through any pause, so rejected requests don't
+    // shift the numbers of the ones after them.
+    fn next_sequence(&self) -> u64 {
+        self.inner.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // A fresh rng for exchange `sequence`, or for one frame of a websocket
+    // connection given its direction and number in that direction. It only
+    // depends on the seed and those numbers, so a chaos run can be replayed
+    // with `--seed` however the concurrent exchanges (and frames) interleave.
+    fn exchange_rng(&self, sequence: u64, frame: Option<(PacketType, u64)>) -> StdRng {
+        let mut seed = [0u8; 32];
+        seed[..8].copy_from_slice(&self.inner.seed.to_le_bytes());
+        seed[8..16].copy_from_slice(&sequence.to_le_bytes());
+        if let Some((direction, number)) = frame {
+            seed[16] = match direction {
+                PacketType::Request | PacketType::RequestDropped(_) => 1,
+                PacketType::Response | PacketType::ResponseDropped(_) => 2,
+            };
+            seed[24..].copy_from_slice(&number.to_le_bytes());
+        }
+        StdRng::from_seed(seed)
+    }
+
+    fn replace_settings(&self, settings: Settings) {
+        *self.inner.settings.write().unwrap() = Arc::new(settings);
+    }
+
+    // Applies `change` to a copy of the settings and swaps it in if it
+    // succeeds, returning its result and the options that changed
+    fn update_settings<T>(
+        &self,
+        change: impl FnOnce(&mut Settings) -> Result<T, String>,
+    ) -> Result<(T, Vec<String>), String> {
+        let mut current = self.inner.settings.write().unwrap();
+        let mut settings = Settings::clone(&current);
+        let result = change(&mut settings)?;
+        let changes = current.diff(&settings);
+        *current = Arc::new(settings);
+        Ok((result, changes))
+    }
+}
+
+fn is_rpc_modules_request(call: &Option<RpcRequest>) -> bool {
+    // notifications get no response so there is nothing to override
+    call.as_ref()
+        .map(|rpc_request| rpc_request.method == "rpc_modules" && !rpc_request.is_notification())
         .unwrap_or(false)
 }
 
@@ -50,23 +143,65 @@ fn get_hostport(uri: &Uri) -> HeaderValue {
         hostport.push_str(host);
     }
     if let Some(port) = uri.port() {
-        hostport.push_str(":");
+        hostport.push(':');
         hostport.push_str(port.as_str());
     }
 
     HeaderValue::from_str(&hostport).expect("should be valid header")
 }
 
-fn get_rpc_modules_override(rpc_modules: &Vec<String>) -> (Response<Body>, String) {
-    let mut response_json = "{\n  \"jsonrpc\": \"2.0\",\n  \"result\": {\n".to_string();
-    if let Some(module) = rpc_modules.first() {
-        response_json.push_str(&format!("    \"{}\": \"1.0\"", module))
-    }
-    for module in rpc_modules.iter().skip(1) {
-        response_json.push_str(",\n");
-        response_json.push_str(&format!("    \"{}\": \"1.0\"", module));
-    }
-    response_json.push_str("\n  },\n  \"id\": 1\n}");
+// The answer to a call of a batch that a fault or `--rpc-modules-override`
+// gives instead of the upstream, `Some(None)` for a notification answered that
+// way. `None` means the call is forwarded.
+fn local_answer(
+    element: &(serde_json::Value, Option<RpcRequest>),
+    fault: Option<&chaos::ErrorRule>,
+    override_rpc: Option<&[String]>,
+    request_path: &str,
+) -> Option<Option<serde_json::Value>> {
+    let (_, call) = element;
+    let answer = match (fault, override_rpc, call) {
+        (Some(rule), _, call) if rule.target.matches_call(call, request_path) => {
+            rule.fault.call_response(element)
+        }
+        (_, Some(rpc_modules), Some(RpcRequest { id: Some(id), .. }))
+            if is_rpc_modules_request(call) =>
+        {
+            get_rpc_modules_result(rpc_modules, id)
+        }
+        _ => return None,
+    };
+    Some(Some(answer).filter(|_| !matches!(call, Some(call) if call.is_notification())))
+}
+
+// True if `--rpc-modules-override` answers every call of the request that
+// expects an answer, which leaves the comparison endpoint nothing to compare
+fn is_answered_locally(rpc_body: &RpcBody, context: &SnoopContext) -> bool {
+    context.settings().override_rpc.is_some()
+        && match rpc_body {
+            RpcBody::Single(call) => is_rpc_modules_request(call),
+            RpcBody::Batch(calls) => calls.iter().all(|(_, call)| {
+                is_rpc_modules_request(call)
+                    || call.as_ref().is_some_and(RpcRequest::is_notification)
+            }),
+        }
+}
+
+fn get_rpc_modules_result(rpc_modules: &[String], id: &RpcId) -> serde_json::Value {
+    let modules = rpc_modules
+        .iter()
+        .map(|module| (module.clone(), serde_json::Value::from("1.0")))
+        .collect::<serde_json::Map<_, _>>();
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "result": modules,
+        "id": id,
+    })
+}
+
+fn get_rpc_modules_override(rpc_modules: &[String], id: &RpcId) -> (Response<Body>, String) {
+    let response_json = serde_json::to_string_pretty(&get_rpc_modules_result(rpc_modules, id))
+        .expect("should serialize json value");
 
     let response = Response::builder()
         .status(200)
@@ -77,10 +212,139 @@ fn get_rpc_modules_override(rpc_modules: &Vec<String>) -> (Response<Body>, Strin
     (response, response_json)
 }
 
-async fn copy_request(
-    source_request: Request<Body>,
+// Answers each call of `calls` that expects a response with its own error,
+// for when the upstream didn't answer them one by one
+fn call_errors<'a>(
+    calls: impl IntoIterator<Item = &'a (serde_json::Value, Option<RpcRequest>)>,
+    message: &str,
+) -> Vec<serde_json::Value> {
+    calls
+        .into_iter()
+        .filter_map(|(_, call)| match call {
+            Some(RpcRequest { id: Some(id), .. }) => Some(
+                serde_json::to_value(RpcErrorResponse {
+                    id: id.clone(),
+                    jsonrpc: "2.0".to_string(),
+                    error: RpcError {
+                        code: -32603,
+                        message: format!("Error processing response: {}", message),
+                    },
+                })
+                .expect("should serialize error"),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+// Forwards the elements of a batch that `local` doesn't answer upstream and
+// appends the local answers (overridden `rpc_modules` results and injected
+// errors) to the batch response. `local` answers notifications with `None`.
+// Batch responses may come in any order, so clients match them up by id.
+async fn get_batch_response_with_local_answers(
+    mut source_request: SourceRequest,
+    calls: &[(serde_json::Value, Option<RpcRequest>)],
+    local: impl Fn(&(serde_json::Value, Option<RpcRequest>)) -> Option<Option<serde_json::Value>>,
     context: &SnoopContext,
-) -> Result<(Request<Body>, String), SnoopError> {
+) -> Result<(Response<Body>, String, Option<String>), SnoopError> {
+    let forwarded_calls = calls
+        .iter()
+        .filter(|element| local(element).is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    let forwarded = forwarded_calls
+        .iter()
+        .map(|(element, _)| element.clone())
+        .collect::<Vec<_>>();
+
+    let mut json_headers = HeaderMap::new();
+    json_headers.insert("content-type", HeaderValue::from_static("application/json"));
+    let (status, version, headers, timing, mut elements, upstream) = if forwarded.is_empty() {
+        (
+            StatusCode::OK,
+            Version::HTTP_11,
+            json_headers,
+            None,
+            vec![],
+            None,
+        )
+    } else {
+        let forwarded_bytes = serde_json::to_vec(&forwarded).expect("should serialize json array");
+        source_request
+            .headers
+            .insert("content-length", HeaderValue::from(forwarded_bytes.len()));
+        source_request.body = forwarded_bytes.into();
+
+        let forwarded_body = RpcBody::Batch(forwarded_calls.clone());
+        let (response, response_json, upstream) =
+            route(&source_request, &forwarded_body, context).await?;
+        match serde_json::from_str::<Vec<serde_json::Value>>(&response_json) {
+            Ok(elements) => {
+                let (parts, _) = response.into_parts();
+                (
+                    parts.status,
+                    parts.version,
+                    parts.headers,
+                    parts.extensions.get::<Timing>().copied(),
+                    elements,
+                    Some(upstream),
+                )
+            }
+            // the upstream rejected the batch as a whole, so answer each
+            // forwarded call with an error next to the overridden answers
+            Err(_) => {
+                let message = match serde_json::from_str::<RpcErrorResponse>(&response_json) {
+                    Ok(rpc_error) => rpc_error.error.message,
+                    Err(_) => format!("upstream answered with status {}", response.status()),
+                };
+                (
+                    StatusCode::OK,
+                    response.version(),
+                    json_headers,
+                    response.extensions().get::<Timing>().copied(),
+                    call_errors(&forwarded_calls, &message),
+                    Some(upstream),
+                )
+            }
+        }
+    };
+
+    elements.extend(calls.iter().filter_map(local).flatten());
+
+    let response_json =
+        serde_json::to_string_pretty(&elements).expect("should serialize json array");
+    let mut source_response = Response::builder()
+        .status(status)
+        .version(version)
+        .body(Body::from(response_json.clone()))?;
+    for (key, value) in headers.iter() {
+        source_response
+            .headers_mut()
+            .insert(key.clone(), value.clone());
+    }
+    source_response
+        .headers_mut()
+        .insert("content-length", HeaderValue::from(response_json.len()));
+    if let Some(timing) = timing {
+        source_response.extensions_mut().insert(timing);
+    }
+
+    Ok((source_response, response_json, upstream))
+}
+
+// An incoming request read into memory so it can be sent to more than one
+// upstream if the first one fails
+#[derive(Clone)]
+struct SourceRequest {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+async fn read_request(
+    source_request: Request<Body>,
+) -> Result<(SourceRequest, String), SnoopError> {
     let (parts, request_body) = source_request.into_parts();
     let request_bytes = hyper::body::to_bytes(request_body).await?;
 
@@ -93,48 +357,90 @@ async fn copy_request(
         }
     };
 
-    let construct_uri = !parts.uri.path().eq("/") || parts.uri.query().is_some();
+    let source_request = SourceRequest {
+        method: parts.method,
+        uri: parts.uri,
+        headers: parts.headers,
+        body: request_bytes,
+    };
+
+    Ok((source_request, request_json))
+}
+
+fn copy_request(
+    source_request: &SourceRequest,
+    upstream: &Upstream,
+    context: &SnoopContext,
+) -> Result<Request<Body>, SnoopError> {
+    let construct_uri = !source_request.uri.path().eq("/") || source_request.uri.query().is_some();
     let mut dest_request = if construct_uri {
-        let mut dest_uri =
-            utils::remove_trailing_slashes(&context.inner.dest_uri.to_string()).to_string();
-        dest_uri.push_str(parts.uri.path());
-        if let Some(query) = parts.uri.query() {
-            dest_uri.push_str("?");
+        let mut dest_uri = utils::remove_trailing_slashes(&upstream.uri.to_string()).to_string();
+        dest_uri.push_str(source_request.uri.path());
+        if let Some(query) = source_request.uri.query() {
+            dest_uri.push('?');
             dest_uri.push_str(query);
         }
-        let dest_uri =
-            utils::parse_uri(&dest_uri).unwrap_or_else(|_| context.inner.dest_uri.clone());
+        let dest_uri = utils::parse_uri(&dest_uri).unwrap_or_else(|_| upstream.uri.clone());
         Request::builder()
-            .method(parts.method)
+            .method(source_request.method.clone())
             .uri(&dest_uri)
-            .body(Body::from(request_bytes))?
+            .body(Body::from(source_request.body.clone()))?
     } else {
         Request::builder()
-            .method(parts.method)
-            .uri(&context.inner.dest_uri)
-            .body(Body::from(request_bytes))?
+            .method(source_request.method.clone())
+            .uri(&upstream.uri)
+            .body(Body::from(source_request.body.clone()))?
     };
 
-    for (key, value) in parts.headers.iter() {
+    for (key, value) in source_request.headers.iter() {
         let mut value = value.clone();
         if key.as_str().eq("accept-encoding") {
             // we don't want fancy encoding of the response
             continue;
         }
         if key.as_str().eq("host") {
-            value = get_hostport(&context.inner.dest_uri)
+            value = get_hostport(&upstream.uri)
         }
         dest_request.headers_mut().insert(key.clone(), value);
     }
 
-    Ok((dest_request, request_json))
+    if let Some(jwt_secret) = &context.inner.jwt_secret {
+        // a fresh token per request so replayed or delayed traffic never
+        // carries a stale iat
+        dest_request.headers_mut().insert(
+            "authorization",
+            HeaderValue::from_str(&format!("Bearer {}", jwt::mint(jwt_secret)))
+                .expect("should be valid header"),
+        );
+    }
+
+    Ok(dest_request)
+}
+
+fn format_response_json(response_bytes: &[u8]) -> String {
+    if response_bytes.is_empty() {
+        "null".to_string()
+    } else {
+        match std::str::from_utf8(response_bytes) {
+            Ok(json_str) => jsonxf::pretty_print(json_str).unwrap_or_else(|_| json_str.to_string()),
+            Err(_) => {
+                // utf-8 error
+                "binary response returned".to_string()
+            }
+        }
+    }
 }
 
 async fn get_response(
     dest_request: Request<Body>,
-    context: &SnoopContext,
+    upstream: &Upstream,
 ) -> Result<(Response<Body>, String), SnoopError> {
-    let response = if context.inner.dest_uri.scheme() == Some(&Scheme::HTTPS) {
+    if let Some(ipc_path) = &upstream.ipc_path {
+        return ipc::get_response(ipc_path, dest_request).await;
+    }
+
+    let start = Instant::now();
+    let response = if upstream.uri.scheme() == Some(&Scheme::HTTPS) {
         let https = HttpsConnector::new();
         let dest_client = Client::builder().build::<_, hyper::Body>(https);
         dest_client.request(dest_request).await?
@@ -142,26 +448,18 @@ async fn get_response(
         let dest_client = Client::new();
         dest_client.request(dest_request).await?
     };
+    // hyper resolves the request once the response headers are in
+    let ttfb = start.elapsed();
 
     let (parts, response_body) = response.into_parts();
     let response_bytes = hyper::body::to_bytes(response_body).await?;
-
-    let response_json = {
-        if response_bytes.is_empty() {
-            "null".to_string()
-        } else {
-            match std::str::from_utf8(&response_bytes) {
-                Ok(json_str) => {
-                    jsonxf::pretty_print(json_str).unwrap_or_else(|_| json_str.to_string())
-                }
-                Err(_) => {
-                    // utf-8 error
-                    "binary response returned".to_string()
-                }
-            }
-        }
+    let timing = Timing {
+        ttfb,
+        total: start.elapsed(),
     };
 
+    let response_json = format_response_json(&response_bytes);
+
     let mut source_response = Response::builder()
         .status(parts.status)
         .version(parts.version)
@@ -172,33 +470,229 @@ async fn get_response(
             .headers_mut()
             .insert(key.clone(), value.clone());
     }
+    source_response.extensions_mut().insert(timing);
 
     Ok((source_response, response_json))
 }
 
+// Sends the request to the upstreams in the order chosen by the configured
+// strategy, failing over to the next one on connection errors (and 5xx
+// responses with `--failover-on-5xx`). Returns the name of the upstream that
+// produced the response.
+async fn forward(
+    source_request: &SourceRequest,
+    upstreams: &Upstreams,
+    context: &SnoopContext,
+) -> Result<(Response<Body>, String, String), SnoopError> {
+    let candidates = upstreams.candidates();
+    for (i, upstream) in candidates.iter().enumerate() {
+        let dest_request = copy_request(source_request, upstream, context)?;
+        let start = Instant::now();
+        let result = get_response(dest_request, upstream).await;
+        let failure = match &result {
+            Ok((response, _))
+                if response.status().is_server_error()
+                    && context.inner.failover_on_server_error =>
+            {
+                Some(format!("status {}", response.status()))
+            }
+            Ok(_) => None,
+            Err(e) => Some(format!("{:?}", e)),
+        };
+        match (failure, candidates.get(i + 1)) {
+            (None, _) => upstream.record_success(start.elapsed()),
+            (Some(failure), Some(next)) => {
+                upstream.record_failure();
+                print_event(
+                    &format!(
+                        "UPSTREAM {} failed ({}), failing over to {}",
+                        upstream.name(),
+                        failure,
+                        next.name()
+                    ),
+                    context,
+                );
+                continue;
+            }
+            // every upstream failed, so pass the last failure on to the client
+            (Some(_), None) => upstream.record_failure(),
+        }
+        return result.map(|(response, response_json)| (response, response_json, upstream.name()));
+    }
+    Err(SnoopError::Io(std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "no upstream to forward to",
+    )))
+}
+
+fn route_of(call: &Option<RpcRequest>, context: &SnoopContext) -> Option<usize> {
+    call.as_ref()
+        .and_then(|call| context.inner.routes.find(&call.method))
+}
+
+// The calls of a batch that take the same route
+type BatchPart<'a> = (
+    Option<usize>,
+    Vec<&'a (serde_json::Value, Option<RpcRequest>)>,
+);
+
+// Groups the calls of a batch by the route they take, in the order each route
+// is first used
+fn split_batch<'a>(
+    calls: &'a [(serde_json::Value, Option<RpcRequest>)],
+    context: &SnoopContext,
+) -> Vec<BatchPart<'a>> {
+    let mut groups = Vec::<BatchPart>::new();
+    for element in calls {
+        let route = route_of(&element.1, context);
+        match groups.iter_mut().find(|(r, _)| *r == route) {
+            Some((_, elements)) => elements.push(element),
+            None => groups.push((route, vec![element])),
+        }
+    }
+    groups
+}
+
+// Puts the responses to the parts of a split batch back in the order of the
+// calls they answer. Responses matching no call's id go last.
+fn in_call_order(
+    calls: &[(serde_json::Value, Option<RpcRequest>)],
+    responses: Vec<serde_json::Value>,
+) -> Vec<serde_json::Value> {
+    pair_responses(calls, responses)
+        .into_iter()
+        .map(|(_, response)| response)
+        .collect()
+}
+
+// Picks the upstreams for the request from the `--route` table. Batches whose
+// calls route to different upstreams are split, each part is forwarded on its
+// own and the responses are merged back into a single batch response.
+async fn route(
+    source_request: &SourceRequest,
+    rpc_body: &RpcBody,
+    context: &SnoopContext,
+) -> Result<(Response<Body>, String, String), SnoopError> {
+    if let Some(replay) = &context.inner.replay {
+        return replay.respond(source_request, rpc_body);
+    }
+    let default = &context.inner.upstreams;
+    let calls = match rpc_body {
+        RpcBody::Single(call) => {
+            let upstreams = context
+                .inner
+                .routes
+                .upstreams(route_of(call, context), default);
+            return forward(source_request, upstreams, context).await;
+        }
+        RpcBody::Batch(calls) => calls,
+    };
+
+    let groups = split_batch(calls, context);
+    if groups.len() <= 1 {
+        let route = groups.first().and_then(|(route, _)| *route);
+        let upstreams = context.inner.routes.upstreams(route, default);
+        return forward(source_request, upstreams, context).await;
+    }
+
+    let parts = groups
+        .iter()
+        .map(|(route, group)| {
+            let forwarded = group
+                .iter()
+                .map(|(element, _)| element.clone())
+                .collect::<Vec<_>>();
+            let forwarded_bytes =
+                serde_json::to_vec(&forwarded).expect("should serialize json array");
+            let mut part = SourceRequest {
+                method: source_request.method.clone(),
+                uri: source_request.uri.clone(),
+                headers: source_request.headers.clone(),
+                body: forwarded_bytes.into(),
+            };
+            part.headers
+                .insert("content-length", HeaderValue::from(part.body.len()));
+            (part, context.inner.routes.upstreams(*route, default))
+        })
+        .collect::<Vec<_>>();
+    let results = futures_util::future::join_all(
+        parts
+            .iter()
+            .map(|(part, upstreams)| forward(part, upstreams, context)),
+    )
+    .await;
+
+    let mut elements = Vec::new();
+    let mut upstream_names = Vec::new();
+    // the parts are forwarded at the same time, so the batch takes as long as
+    // the slowest of them
+    let timing = results
+        .iter()
+        .filter_map(|result| result.as_ref().ok())
+        .filter_map(|(response, _, _)| response.extensions().get::<Timing>().copied())
+        .max_by_key(|timing| timing.total);
+    for ((_, group), result) in groups.into_iter().zip(results) {
+        let responses = result.as_ref().ok().and_then(|(_, response_json, _)| {
+            serde_json::from_str::<Vec<serde_json::Value>>(response_json).ok()
+        });
+        match (result, responses) {
+            (Ok((_, _, upstream)), Some(responses)) => {
+                elements.extend(responses);
+                upstream_names.push(upstream);
+            }
+            // answer each call in the part that failed with its own error
+            (result, _) => {
+                let message = match result {
+                    Ok((response, _, upstream)) => {
+                        upstream_names.push(upstream);
+                        format!("upstream answered with status {}", response.status())
+                    }
+                    Err(e) => format!("{:?}", e),
+                };
+                elements.extend(call_errors(group, &message));
+            }
+        }
+    }
+
+    let response_json = serde_json::to_string_pretty(&in_call_order(calls, elements))
+        .expect("should serialize json array");
+    let mut source_response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(response_json.clone()))?;
+    if let Some(timing) = timing {
+        source_response.extensions_mut().insert(timing);
+    }
+
+    Ok((source_response, response_json, upstream_names.join(", ")))
+}
+
 fn print_json(
     json: &str,
-    headers: &Vec<(HeaderName, HeaderValue)>,
+    headers: &[(HeaderName, HeaderValue)],
     json_type: PacketType,
     msg_info: &str,
     status: Option<StatusCode>,
     context: &SnoopContext,
 ) {
+    // the TUI draws exchanges itself
+    if context.inner.tui.is_some() {
+        return;
+    }
     let now = chrono::offset::Local::now()
         .format("%b %e %T%.3f %Y")
         .to_string();
-    let header_string =
-        |headers: &Vec<(HeaderName, HeaderValue)>, context: &SnoopContext| -> String {
-            if !context.inner.log_headers || headers.is_empty() {
-                String::new()
-            } else {
-                let mut result = String::from("headers:\n");
-                for (key, value) in headers {
-                    result.push_str(&format!("    ({},{:?})\n", key, value))
-                }
-                result
+    let header_string = |headers: &[(HeaderName, HeaderValue)], context: &SnoopContext| -> String {
+        if !context.settings().log_headers || headers.is_empty() {
+            String::new()
+        } else {
+            let mut result = String::from("headers:\n");
+            for (key, value) in headers {
+                result.push_str(&format!("    ({},{:?})\n", key, value))
             }
-        };
+            result
+        }
+    };
     let msg_string = |msg: &str| -> String {
         if msg.is_empty() || msg.eq("/") {
             String::new()
@@ -219,12 +713,12 @@ fn print_json(
 
     let status_str = status
         .map(|s| format!(" (status {})", s))
-        .unwrap_or_else(String::new);
+        .unwrap_or_default();
 
     println!(
         "{} {}{} {}\n{}{}",
         now,
-        json_type.to_string(),
+        json_type,
         status_str,
         msg_string(msg_info),
         header_string(headers, context),
@@ -232,35 +726,101 @@ fn print_json(
     );
 }
 
-async fn get_random_packet_type(direction: PacketType, context: &SnoopContext) -> PacketType {
+// Prints a timestamped line for events that don't carry any JSON
+fn print_event(msg: &str, context: &SnoopContext) {
+    if let Some(tui) = &context.inner.tui {
+        tui.set_status(msg);
+        return;
+    }
+    let now = chrono::offset::Local::now()
+        .format("%b %e %T%.3f %Y")
+        .to_string();
+    println!(
+        "{} {}",
+        now,
+        color_treat(msg.to_string(), context.inner.colors.white).trim_end()
+    );
+}
+
+fn get_random_packet_type(
+    direction: PacketType,
+    rng: &mut StdRng,
+    context: &SnoopContext,
+) -> PacketType {
+    let settings = context.settings();
     match direction {
         PacketType::Request | PacketType::RequestDropped(_) => {
-            if context.inner.drop_request_rate == 0.0 {
+            if settings.drop_request_rate == 0.0 {
                 PacketType::Request
+            } else if rng.gen::<f32>() <= settings.drop_request_rate {
+                PacketType::RequestDropped(12.0)
             } else {
-                let mut rng = context.inner.rng.lock().await;
-                if rng.gen::<f32>() <= context.inner.drop_request_rate {
-                    PacketType::RequestDropped(12.0)
-                } else {
-                    PacketType::Request
-                }
+                PacketType::Request
             }
         }
         PacketType::Response | PacketType::ResponseDropped(_) => {
-            if context.inner.drop_response_rate == 0.0 {
+            if settings.drop_response_rate == 0.0 {
                 PacketType::Response
+            } else if rng.gen::<f32>() <= settings.drop_response_rate {
+                PacketType::ResponseDropped(12.0)
             } else {
-                let mut rng = context.inner.rng.lock().await;
-                if rng.gen::<f32>() <= context.inner.drop_response_rate {
-                    PacketType::ResponseDropped(12.0)
-                } else {
-                    PacketType::Response
-                }
+                PacketType::Response
             }
         }
     }
 }
 
+// The delay the first matching `--delay-request` (or `--delay-response`) rule
+// adds to this exchange
+fn sample_delay(
+    direction: PacketType,
+    rpc_body: &RpcBody,
+    request_path: &str,
+    rng: &mut StdRng,
+    context: &SnoopContext,
+) -> Option<Duration> {
+    let settings = context.settings();
+    let rules = match direction {
+        PacketType::Request | PacketType::RequestDropped(_) => &settings.delay_request,
+        PacketType::Response | PacketType::ResponseDropped(_) => &settings.delay_response,
+    };
+    let rule = chaos::delay_rule(rules, rpc_body, request_path)?;
+    Some(rule.distribution.sample(rng))
+}
+
+// The first `--inject-error` rule that fires for this exchange
+fn inject_fault(
+    rpc_body: &RpcBody,
+    request_path: &str,
+    rng: &mut StdRng,
+    context: &SnoopContext,
+) -> Option<chaos::ErrorRule> {
+    let settings = context.settings();
+    let rules = settings.inject_error.as_ref()?;
+    chaos::injected_fault(rules, rpc_body, request_path, rng).cloned()
+}
+
+// The first `--mutate-response` rule that fires mangles a successful response,
+// returning the body to send, the body to log and what was changed
+fn mutate_response(
+    rpc_body: &RpcBody,
+    request_path: &str,
+    response_json: &str,
+    rng: &mut StdRng,
+    context: &SnoopContext,
+) -> Option<(String, String, String)> {
+    let settings = context.settings();
+    let rules = settings.mutate_response.as_ref()?;
+    mutate::mutate_response(rules, rpc_body, request_path, response_json, rng)
+}
+
+fn delay_tag(delay: Option<Duration>) -> Vec<String> {
+    delay
+        .map(|delay| format!("[delayed {}ms]", delay.as_millis()))
+        .into_iter()
+        .collect()
+}
+
 fn copy_headers(headers: &HeaderMap<HeaderValue>) -> Vec<(HeaderName, HeaderValue)> {
     headers
         .iter()
@@ -268,10 +828,20 @@ fn copy_headers(headers: &HeaderMap<HeaderValue>) -> Vec<(HeaderName, HeaderValu
         .collect()
 }
 
+// Rules can be switched off from the TUI
+fn suppression_enabled(rule: &str, context: &SnoopContext) -> bool {
+    context
+        .inner
+        .tui
+        .as_ref()
+        .map(|tui| tui.rule_enabled(rule))
+        .unwrap_or(true)
+}
+
 fn suppress_log(
     message_type: PacketType,
-    request_json: &String,
-    request_path: &String,
+    method: Option<&str>,
+    request_path: &str,
     request_type: PacketType,
     response_type: PacketType,
     context: &SnoopContext,
@@ -282,174 +852,806 @@ fn suppress_log(
         // if either request or response is dropped, don't suppress
         return None;
     }
-    if let Some((method, lines, suppress_type)) = serde_json::from_str::<RpcRequest>(request_json)
-        .ok()
-        .and_then(|rpc_request| {
-            context.inner.suppress_method.as_ref().and_then(|method| {
-                method
-                    .get(&rpc_request.method)
-                    .map(|(l, s)| (rpc_request.method.clone(), l, s))
-            })
+    let settings = context.settings();
+    // a rule switched off in the TUI doesn't count as a match, so a later
+    // rule can still apply
+    let first_match = |rules: &Option<Vec<(Pattern, (i32, SuppressType))>>, kind, s: &str| {
+        rules.as_ref().and_then(|rules| {
+            rules
+                .iter()
+                .find(|(pattern, _)| {
+                    pattern.matches(s)
+                        && suppression_enabled(&tui::rule_name(kind, &pattern.to_string()), context)
+                })
+                .map(|(_, rule)| *rule)
         })
-    {
-        if message_type.suppress(*suppress_type) {
-            return Some((*lines, format!("[method {}]", method)));
+    };
+    if let Some(method) = method {
+        if let Some((lines, suppress_type)) =
+            first_match(&settings.suppress_method, "method", method)
+        {
+            if message_type.suppress(suppress_type) {
+                return Some((lines, format!("[method {}]", method)));
+            }
         }
     }
-    if let Some((lines, suppress_type)) = context
-        .inner
-        .suppress_path
-        .as_ref()
-        .and_then(|path| path.get(request_path))
+    let request_path = match request_path.split_once('?') {
+        Some((path, _)) if settings.suppress_ignore_query => path,
+        _ => request_path,
+    };
+    if let Some((lines, suppress_type)) = first_match(&settings.suppress_path, "path", request_path)
     {
-        if message_type.suppress(*suppress_type) {
-            return Some((*lines, request_path.clone()));
+        if message_type.suppress(suppress_type) {
+            return Some((lines, request_path.to_string()));
         }
     }
-    None
-}
-
-async fn handle_request(
-    context: SnoopContext,
-    _address: SocketAddr,
-    source_request: Request<Body>,
-) -> Result<Response<Body>, &'static str> {
-    let mut request_path = source_request.uri().path().to_string();
-    if let Some(query) = source_request.uri().query() {
-        request_path.push_str("?");
-        request_path.push_str(query);
-    }
-    let (dest_request, request_json) = match copy_request(source_request, &context).await {
-        Ok(result) => result,
-        Err(e) => {
-            let error_body = {
-                let rpc_error = RpcErrorResponse::from(("Error processing request", e));
-                serde_json::to_string_pretty(&rpc_error)
-                    .unwrap_or_else(|_| serde_json::json!(rpc_error).to_string())
-            };
-            println!(
-                "{}",
-                color_treat(error_body.clone(), context.inner.colors.red)
-            );
-            let source_response = Response::builder()
-                .status(500)
-                .body(Body::from(error_body))
-                .unwrap();
 
-            return Ok(source_response);
-        }
-    };
-    let request_headers = copy_headers(dest_request.headers());
-
-    let request_type = get_random_packet_type(PacketType::Request, &context).await;
-    let response_type = get_random_packet_type(PacketType::Response, &context).await;
-    match suppress_log(
-        PacketType::Request,
-        &request_json,
-        &request_path,
-        request_type,
-        response_type,
-        &context,
-    ) {
-        Some((limit, _)) if limit < 0 => {}
-        Some((limit, msg)) => print_json(
-            &utils::trim_json(&request_json, limit),
-            &request_headers,
-            request_type,
-            &msg,
-            None,
-            &context,
+    // `--only-method` and `--only-path` hide everything they don't match. On
+    // a match, TYPE says which messages are shown and LINES limits them.
+    let mut limit = None;
+    for (rules, s, tag) in [
+        (
+            &settings.only_method,
+            method,
+            method.map(|method| format!("[method {}]", method)),
         ),
-        None => print_json(
-            &request_json,
-            &request_headers,
-            request_type,
-            &request_path,
-            None,
-            &context,
+        (
+            &settings.only_path,
+            Some(request_path),
+            Some(request_path.to_string()),
         ),
-    }
-
-    if let PacketType::RequestDropped(delay) = request_type {
-        let ms = (delay * 1000.0) as u64;
-        sleep(Duration::from_millis(ms)).await;
-        return Err("Request Dropped");
-    }
-
-    let (source_response, response_json) =
-        if context.inner.override_rpc.is_some() && is_rpc_modules_request(&request_json) {
-            get_rpc_modules_override(context.inner.override_rpc.as_ref().unwrap())
-        } else {
-            match get_response(dest_request, &context).await {
-                Ok(result) => result,
-                Err(e) => {
-                    let error_body = {
-                        let rpc_error = RpcErrorResponse::from(("Error processing response", e));
-                        serde_json::to_string_pretty(&rpc_error)
-                            .unwrap_or_else(|_| serde_json::json!(rpc_error).to_string())
+    ] {
+        let rules = match rules {
+            Some(rules) => rules,
+            None => continue,
+        };
+        match s.and_then(|s| rules.iter().find(|(pattern, _)| pattern.matches(s))) {
+            Some((_, (lines, show_type))) if message_type.suppress(*show_type) => {
+                if *lines >= 0 {
+                    limit = match limit {
+                        Some((limit, tag)) if limit <= *lines => Some((limit, tag)),
+                        _ => Some((*lines, tag.unwrap_or_default())),
                     };
-                    let source_response = Response::builder()
-                        .status(500)
-                        .body(Body::from(error_body.clone()))
-                        .unwrap();
-                    (source_response, error_body)
                 }
             }
-        };
-    let response_headers = copy_headers(source_response.headers());
+            _ => return Some((-1, String::new())),
+        }
+    }
+    limit
+}
 
-    match suppress_log(
-        PacketType::Response,
-        &request_json,
-        &request_path,
-        request_type,
-        response_type,
-        &context,
-    ) {
-        Some((limit, _)) if limit < 0 => {}
-        Some((limit, _msg)) => print_json(
-            &utils::trim_json(&response_json, limit),
-            &response_headers,
-            response_type,
-            "",
-            Some(source_response.status()),
-            &context,
-        ),
-        None => print_json(
-            &response_json,
-            &response_headers,
-            response_type,
-            "",
-            Some(source_response.status()),
-            &context,
+// Prints `json` unless it is fully suppressed, trimming it to the suppressed
+// number of lines when a suppression rule applies.
+fn log_json(
+    json: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    json_type: PacketType,
+    msg: &str,
+    status: Option<StatusCode>,
+    suppressed_lines: Option<i32>,
+    context: &SnoopContext,
+) {
+    match suppressed_lines {
+        Some(limit) if limit < 0 => {}
+        Some(limit) => print_json(
+            &utils::trim_json(json, limit),
+            headers,
+            json_type,
+            msg,
+            status,
+            context,
         ),
+        None => print_json(json, headers, json_type, msg, status, context),
     }
+}
 
-    if let PacketType::ResponseDropped(delay) = response_type {
-        let ms = (delay * 1000.0) as u64;
-        sleep(Duration::from_millis(ms)).await;
-        return Err("Response Dropped");
+fn tag_msg(msg: &str, tag: &str) -> String {
+    if msg.is_empty() || msg.eq("/") {
+        tag.to_string()
+    } else {
+        format!("{} {}", msg, tag)
     }
+}
 
-    Ok(source_response)
+fn batch_msg(msg: &str, index: Option<usize>, len: usize) -> String {
+    match index {
+        Some(i) => tag_msg(msg, &format!("[batch {}/{}]", i + 1, len)),
+        None => tag_msg(msg, "[batch unmatched]"),
+    }
 }
 
-const SUPPRESS_HELP: &'static str = "
-LINES=n specifies the degree of suppression:
-    n < 0 Ignore message completely and log nothing [default]
-    n = 0 Log that message occurred, but don't print any JSON
-    n > 0 Log at most n lines of JSON
-TYPE is one of:
+fn to_pretty_json(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
+
+// `hidden` marks calls (by position in the request) that `--filter` hides
+#[allow(clippy::too_many_arguments)]
+fn log_request(
+    rpc_body: &RpcBody,
+    request_json: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    hidden: &[bool],
+    request_path: &str,
+    request_type: PacketType,
+    response_type: PacketType,
+    tags: &[String],
+    context: &SnoopContext,
+) {
+    let log_call = |json: &str,
+                    headers: &[(HeaderName, HeaderValue)],
+                    call: &Option<RpcRequest>,
+                    batch_index: Option<(usize, usize)>|
+     -> bool {
+        let is_hidden = hidden
+            .get(batch_index.map(|(i, _)| i).unwrap_or(0))
+            .copied()
+            .unwrap_or(false);
+        let suppressed = if is_hidden {
+            Some((-1, String::new()))
+        } else {
+            suppress_log(
+                PacketType::Request,
+                call.as_ref().map(|call| call.method.as_str()),
+                request_path,
+                request_type,
+                response_type,
+                context,
+            )
+        };
+        let (lines, msg) = match suppressed {
+            Some((lines, msg)) => (Some(lines), msg),
+            None => (None, request_path.to_string()),
+        };
+        let msg = match batch_index {
+            Some((i, len)) => batch_msg(&msg, Some(i), len),
+            None => msg,
+        };
+        let msg = match call {
+            Some(call) if call.is_notification() => tag_msg(&msg, "[notification]"),
+            _ => msg,
+        };
+        let msg = tags.iter().fold(msg, |msg, tag| tag_msg(&msg, tag));
+        log_json(json, headers, request_type, &msg, None, lines, context);
+        !matches!(lines, Some(limit) if limit < 0)
+    };
+
+    match rpc_body {
+        RpcBody::Single(call) => {
+            log_call(request_json, headers, call, None);
+        }
+        RpcBody::Batch(calls) => {
+            // only print the headers once for the whole batch
+            let mut headers = headers;
+            for (i, (element, call)) in calls.iter().enumerate() {
+                if log_call(
+                    &to_pretty_json(element),
+                    headers,
+                    call,
+                    Some((i, calls.len())),
+                ) {
+                    headers = &[];
+                }
+            }
+        }
+    }
+}
+
+// e.g. `143ms (ttfb 120ms)`, highlighted when over `--slow-threshold`
+fn timing_msg(timing: &Timing, context: &SnoopContext) -> String {
+    let msg = format!(
+        "{}ms (ttfb {}ms)",
+        timing.total.as_millis(),
+        timing.ttfb.as_millis()
+    );
+    match context.settings().slow_threshold {
+        Some(threshold) if timing.total >= threshold => format!(
+            "{}{} SLOW{}",
+            context.inner.colors.yellow, msg, context.inner.colors.reset
+        ),
+        _ => msg,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn log_response(
+    rpc_body: &RpcBody,
+    response_json: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    hidden: &[bool],
+    status: Option<StatusCode>,
+    timing: Option<&Timing>,
+    upstream: Option<&str>,
+    request_path: &str,
+    request_type: PacketType,
+    response_type: PacketType,
+    tags: &[String],
+    context: &SnoopContext,
+) {
+    let msg = [
+        timing.map(|timing| timing_msg(timing, context)),
+        upstream.map(|upstream| format!("from {}", upstream)),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(tags.iter().cloned())
+    .collect::<Vec<_>>()
+    .join(" ");
+    let suppressed_lines = |i: usize, call: &Option<RpcRequest>| -> Option<i32> {
+        if hidden.get(i).copied().unwrap_or(false) {
+            return Some(-1);
+        }
+        suppress_log(
+            PacketType::Response,
+            call.as_ref().map(|call| call.method.as_str()),
+            request_path,
+            request_type,
+            response_type,
+            context,
+        )
+        .map(|(lines, _)| lines)
+    };
+
+    let (calls, responses) = match (
+        rpc_body,
+        serde_json::from_str::<Vec<serde_json::Value>>(response_json),
+    ) {
+        (RpcBody::Batch(calls), Ok(responses)) => (calls, responses),
+        (RpcBody::Batch(calls), Err(_)) => {
+            // the upstream rejected the batch as a whole
+            let all_hidden = !calls.is_empty()
+                && hidden.len() == calls.len()
+                && hidden.iter().all(|hidden| *hidden);
+            log_json(
+                response_json,
+                headers,
+                response_type,
+                &msg,
+                status,
+                if all_hidden { Some(-1) } else { None },
+                context,
+            );
+            return;
+        }
+        (RpcBody::Single(call), _) => {
+            log_json(
+                response_json,
+                headers,
+                response_type,
+                &msg,
+                status,
+                suppressed_lines(0, call),
+                context,
+            );
+            return;
+        }
+    };
+
+    let mut headers = headers;
+    for (i, response) in pair_responses(calls, responses) {
+        let lines = i.and_then(|i| suppressed_lines(i, &calls[i].1));
+        log_json(
+            &to_pretty_json(&response),
+            headers,
+            response_type,
+            &batch_msg(&msg, i, calls.len()),
+            status,
+            lines,
+            context,
+        );
+        if !matches!(lines, Some(limit) if limit < 0) {
+            headers = &[];
+        }
+    }
+}
+
+// Pairs each response to a batch with the position of the call it answers by
+// id, in request order. Responses answering no call come last, without one.
+fn pair_responses(
+    calls: &[(serde_json::Value, Option<RpcRequest>)],
+    mut responses: Vec<serde_json::Value>,
+) -> Vec<(Option<usize>, serde_json::Value)> {
+    let mut paired = vec![];
+    for (i, (element, _)) in calls.iter().enumerate() {
+        let id = match element.get("id") {
+            Some(id) => id,
+            None => continue,
+        };
+        if let Some(position) = responses
+            .iter()
+            .position(|response| response.get("id") == Some(id))
+        {
+            paired.push((Some(i), responses.remove(position)));
+        }
+    }
+    paired.extend(responses.into_iter().map(|response| (None, response)));
+    paired
+}
+
+// True if neither the request nor the response of any call would be printed
+fn is_fully_suppressed(
+    rpc_body: &RpcBody,
+    request_path: &str,
+    request_type: PacketType,
+    response_type: PacketType,
+    context: &SnoopContext,
+) -> bool {
+    let hidden = |method: Option<&str>| {
+        [PacketType::Request, PacketType::Response]
+            .into_iter()
+            .all(|message_type| {
+                matches!(
+                    suppress_log(message_type, method, request_path, request_type, response_type, context),
+                    Some((lines, _)) if lines < 0
+                )
+            })
+    };
+    match rpc_body {
+        RpcBody::Single(call) => hidden(call.as_ref().map(|call| call.method.as_str())),
+        RpcBody::Batch(calls) => calls
+            .iter()
+            .all(|(_, call)| hidden(call.as_ref().map(|call| call.method.as_str()))),
+    }
+}
+
+// Which calls of the exchange `--filter` hides, in request order. Like
+// suppression, filters never hide dropped requests or responses.
+fn filtered_calls(rpc_body: &RpcBody, exchange: &Exchange, context: &SnoopContext) -> Vec<bool> {
+    match &context.settings().filters {
+        Some(filters) if !exchange.request_dropped && !exchange.response_dropped => {
+            filter::hidden_calls(filters, rpc_body, exchange)
+        }
+        _ => vec![],
+    }
+}
+
+fn is_filtered_out(rpc_body: &RpcBody, exchange: &Exchange, context: &SnoopContext) -> bool {
+    let hidden = filtered_calls(rpc_body, exchange, context);
+    !hidden.is_empty() && hidden.iter().all(|hidden| *hidden)
+}
+
+fn record_exchange(
+    exchange: &Exchange,
+    rpc_body: &RpcBody,
+    request_type: PacketType,
+    response_type: PacketType,
+    context: &SnoopContext,
+) {
+    if let Some(traffic_log) = &context.inner.traffic_log {
+        if !traffic_log.honour_suppression
+            || !(is_fully_suppressed(
+                rpc_body,
+                &exchange.path,
+                request_type,
+                response_type,
+                context,
+            ) || is_filtered_out(rpc_body, exchange, context))
+        {
+            if let Err(e) = traffic_log.write(exchange) {
+                print_event(&format!("Unable to write to traffic log: {}", e), context);
+            }
+        }
+    }
+    // HAR has no place for websocket frames outside of browser extensions
+    if let (Some(har_log), false) = (
+        &context.inner.har_log,
+        exchange.http_method == WEBSOCKET_METHOD,
+    ) {
+        if let Err(e) = har_log.write(exchange) {
+            print_event(&format!("Unable to write to har file: {}", e), context);
+        }
+    }
+    if let Some(metrics) = &context.inner.metrics {
+        metrics.record(exchange, rpc_body);
+    }
+    if let Some(tui) = &context.inner.tui {
+        tui.record(exchange, rpc_body, request_type, response_type);
+    }
+}
+
+async fn handle_request(
+    context: SnoopContext,
+    address: ClientAddr,
+    source_request: Request<Body>,
+) -> Result<Response<Body>, &'static str> {
+    // ipc clients can't send a token, the socket's permissions guard it instead
+    if let (Some(inbound_jwt_secret), ClientAddr::Tcp(_)) =
+        (&context.inner.inbound_jwt_secret, &address)
+    {
+        let authorization = source_request
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        if let Err(e) = jwt::validate(inbound_jwt_secret, authorization) {
+            print_event(
+                &format!(
+                    "REJECTED {} from {}: {}",
+                    source_request.uri().path(),
+                    address,
+                    e
+                ),
+                &context,
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Body::from(e))
+                .unwrap());
+        }
+    }
+    context.inner.pause.wait().await;
+    let sequence = context.next_sequence();
+    if context.inner.upstreams.is_websocket() && websocket::is_upgrade_request(&source_request) {
+        return Ok(websocket::handle_upgrade(context, address, source_request, sequence).await);
+    }
+    let mut rng = context.exchange_rng(sequence, None);
+    let timestamp = chrono::offset::Local::now();
+    let mut request_path = source_request.uri().path().to_string();
+    if let Some(query) = source_request.uri().query() {
+        request_path.push('?');
+        request_path.push_str(query);
+    }
+    let (source_request, request_json) = match read_request(source_request).await {
+        Ok(result) => result,
+        Err(e) => {
+            let rpc_error = RpcErrorResponse::from(("Error processing request", e, RpcId::Null));
+            let error_body = serde_json::to_string_pretty(&rpc_error)
+                .unwrap_or_else(|_| serde_json::json!(rpc_error).to_string());
+            match &context.inner.tui {
+                Some(_) => print_event(&rpc_error.error.message, &context),
+                None => println!(
+                    "{}",
+                    color_treat(error_body.clone(), context.inner.colors.red)
+                ),
+            }
+            let source_response = Response::builder()
+                .status(500)
+                .body(Body::from(error_body))
+                .unwrap();
+
+            return Ok(source_response);
+        }
+    };
+    let request_headers = copy_headers(&source_request.headers);
+    let rpc_body = RpcBody::parse(&request_json);
+
+    let request_type = get_random_packet_type(PacketType::Request, &mut rng, &context);
+    let response_type = get_random_packet_type(PacketType::Response, &mut rng, &context);
+    let request_delay = match request_type {
+        PacketType::RequestDropped(_) => None,
+        _ => sample_delay(
+            PacketType::Request,
+            &rpc_body,
+            &request_path,
+            &mut rng,
+            &context,
+        ),
+    };
+    // filters may look at the response, so the request is logged along with it
+    let defer_request_log = context.settings().filters.is_some()
+        && !matches!(request_type, PacketType::RequestDropped(_));
+    if !defer_request_log {
+        log_request(
+            &rpc_body,
+            &request_json,
+            &request_headers,
+            &[],
+            &request_path,
+            request_type,
+            response_type,
+            &delay_tag(request_delay),
+            &context,
+        );
+    }
+
+    let mut exchange = Exchange {
+        timestamp,
+        client: address.to_string(),
+        http_method: source_request.method.to_string(),
+        path: request_path.clone(),
+        methods: rpc_body.methods(),
+        request_headers: request_headers.clone(),
+        request_json: request_json.clone(),
+        request_bytes: source_request.body.len(),
+        status: None,
+        response_headers: vec![],
+        response_json: None,
+        response_bytes: None,
+        upstream: None,
+        latency: None,
+        ttfb: None,
+        request_dropped: matches!(request_type, PacketType::RequestDropped(_)),
+        response_dropped: matches!(response_type, PacketType::ResponseDropped(_)),
+        injected: false,
+        mutation: None,
+    };
+
+    if let PacketType::RequestDropped(delay) = request_type {
+        record_exchange(&exchange, &rpc_body, request_type, response_type, &context);
+        let ms = (delay * 1000.0) as u64;
+        sleep(Duration::from_millis(ms)).await;
+        return Err("Request Dropped");
+    }
+    if let Some(delay) = request_delay {
+        sleep(delay).await;
+    }
+
+    let fault = inject_fault(&rpc_body, &request_path, &mut rng, &context);
+    let mirrored = if rpc_body.expects_response()
+        && fault.is_none()
+        && !is_answered_locally(&rpc_body, &context)
+    {
+        compare::mirror(&source_request, &context)
+    } else {
+        None
+    };
+    let start = Instant::now();
+    let settings = context.settings();
+    let faulted = |call: &Option<RpcRequest>| {
+        fault
+            .as_ref()
+            .is_some_and(|rule| rule.target.matches_call(call, &request_path))
+    };
+    let local = |element: &(serde_json::Value, Option<RpcRequest>)| {
+        local_answer(
+            element,
+            fault.as_ref(),
+            settings.override_rpc.as_deref(),
+            &request_path,
+        )
+    };
+    let response = match (&fault, &settings.override_rpc, &rpc_body) {
+        (Some(rule), _, RpcBody::Single(_)) => {
+            let (response, response_json) = rule.fault.response(&rpc_body);
+            Ok((response, response_json, None))
+        }
+        (Some(rule), _, RpcBody::Batch(calls)) if calls.iter().all(|(_, call)| faulted(call)) => {
+            let (response, response_json) = rule.fault.response(&rpc_body);
+            Ok((response, response_json, None))
+        }
+        (None, Some(rpc_modules), RpcBody::Single(call)) if is_rpc_modules_request(call) => {
+            let (response, response_json) = get_rpc_modules_override(rpc_modules, &rpc_body.id());
+            Ok((response, response_json, None))
+        }
+        (_, _, RpcBody::Batch(calls)) if calls.iter().any(|element| local(element).is_some()) => {
+            get_batch_response_with_local_answers(source_request, calls, local, &context).await
+        }
+        _ => route(&source_request, &rpc_body, &context)
+            .await
+            .map(|(response, response_json, upstream)| (response, response_json, Some(upstream))),
+    };
+    let (source_response, response_json, upstream) = match response {
+        Ok(result) => result,
+        Err(e) => {
+            let error_body = {
+                let rpc_error =
+                    RpcErrorResponse::from(("Error processing response", e, rpc_body.id()));
+                serde_json::to_string_pretty(&rpc_error)
+                    .unwrap_or_else(|_| serde_json::json!(rpc_error).to_string())
+            };
+            let source_response = Response::builder()
+                .status(500)
+                .body(Body::from(error_body.clone()))
+                .unwrap();
+            (source_response, error_body, None)
+        }
+    };
+    let latency = start.elapsed();
+    let response_headers = copy_headers(source_response.headers());
+    let timing = source_response.extensions().get::<Timing>().copied();
+
+    exchange.status = Some(source_response.status());
+    exchange.response_headers = response_headers;
+    exchange.response_json = Some(response_json);
+    exchange.response_bytes = source_response
+        .body()
+        .size_hint()
+        .exact()
+        .map(|bytes| bytes as usize);
+    exchange.upstream = upstream;
+    exchange.latency = Some(latency);
+    exchange.ttfb = timing.map(|timing| timing.ttfb);
+    exchange.injected = fault.is_some();
+    let response_delay = match response_type {
+        PacketType::ResponseDropped(_) => None,
+        _ => sample_delay(
+            PacketType::Response,
+            &rpc_body,
+            &request_path,
+            &mut rng,
+            &context,
+        ),
+    };
+
+    let hidden = filtered_calls(&rpc_body, &exchange, &context);
+    if defer_request_log {
+        log_request(
+            &rpc_body,
+            &request_json,
+            &request_headers,
+            &hidden,
+            &request_path,
+            request_type,
+            response_type,
+            &delay_tag(request_delay),
+            &context,
+        );
+    }
+    let response_json = exchange.response_json.as_deref().unwrap_or_default();
+    log_response(
+        &rpc_body,
+        response_json,
+        &exchange.response_headers,
+        &hidden,
+        exchange.status,
+        timing.as_ref(),
+        exchange.upstream.as_deref(),
+        &request_path,
+        request_type,
+        response_type,
+        &[
+            fault.as_ref().map(|_| "[injected]".to_string()),
+            delay_tag(response_delay).pop(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>(),
+        &context,
+    );
+    let mutation = match (&fault, response_type, exchange.status) {
+        (None, PacketType::Response, Some(status)) if status.is_success() => {
+            mutate_response(&rpc_body, &request_path, response_json, &mut rng, &context)
+        }
+        _ => None,
+    };
+    if let Some(mirrored) = mirrored {
+        tokio::spawn(compare::report(
+            mirrored,
+            rpc_body.clone(),
+            source_response.status(),
+            response_json.to_string(),
+            exchange.upstream.clone(),
+            context.clone(),
+        ));
+    }
+    // the mutated response is logged below the original it was made from
+    let source_response = match mutation {
+        Some((body, pretty, description)) => {
+            // suppressed and filtered per call like the original
+            log_response(
+                &rpc_body,
+                &pretty,
+                &[],
+                &hidden,
+                exchange.status,
+                None,
+                None,
+                &request_path,
+                request_type,
+                response_type,
+                &[format!("[mutated: {}]", description)],
+                &context,
+            );
+            exchange.response_json = Some(pretty);
+            exchange.response_bytes = Some(body.len());
+            exchange.mutation = Some(description);
+            let (mut parts, _) = source_response.into_parts();
+            parts.headers.remove(hyper::header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(body))
+        }
+        None => source_response,
+    };
+    record_exchange(&exchange, &rpc_body, request_type, response_type, &context);
+
+    if let PacketType::ResponseDropped(delay) = response_type {
+        let ms = (delay * 1000.0) as u64;
+        sleep(Duration::from_millis(ms)).await;
+        return Err("Response Dropped");
+    }
+    if let Some(delay) = response_delay {
+        sleep(delay).await;
+    }
+
+    Ok(source_response)
+}
+
+// the LINES and TYPE of `--suppress-method` and `--suppress-path`
+macro_rules! suppress_help {
+    ($first_line:literal) => {
+        concat!(
+            $first_line,
+            "
+LINES=n specifies the degree of suppression:
+    n < 0 Ignore message completely and log nothing [default]
+    n = 0 Log that message occurred, but don't print any JSON
+    n > 0 Log at most n lines of JSON
+TYPE is one of:
     REQUEST:  Suppress request log
     RESPONSE: Suppress response log
-    ALL:      Suppress both logs [default]";
+    ALL:      Suppress both logs [default]"
+        )
+    };
+}
 
-#[tokio::main]
-async fn main() {
-    let matches = App::new("JSON-RPC Snooping Tool")
+const SUPPRESS_METHOD_HELP: &str = suppress_help!("Suppress output of JSON RPC calls of this METHOD (can specify more than once). METHOD may be a glob (`eth_get*`) or a regex prefixed with `re:`, and the first matching rule wins");
+
+const SUPPRESS_PATH_HELP: &str = suppress_help!("Suppress output of requests to the endpoint with this PATH (can specify more than once). PATH may be a glob (`/eth/v1/validator/duties/*`) or a regex prefixed with `re:`, and the first matching rule wins");
+
+const FILTER_HELP: &str = "Only show calls for which EXPR holds (can specify more than once, all must hold). Everything is still proxied, and each call of a batch is checked on its own. Requests are printed together with their response when filters are given.
+FIELDS:
+    method, params, id, request     from the request, e.g. params[0].address
+    response, result, error         from the response, e.g. error.code
+    status                          http status of the response
+    latency, ttfb                   in milliseconds
+    path, http_method, upstream, batch
+OPERATORS:
+    == != < <= > >=   compare; 0x quantities compare as numbers
+    =~ \"regex\"        regex match
+    && || ! ( )       ! binds tightest, then comparisons, && and ||
+LITERALS:
+    \"string\", 42, -32000, 0x2a, 200ms, 1.5s, true, false, null
+A missing field is null, and null and false count as false.
+Filters apply to websocket frames too, subscription notifications have no request.
+EXAMPLE:
+    --filter 'method != \"eth_call\" || error'    hide eth_call unless it errored";
+
+// `--delay-request` and `--delay-response` only differ in what they delay
+macro_rules! delay_help {
+    ($what:literal, $option:literal) => {
+        concat!(
+            "Delay ",
+            $what,
+            " for chaos testing (can specify more than once)
+TARGET limits the rule to calls whose method matches it, or to requests whose path matches it
+when it starts with `/`. Globs and `re:` regexes are accepted and the first matching rule wins.
+On a websocket each frame is delayed on its own, holding up only the frames behind it.
+DELAY is one of:
+    250ms, 1.5s                  a fixed delay
+    100ms..2s                    uniformly distributed in the range
+    normal(500ms,100ms)          normally distributed with this mean and standard deviation
+    pareto(100ms,1.5)            long tailed: at least 100ms, a smaller shape gives longer tails
+EXAMPLE:
+    --",
+            $option,
+            " 'eth_getLogs=pareto(200ms,1.2)' --",
+            $option,
+            " 50ms..150ms"
+        )
+    };
+}
+
+const DELAY_REQUEST_HELP: &str = delay_help!("forwarding requests", "delay-request");
+
+const DELAY_RESPONSE_HELP: &str = delay_help!("returning responses", "delay-response");
+
+const INJECT_ERROR_HELP: &str = "Answer RATE% of matching calls with an error instead of forwarding them, for chaos testing (can specify more than once)
+TARGET limits the rule to calls whose method matches it, or to requests whose path matches it
+when it starts with `/`, like `--delay-request`. Every matching rule gets its own chance to fire,
+in the order given.
+CODE from 400 to 599 is returned as that http status with MESSAGE (or the status text) as the
+body. Any other CODE is returned as a JSON-RPC error with the caller's id and MESSAGE.
+In a batch only the calls TARGET matches get the error, the others are still forwarded.
+Calls sent over a WebSocket connection are always forwarded, errors are only injected over http.
+EXAMPLE:
+    --inject-error 'eth_getBlockByNumber:5%:-32000:header not found' --inject-error '1%:503'";
+
+const MUTATE_HELP: &str = "Mangle RATE% of matching successful responses, for fuzzing clients (can specify more than once)
+TARGET works like it does for `--inject-error`. One of the MUTATIONs (all of them by default) is
+picked at random among those that apply to the response and made to a random value in its result:
+    flip-hex          change one digit of a 0x hex string
+    truncate-array    drop elements from the end of an array
+    drop-field        remove a field from an object
+    null              replace a value with null
+    change-type       replace a value with one of another type, e.g. 0x10 with 16
+    invalid-json      cut the response short
+Each mutated response is printed below the original with a description of the change.
+Responses sent over a WebSocket connection are passed on unchanged, only http responses are mutated.
+EXAMPLE:
+    --mutate-response 'eth_getBlockByNumber:10%:flip-hex,drop-field' --mutate-response '1%'";
+
+fn app() -> App<'static> {
+    App::new("JSON-RPC Snooping Tool")
         .version("0.2")
         .author("Mark Mackey <ethereumdreamer@gmail.com>")
-        .about("Proxies an http JSON-RPC endpoint and dumps requests and responses to screen")
+        .about("Proxies an http or websocket JSON-RPC endpoint and dumps requests and responses to screen")
         .arg(
             Arg::with_name("bind-address")
                 .short('b')
@@ -468,6 +1670,24 @@ async fn main() {
                 .default_value("3000")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("listen-ipc")
+                .long("listen-ipc")
+                .value_name("PATH")
+                .help("Also listen for JSON-RPC requests on an ipc socket at PATH (e.g. for `geth attach`)")
+                .value_parser(clap::value_parser!(PathBuf))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("listen-ipc-mode")
+                .long("listen-ipc-mode")
+                .value_name("MODE")
+                .help("Octal file permissions of the ipc socket created by --listen-ipc")
+                .requires("listen-ipc")
+                .value_parser(utils::parse_file_mode)
+                .default_value("600")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("log-headers")
                 .short('l')
@@ -476,6 +1696,89 @@ async fn main() {
                 .required(false)
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .help("Append one JSON record per exchange to FILE (newline-delimited JSON)")
+                .value_parser(clap::value_parser!(PathBuf))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file-suppress")
+                .long("log-file-suppress")
+                .help("Leave exchanges that are completely suppressed on screen out of --log-file")
+                .requires("log-file")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("har")
+                .long("har")
+                .value_name("FILE")
+                .help("Record every exchange into FILE in HAR 1.2 format")
+                .value_parser(clap::value_parser!(PathBuf))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .help("Answer requests from a session recorded with --log-file or --har instead of forwarding them")
+                .long_help("Answer requests from a session recorded with --log-file or --har instead of forwarding them. JSON-RPC calls are matched by method and params, ignoring the id, and answered with the recorded response carrying the caller's id. Calls recorded more than once are answered in recorded order, repeating the last response once they run out. Calls with no recorded response are answered with an error")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all(&["RPC_ENDPOINT", "route"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compare-with")
+                .long("compare-with")
+                .value_name("RPC_ENDPOINT")
+                .help("Also send every request to RPC_ENDPOINT and print where its responses differ from the primary's")
+                .long_help("Also send every request to RPC_ENDPOINT and print where its responses differ from the primary's. Clients always get the primary's response and the diff is printed once the comparison endpoint answers, so a slow comparison endpoint doesn't slow down the client")
+                .value_parser(utils::parse_endpoint)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compare-ignore")
+                .long("compare-ignore")
+                .value_name("FIELD")
+                .help("Leave object keys matching FIELD (e.g. `timestamp`, `*Hash`) out of the --compare-with diff (can specify more than once)")
+                .requires("compare-with")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slow-threshold")
+                .long("slow-threshold")
+                .value_name("MS")
+                .help("Highlight responses that took the upstream at least MS milliseconds")
+                .value_parser(clap::value_parser!(u64))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-port")
+                .long("metrics-port")
+                .value_name("PORT")
+                .help("Serve Prometheus metrics on PORT (on the same bind-address)")
+                .value_parser(clap::value_parser!(u16))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin-port")
+                .long("admin-port")
+                .value_name("PORT")
+                .help("Serve a JSON-RPC api on PORT (on the same bind-address) to inspect and change suppression, drop rates and pausing at runtime")
+                .value_parser(clap::value_parser!(u16))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tui")
+                .long("tui")
+                .help("Show exchanges in an interactive terminal UI instead of printing them")
+                .long_help("Show exchanges in an interactive terminal UI instead of printing them. Select an exchange to view the full request and response, search with `/`, switch suppression rules on and off with `s` and pause capturing with `p`")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("no-color")
                 .long("no-color")
@@ -490,7 +1793,7 @@ async fn main() {
                 .long("suppress-method")
                 .value_name("METHOD[:LINES][:TYPE]")
                 .help("Suppress output of JSON RPC calls of this METHOD (can specify more than once)")
-                .long_help(format!("Suppress output of JSON RPC calls of this METHOD (can specify more than once){}", SUPPRESS_HELP).as_str())
+                .long_help(SUPPRESS_METHOD_HELP)
                 .multiple(true)
                 .number_of_values(1)
                 .value_parser(utils::parse_suppress)
@@ -502,12 +1805,51 @@ async fn main() {
                 .long("suppress-path")
                 .value_name("PATH[:LINES][:TYPE]")
                 .help("Suppress output of requests to the endpoint with this PATH (can specify more than once)")
-                .long_help(format!("Suppress output of requests to the endpoint with this PATH (can specify more than once){}", SUPPRESS_HELP).as_str())
+                .long_help(SUPPRESS_PATH_HELP)
+                .multiple(true)
+                .number_of_values(1)
+                .value_parser(utils::parse_suppress)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("only-method")
+                .long("only-method")
+                .value_name("METHOD[:LINES][:TYPE]")
+                .help("Only show JSON RPC calls of this METHOD, everything is still proxied (can specify more than once)")
+                .long_help("Only show JSON RPC calls of this METHOD, everything is still proxied (can specify more than once). METHOD may be a glob or a regex prefixed with `re:` as with --suppress-method. TYPE picks which of the request and response are shown and LINES limits how many lines of them are printed (no limit by default). --suppress-method and --suppress-path rules still apply to matching calls")
                 .multiple(true)
                 .number_of_values(1)
                 .value_parser(utils::parse_suppress)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("only-path")
+                .long("only-path")
+                .value_name("PATH[:LINES][:TYPE]")
+                .help("Only show requests to the endpoint with this PATH, everything is still proxied (can specify more than once)")
+                .long_help("Only show requests to the endpoint with this PATH, everything is still proxied (can specify more than once). Patterns, LINES and TYPE work as with --only-method. When both --only-method and --only-path are given an exchange must match both to be shown")
+                .multiple(true)
+                .number_of_values(1)
+                .value_parser(utils::parse_suppress)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("EXPR")
+                .help("Only show calls for which EXPR holds, e.g. 'method == \"eth_call\" && !error' (can specify more than once)")
+                .long_help(FILTER_HELP)
+                .multiple(true)
+                .number_of_values(1)
+                .value_parser(filter::parse_filter)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("suppress-ignore-query")
+                .long("suppress-ignore-query")
+                .help("Match --suppress-path and --only-path against the path without its query string")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("drop-request-rate")
                 .long("drop-request-rate")
@@ -524,6 +1866,107 @@ async fn main() {
                 .default_value("0")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("delay-request")
+                .long("delay-request")
+                .value_name("[TARGET=]DELAY")
+                .help("Delay forwarding requests for chaos testing (can specify more than once)")
+                .long_help(DELAY_REQUEST_HELP)
+                .value_parser(chaos::parse_delay)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("delay-response")
+                .long("delay-response")
+                .value_name("[TARGET=]DELAY")
+                .help("Delay returning responses for chaos testing (can specify more than once)")
+                .long_help(DELAY_RESPONSE_HELP)
+                .value_parser(chaos::parse_delay)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("inject-error")
+                .long("inject-error")
+                .value_name("[TARGET:]RATE%:CODE[:MESSAGE]")
+                .help("Answer RATE% of matching calls with an error instead of forwarding them, for chaos testing (can specify more than once)")
+                .long_help(INJECT_ERROR_HELP)
+                .value_parser(chaos::parse_error)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("mutate-response")
+                .long("mutate-response")
+                .value_name("[TARGET:]RATE%[:MUTATION,...]")
+                .help("Mangle RATE% of matching successful responses, for fuzzing clients (can specify more than once)")
+                .long_help(MUTATE_HELP)
+                .value_parser(mutate::parse_mutate)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed for the drop, delay, error and mutation decisions, to reproduce a chaos run (random by default)")
+                .value_parser(clap::value_parser!(u64))
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("failover-on-5xx")
+                .long("failover-on-5xx")
+                .help("Also fail over to the next RPC_ENDPOINT when one answers with a 5xx status, not just on connection errors. The failed request may already have been carried out, so only use this when the calls sent are safe to repeat")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("upstream-strategy")
+                .long("upstream-strategy")
+                .value_name("STRATEGY")
+                .help("How to pick between multiple RPC_ENDPOINTs: round-robin, least-latency or fallback (always prefer the first healthy endpoint)")
+                .value_parser(Strategy::from_str)
+                .default_value("fallback")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("route")
+                .long("route")
+                .value_name("METHOD_PATTERN=RPC_ENDPOINT")
+                .help("Forward calls whose method matches METHOD_PATTERN (e.g. `engine_*`) to RPC_ENDPOINT instead (can specify more than once)")
+                .long_help("Forward calls whose method matches METHOD_PATTERN (e.g. `engine_*`) to RPC_ENDPOINT instead (can specify more than once). Patterns may use `*` and `?` wildcards, or be a regex when prefixed with `re:` (e.g. `re:^(debug|trace)_`), and the first matching route wins. Giving the same pattern more than once adds failover endpoints to that route. Calls matching no route go to the default RPC_ENDPOINT. WebSocket connections are relayed to the default RPC_ENDPOINT as a whole, their frames are not routed")
+                .multiple(true)
+                .number_of_values(1)
+                .value_parser(utils::parse_route)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("jwt-secret")
+                .long("jwt-secret")
+                .value_name("FILE")
+                .help("Sign every forwarded request with a fresh HS256 token from the hex encoded secret in FILE, replacing any inbound Authorization header (e.g. for the Engine API)")
+                .value_parser(clap::value_parser!(PathBuf))
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("validate-inbound-jwt")
+                .long("validate-inbound-jwt")
+                .help("Reject tcp requests whose Authorization token doesn't verify against --inbound-jwt-secret or, if not given, --jwt-secret (ipc clients are not checked)")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("inbound-jwt-secret")
+                .long("inbound-jwt-secret")
+                .value_name("FILE")
+                .help("Secret used by --validate-inbound-jwt when callers use a different secret than the upstream")
+                .requires("validate-inbound-jwt")
+                .value_parser(clap::value_parser!(PathBuf))
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("fix-geth-attach")
                 .short('f')
@@ -543,77 +1986,691 @@ async fn main() {
         )
         .arg(
             Arg::with_name("RPC_ENDPOINT")
-                .help("JSON-RPC endpoint to forward incoming requests")
-                .value_parser(utils::parse_uri)
-                .required(true)
+                .help("JSON-RPC endpoint to forward incoming requests (http, https, ws, wss, ipc or a path to an ipc socket). Pass more than one to balance load and fail over between them")
+                .value_parser(utils::parse_endpoint)
+                .multiple_values(true)
+                .required_unless_present("replay")
                 .index(1),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Read settings from a TOML file whose keys are the long option names (and `endpoint`). Options on the command line take precedence. Suppression, filter, chaos and rpc-modules settings are reloaded when the file changes or on SIGHUP")
+                .value_parser(clap::value_parser!(PathBuf))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("NAME")
+                .requires("config")
+                .help("Apply the settings in the [profiles.NAME] table of the --config file on top of its top level settings")
+                .takes_value(true),
+        )
+}
 
-    let rng = match rand::rngs::StdRng::from_rng(rand::rngs::OsRng::default()) {
-        Ok(rng) => rng,
-        Err(e) => {
-            eprintln!("Unable to initialize random number generator: {:?}", e);
-            return;
+// Everything the proxy shares between requests, from the parsed command line.
+// The recorders' files are opened here too.
+fn build_context(matches: &ArgMatches) -> Result<SnoopContext, String> {
+    let seed = match matches.get_one::<u64>("seed") {
+        Some(seed) => *seed,
+        None => {
+            let mut bytes = [0u8; 8];
+            rand::rngs::OsRng
+                .try_fill_bytes(&mut bytes)
+                .map_err(|e| format!("Unable to initialize random number generator: {:?}", e))?;
+            u64::from_le_bytes(bytes)
         }
     };
 
-    let context = SnoopContext {
+    let endpoints = matches
+        .get_many::<Endpoint>("RPC_ENDPOINT")
+        .map(|iter| iter.cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let strategy = *matches.get_one::<Strategy>("upstream-strategy").unwrap();
+
+    let read_secret = |arg: &str| -> Result<Option<Vec<u8>>, String> {
+        matches
+            .get_one::<PathBuf>(arg)
+            .map(|path| jwt::read_secret(path))
+            .transpose()
+    };
+    let (jwt_secret, inbound_jwt_secret) = (
+        read_secret("jwt-secret")?,
+        read_secret("inbound-jwt-secret")?,
+    );
+    let inbound_jwt_secret = match (
+        matches.is_present("validate-inbound-jwt"),
+        inbound_jwt_secret.or_else(|| jwt_secret.clone()),
+    ) {
+        (false, _) => None,
+        (true, Some(secret)) => Some(secret),
+        (true, None) => {
+            return Err(
+                "--validate-inbound-jwt requires --jwt-secret or --inbound-jwt-secret".to_string(),
+            )
+        }
+    };
+
+    let traffic_log = matches
+        .get_one::<PathBuf>("log-file")
+        .map(|path| {
+            TrafficLog::open(path, matches.is_present("log-file-suppress"))
+                .map_err(|e| format!("Unable to open log file {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let har_log = matches
+        .get_one::<PathBuf>("har")
+        .map(|path| {
+            HarLog::create(path)
+                .map_err(|e| format!("Unable to create har file {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let replay = matches
+        .get_one::<PathBuf>("replay")
+        .map(|path| Replay::load(path))
+        .transpose()?;
+
+    Ok(SnoopContext {
         inner: Arc::new(Inner {
-            dest_uri: matches.get_one::<Uri>("RPC_ENDPOINT").unwrap().clone(),
-            rng: Mutex::new(rng),
-            suppress_method: matches
-                .get_many("suppress-method")
-                .map(|iter| iter.cloned().collect()),
-            suppress_path: matches
-                .get_many("suppress-path")
-                .map(|iter| iter.cloned().collect()),
-            drop_request_rate: *matches.get_one::<u32>("drop-request-rate").unwrap() as f32 / 100.0,
-            drop_response_rate: *matches.get_one::<u32>("drop-response-rate").unwrap() as f32
-                / 100.0,
-            override_rpc: matches
-                .values_of("rpc-modules-override")
-                .map(|values| values.into_iter().map(|s| s.to_string()).collect())
-                .or(if matches.is_present("fix-geth-attach") {
-                    Some(
-                        vec!["eth", "net", "web3"]
-                            .into_iter()
-                            .map(Into::into)
-                            .collect(),
-                    )
-                } else {
-                    None
-                }),
+            upstreams: Upstreams::new(&endpoints, strategy),
+            jwt_secret,
+            inbound_jwt_secret,
+            routes: Routes::new(
+                &matches
+                    .get_many::<(Pattern, Endpoint)>("route")
+                    .map(|iter| iter.cloned().collect::<Vec<_>>())
+                    .unwrap_or_default(),
+                strategy,
+            ),
+            failover_on_server_error: matches.is_present("failover-on-5xx"),
+            seed,
+            sequence: AtomicU64::new(0),
+            settings: RwLock::new(Arc::new(Settings::from_matches(matches))),
+            pause: Pause::new(),
             colors: Colors::new(matches.is_present("no-color")),
-            log_headers: matches.is_present("log-headers"),
+            traffic_log,
+            har_log,
+            metrics: matches
+                .get_one::<u16>("metrics-port")
+                .map(|_| Metrics::default()),
+            tui: if matches.is_present("tui") {
+                Some(Tui::new())
+            } else {
+                None
+            },
+            replay,
+            comparison: matches.get_one::<Endpoint>("compare-with").map(|endpoint| {
+                Comparison::new(
+                    Upstream::new(endpoint),
+                    matches
+                        .values_of("compare-ignore")
+                        .map(|values| values.map(|s| s.to_string()).collect())
+                        .unwrap_or_default(),
+                )
+            }),
         }),
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    let app = app();
+    let cli_args = std::env::args_os().collect::<Vec<_>>();
+    let args = match config::with_config_args(&app, cli_args.clone()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
     };
+    let matches = app.clone().get_matches_from(args);
 
+    let context = match build_context(&matches) {
+        Ok(context) => context,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let seed = context.inner.seed;
+
+    print_event(
+        &format!("Using seed {} (pass --seed {} to reproduce)", seed, seed),
+        &context,
+    );
+
+    let ipc_context = context.clone();
+    let metrics_context = context.clone();
+    let admin_context = context.clone();
+    let tui_context = context.clone();
+    let reload_context = context.clone();
+    let shutdown_context = context.clone();
     // A `MakeService` that produces a `Service` to handle each connection.
     let make_service = make_service_fn(move |conn: &AddrStream| {
         let context = context.clone();
-        let addr = conn.remote_addr();
+        let addr = ClientAddr::Tcp(conn.remote_addr());
         // Create a `Service` for responding to the request.
-        let service = service_fn(move |req| handle_request(context.clone(), addr, req));
+        let service = service_fn(move |req| handle_request(context.clone(), addr.clone(), req));
         // Return the service to hyper.
         async move { Ok::<_, Infallible>(service) }
     });
 
-    match SocketAddr::from_str(&format!(
-        "{}:{}",
-        matches.value_of("bind-address").unwrap(),
-        matches.value_of("port").unwrap()
-    )) {
-        Ok(socket) => match Server::try_bind(&socket) {
-            Ok(server) => {
-                if let Err(e) = server.serve(make_service).await {
-                    eprintln!("server error: {}", e);
+    let listen_ipc = matches.get_one::<PathBuf>("listen-ipc");
+    let ipc_listener = match listen_ipc {
+        Some(path) => {
+            let mode = *matches.get_one::<u32>("listen-ipc-mode").unwrap();
+            match ipc::bind(path, mode) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    eprintln!("Unable to listen on ipc socket {}: {}", path.display(), e);
+                    return;
                 }
             }
-            Err(e) => {
-                eprintln!("Unable to bind to socket: {}", e);
-            }
-        },
-        Err(e) => eprintln!("Error parsing listen address: {:?}", e),
+        }
+        None => None,
+    };
+
+    let tcp_server = async {
+        match SocketAddr::from_str(&format!(
+            "{}:{}",
+            matches.value_of("bind-address").unwrap(),
+            matches.value_of("port").unwrap()
+        )) {
+            Ok(socket) => match Server::try_bind(&socket) {
+                Ok(server) => server
+                    .serve(make_service)
+                    .await
+                    .map_err(|e| format!("server error: {}", e)),
+                Err(e) => Err(format!("Unable to bind to socket: {}", e)),
+            },
+            Err(e) => Err(format!("Error parsing listen address: {:?}", e)),
+        }
+    };
+    let metrics_server = async {
+        let port = match matches.get_one::<u16>("metrics-port") {
+            Some(port) => port,
+            None => return std::future::pending().await,
+        };
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let context = metrics_context.clone();
+            let service = service_fn(move |req| metrics::handle_request(context.clone(), req));
+            async move { Ok::<_, Infallible>(service) }
+        });
+        match SocketAddr::from_str(&format!(
+            "{}:{}",
+            matches.value_of("bind-address").unwrap(),
+            port
+        )) {
+            Ok(socket) => match Server::try_bind(&socket) {
+                Ok(server) => server
+                    .serve(make_service)
+                    .await
+                    .map_err(|e| format!("metrics server error: {}", e)),
+                Err(e) => Err(format!("Unable to bind metrics socket: {}", e)),
+            },
+            Err(e) => Err(format!("Error parsing metrics listen address: {:?}", e)),
+        }
+    };
+    let admin_server = async {
+        let port = match matches.get_one::<u16>("admin-port") {
+            Some(port) => port,
+            None => return std::future::pending().await,
+        };
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let context = admin_context.clone();
+            let service = service_fn(move |req| admin::handle_request(context.clone(), req));
+            async move { Ok::<_, Infallible>(service) }
+        });
+        match SocketAddr::from_str(&format!(
+            "{}:{}",
+            matches.value_of("bind-address").unwrap(),
+            port
+        )) {
+            Ok(socket) => match Server::try_bind(&socket) {
+                Ok(server) => server
+                    .serve(make_service)
+                    .await
+                    .map_err(|e| format!("admin server error: {}", e)),
+                Err(e) => Err(format!("Unable to bind admin socket: {}", e)),
+            },
+            Err(e) => Err(format!("Error parsing admin listen address: {:?}", e)),
+        }
+    };
+    let tui = async {
+        match &tui_context.inner.tui {
+            Some(_) => tui::run(tui_context.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let reload = settings::watch(
+        &app,
+        cli_args,
+        matches.get_one::<PathBuf>("config").cloned(),
+        reload_context,
+    );
+    let ipc_server = async {
+        match ipc_listener {
+            Some(listener) => ipc::serve(listener, ipc_context).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // the TUI is dropped along with the select, so errors are printed to a
+    // terminal that has left raw mode
+    let result = tokio::select! {
+        result = tcp_server => result,
+        _ = ipc_server => Ok(()),
+        result = metrics_server => result,
+        result = admin_server => result,
+        _ = tui => Ok(()),
+        _ = reload => Ok(()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+    }
+
+    if let Some(path) = listen_ipc {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(traffic_log) = &shutdown_context.inner.traffic_log {
+        traffic_log.finish();
+    }
+    if let Some(har_log) = &shutdown_context.inner.har_log {
+        har_log.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A context built from `args` the way `main` builds it
+    pub fn context(args: &[&str]) -> SnoopContext {
+        let matches = app()
+            .try_get_matches_from(std::iter::once("json_rpc_snoop").chain(args.iter().copied()))
+            .unwrap();
+        build_context(&matches).unwrap()
+    }
+
+    fn call(id: Option<u64>, method: &str) -> serde_json::Value {
+        let mut call = serde_json::json!({"jsonrpc": "2.0", "method": method});
+        if let Some(id) = id {
+            call["id"] = id.into();
+        }
+        call
+    }
+
+    // An upstream answering every call of a batch with its name after
+    // `delay`, in reverse order
+    fn upstream(name: &'static str, delay: Duration) -> String {
+        let service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let calls = serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap();
+                sleep(delay).await;
+                let responses = calls
+                    .iter()
+                    .rev()
+                    .filter_map(|call| {
+                        Some(serde_json::json!({"jsonrpc": "2.0", "id": call.get("id")?, "result": name}))
+                    })
+                    .collect::<Vec<_>>();
+                Ok::<_, Infallible>(Response::new(Body::from(
+                    serde_json::to_string(&responses).unwrap(),
+                )))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let uri = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        uri
+    }
+
+    #[test]
+    fn splits_batches_by_route() {
+        let context = context(&[
+            "--route",
+            "engine_*=http://localhost:8551",
+            "--route",
+            "re:^(debug|trace)_=http://localhost:8552",
+            "http://localhost:8545",
+        ]);
+        let request = serde_json::Value::from(vec![
+            call(Some(1), "engine_newPayloadV2"),
+            call(Some(2), "eth_chainId"),
+            call(Some(3), "trace_block"),
+            call(None, "engine_exchangeCapabilities"),
+            serde_json::json!("not a call"),
+        ]);
+        let calls = match RpcBody::parse(&request.to_string()) {
+            RpcBody::Batch(calls) => calls,
+            RpcBody::Single(_) => unreachable!(),
+        };
+        let groups = split_batch(&calls, &context)
+            .into_iter()
+            .map(|(route, group)| {
+                (
+                    route,
+                    group.iter().map(|(element, _)| element.clone()).collect(),
+                )
+            })
+            .collect::<Vec<(Option<usize>, Vec<serde_json::Value>)>>();
+        assert_eq!(
+            groups,
+            [
+                (Some(0), vec![request[0].clone(), request[3].clone()]),
+                (None, vec![request[1].clone(), request[4].clone()]),
+                (Some(1), vec![request[2].clone()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn puts_responses_in_call_order() {
+        let request = serde_json::Value::from(vec![
+            call(Some(1), "eth_chainId"),
+            call(None, "eth_subscribe"),
+            call(Some(2), "engine_newPayloadV2"),
+            call(Some(3), "eth_blockNumber"),
+        ]);
+        let calls = match RpcBody::parse(&request.to_string()) {
+            RpcBody::Batch(calls) => calls,
+            RpcBody::Single(_) => unreachable!(),
+        };
+        let response = |id: serde_json::Value| serde_json::json!({"jsonrpc": "2.0", "id": id, "result": "0x1"});
+        let ordered = in_call_order(
+            &calls,
+            vec![
+                response(3.into()),
+                response(serde_json::Value::Null),
+                response(1.into()),
+                response(2.into()),
+            ],
+        );
+        assert_eq!(
+            ordered,
+            [
+                response(1.into()),
+                response(2.into()),
+                response(3.into()),
+                response(serde_json::Value::Null),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_the_parts_of_a_split_batch_concurrently() {
+        let default = upstream("default", Duration::from_millis(300));
+        let engine = upstream("engine", Duration::from_millis(300));
+        let context = context(&["--route", &format!("engine_*={}", engine), &default]);
+        let request = serde_json::Value::from(vec![
+            call(Some(1), "eth_chainId"),
+            call(Some(2), "engine_newPayloadV2"),
+            call(None, "engine_exchangeCapabilities"),
+            call(Some(3), "eth_blockNumber"),
+            call(Some(4), "engine_forkchoiceUpdatedV2"),
+        ])
+        .to_string();
+        let source_request = SourceRequest {
+            method: Method::POST,
+            uri: Uri::from_static("/"),
+            headers: HeaderMap::new(),
+            body: request.clone().into(),
+        };
+
+        let start = Instant::now();
+        let (response, response_json, upstreams) =
+            route(&source_request, &RpcBody::parse(&request), &context)
+                .await
+                .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(550));
+        assert!(response.extensions().get::<Timing>().unwrap().total >= Duration::from_millis(300));
+        assert_eq!(upstreams, format!("{}, {}", default, engine));
+        let results = serde_json::from_str::<Vec<serde_json::Value>>(&response_json)
+            .unwrap()
+            .into_iter()
+            .map(|response| (response["id"].clone(), response["result"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                (1.into(), "default".into()),
+                (2.into(), "engine".into()),
+                (3.into(), "default".into()),
+                (4.into(), "engine".into()),
+            ]
+        );
+    }
+
+    fn batch(calls: &[serde_json::Value]) -> Vec<(serde_json::Value, Option<RpcRequest>)> {
+        match RpcBody::parse(&serde_json::Value::from(calls).to_string()) {
+            RpcBody::Batch(calls) => calls,
+            RpcBody::Single(_) => unreachable!(),
+        }
+    }
+
+    fn post(calls: &[serde_json::Value]) -> SourceRequest {
+        SourceRequest {
+            method: Method::POST,
+            uri: Uri::from_static("/"),
+            headers: HeaderMap::new(),
+            body: serde_json::Value::from(calls).to_string().into(),
+        }
+    }
+
+    // (id, result or error code) of each response, in the order they came
+    fn answers(response_json: &str) -> Vec<(serde_json::Value, serde_json::Value)> {
+        serde_json::from_str::<Vec<serde_json::Value>>(response_json)
+            .unwrap()
+            .into_iter()
+            .map(|response| match response.get("error") {
+                Some(error) => (response["id"].clone(), error["code"].clone()),
+                None => (response["id"].clone(), response["result"].clone()),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn answers_override_only_batches_without_the_upstream() {
+        // nothing listens there
+        let context = context(&["-f", "http://127.0.0.1:1"]);
+        let calls = [call(Some(1), "rpc_modules"), call(Some(2), "rpc_modules")];
+        let settings = context.settings();
+        let local = |element: &(serde_json::Value, Option<RpcRequest>)| {
+            local_answer(element, None, settings.override_rpc.as_deref(), "/")
+        };
+        let (response, response_json, upstream) =
+            get_batch_response_with_local_answers(post(&calls), &batch(&calls), local, &context)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(upstream, None);
+        let modules = serde_json::json!({"eth": "1.0", "net": "1.0", "web3": "1.0"});
+        assert_eq!(
+            answers(&response_json),
+            [(1.into(), modules.clone()), (2.into(), modules)]
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_local_answers_into_forwarded_batches() {
+        let node = upstream("node", Duration::ZERO);
+        let context = context(&["-f", &node]);
+        let calls = [
+            call(Some(1), "eth_chainId"),
+            call(Some(2), "rpc_modules"),
+            call(Some(3), "eth_call"),
+            call(None, "eth_call"),
+            call(None, "rpc_modules"),
+            call(Some(4), "eth_blockNumber"),
+        ];
+        let fault = chaos::parse_error("eth_call:100%:-32000").unwrap();
+        let settings = context.settings();
+        let local = |element: &(serde_json::Value, Option<RpcRequest>)| {
+            local_answer(element, Some(&fault), settings.override_rpc.as_deref(), "/")
+        };
+        let (_, response_json, upstream) =
+            get_batch_response_with_local_answers(post(&calls), &batch(&calls), local, &context)
+                .await
+                .unwrap();
+        assert_eq!(upstream.as_deref(), Some(node.as_str()));
+        // the upstream answers in reverse, the local answers come last and
+        // the notifications get none
+        assert_eq!(
+            answers(&response_json),
+            [
+                (4.into(), "node".into()),
+                (1.into(), "node".into()),
+                (
+                    2.into(),
+                    serde_json::json!({"eth": "1.0", "net": "1.0", "web3": "1.0"})
+                ),
+                (3.into(), (-32000).into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pairs_batch_responses_with_their_calls() {
+        let calls = batch(&[
+            call(Some(1), "eth_chainId"),
+            call(None, "eth_subscribe"),
+            call(Some(2), "eth_call"),
+            call(Some(3), "eth_blockNumber"),
+            serde_json::json!("not a call"),
+        ]);
+        let response = |id: serde_json::Value| serde_json::json!({"jsonrpc": "2.0", "id": id, "result": "0x1"});
+        let paired = pair_responses(
+            &calls,
+            vec![
+                response(3.into()),
+                response(serde_json::Value::Null),
+                response(1.into()),
+                response(7.into()),
+            ],
+        );
+        assert_eq!(
+            paired,
+            [
+                (Some(0), response(1.into())),
+                (Some(3), response(3.into())),
+                (None, response(serde_json::Value::Null)),
+                (None, response(7.into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn knows_what_the_override_answers() {
+        let overridden = context(&["-f", "http://localhost:8545"]);
+        let single = |method: &str| RpcBody::Single(batch(&[call(Some(1), method)])[0].1.clone());
+        assert!(is_answered_locally(&single("rpc_modules"), &overridden));
+        assert!(!is_answered_locally(&single("eth_chainId"), &overridden));
+        assert!(is_answered_locally(
+            &RpcBody::Batch(batch(&[
+                call(Some(1), "rpc_modules"),
+                call(None, "eth_subscribe"),
+                call(Some(2), "rpc_modules"),
+            ])),
+            &overridden
+        ));
+        assert!(!is_answered_locally(
+            &RpcBody::Batch(batch(&[
+                call(Some(1), "rpc_modules"),
+                call(Some(2), "eth_chainId"),
+            ])),
+            &overridden
+        ));
+        let context = context(&["http://localhost:8545"]);
+        assert!(!is_answered_locally(&single("rpc_modules"), &context));
+    }
+
+    // An upstream answering everything with 503
+    fn failing_upstream() -> String {
+        let service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Body::from("overloaded"))
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let uri = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        uri
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_5xx_only_when_asked_to() {
+        let failing = failing_upstream();
+        let node = upstream("node", Duration::ZERO);
+        let calls = [call(Some(1), "eth_sendRawTransaction")];
+
+        let passing_on = context(&[&failing, &node]);
+        let (response, _, upstream) =
+            forward(&post(&calls), &passing_on.inner.upstreams, &passing_on)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(upstream, failing);
+
+        let failing_over = context(&["--failover-on-5xx", &failing, &node]);
+        let (response, response_json, upstream) =
+            forward(&post(&calls), &failing_over.inner.upstreams, &failing_over)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(upstream, node);
+        assert_eq!(answers(&response_json), [(1.into(), "node".into())]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_connection_errors() {
+        let node = upstream("node", Duration::ZERO);
+        // nothing listens there
+        let context = context(&["http://127.0.0.1:1", &node]);
+        let calls = [call(Some(1), "eth_chainId")];
+        let (_, response_json, upstream) =
+            forward(&post(&calls), &context.inner.upstreams, &context)
+                .await
+                .unwrap();
+        assert_eq!(upstream, node);
+        assert_eq!(answers(&response_json), [(1.into(), "node".into())]);
+    }
+
+    #[test]
+    fn derives_exchange_rngs_from_the_seed_and_position() {
+        let seeded = context(&["--seed", "42", "http://localhost:8545"]);
+        let draws = |sequence, frame| {
+            let mut rng = seeded.exchange_rng(sequence, frame);
+            (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        let request = |number| Some((PacketType::Request, number));
+        let response = |number| Some((PacketType::Response, number));
+
+        assert_eq!(draws(3, None), draws(3, None));
+        assert_eq!(draws(3, request(0)), draws(3, request(0)));
+        // dropping a frame doesn't change its direction
+        assert_eq!(
+            draws(3, Some((PacketType::RequestDropped(12.0), 5))),
+            draws(3, request(5))
+        );
+        assert_ne!(draws(3, None), draws(4, None));
+        assert_ne!(draws(3, request(0)), draws(4, request(0)));
+        assert_ne!(draws(3, request(0)), draws(3, response(0)));
+        assert_ne!(draws(3, request(0)), draws(3, request(1)));
+        assert_ne!(draws(3, None), draws(3, request(0)));
+
+        let other = context(&["--seed", "43", "http://localhost:8545"]);
+        assert_ne!(
+            other.exchange_rng(3, None).next_u64(),
+            seeded.exchange_rng(3, None).next_u64()
+        );
     }
 }
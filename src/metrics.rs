@@ -0,0 +1,415 @@
+use crate::exchange::{Exchange, WEBSOCKET_METHOD};
+use crate::utils::RpcBody;
+use crate::SnoopContext;
+use hyper::{Body, Request, Response};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Prometheus' default buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Distinct paths given their own label, the rest are counted under `OTHER_PATH`
+const MAX_PATHS: usize = 100;
+const OTHER_PATH: &str = "(other)";
+// Likewise for methods, which any caller can make up
+const MAX_METHODS: usize = 100;
+const OTHER_METHOD: &str = "(other)";
+
+// (JSON-RPC method, path) - the method is empty for bodies that aren't JSON-RPC
+type Labels = (String, String);
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // cumulative counts, one per bucket in `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    requests: BTreeMap<Labels, u64>,
+    rpc_errors: BTreeMap<Labels, u64>,
+    // keyed by the labels plus the status class, e.g. `2xx`
+    responses: BTreeMap<(Labels, String), u64>,
+    dropped_requests: BTreeMap<Labels, u64>,
+    dropped_responses: BTreeMap<Labels, u64>,
+    injected_errors: BTreeMap<Labels, u64>,
+    latency: BTreeMap<Labels, Histogram>,
+    // bodies can't be split between the calls of a batch, so these are only
+    // counted per path
+    request_bytes: BTreeMap<String, u64>,
+    response_bytes: BTreeMap<String, u64>,
+    // subscription notifications sent over websockets, per path
+    notifications: BTreeMap<String, u64>,
+    paths: BTreeSet<String>,
+    methods: BTreeSet<String>,
+}
+
+impl Registry {
+    // The path label for a request path, ids in it replaced by `:id` so e.g.
+    // `/rpc/<api key>` doesn't get a label per key
+    fn path_label(&mut self, path: &str) -> String {
+        let path = path.split_once('?').map(|(path, _)| path).unwrap_or(path);
+        let path = path
+            .split('/')
+            .map(|segment| if is_id(segment) { ":id" } else { segment })
+            .collect::<Vec<_>>()
+            .join("/");
+        if self.paths.contains(&path) {
+            path
+        } else if self.paths.len() < MAX_PATHS {
+            self.paths.insert(path.clone());
+            path
+        } else {
+            OTHER_PATH.to_string()
+        }
+    }
+
+    fn method_label(&mut self, method: String) -> String {
+        if self.methods.contains(&method) {
+            method
+        } else if self.methods.len() < MAX_METHODS {
+            self.methods.insert(method.clone());
+            method
+        } else {
+            OTHER_METHOD.to_string()
+        }
+    }
+}
+
+// Numbers, hex strings and other long tokens (api keys, uuids, hashes)
+fn is_id(segment: &str) -> bool {
+    let digits = segment.strip_prefix("0x").unwrap_or(segment);
+    !digits.is_empty()
+        && (digits.chars().all(|c| c.is_ascii_digit())
+            || segment.starts_with("0x") && digits.chars().all(|c| c.is_ascii_hexdigit())
+            || segment.len() >= 16
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+}
+
+// Counters for `--metrics-port`, fed from every completed exchange. Batches
+// count each of their calls under its own method, websocket frames count as
+// answered with status `ws`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels((method, path): &Labels) -> String {
+    format!("method=\"{}\",path=\"{}\"", escape(method), escape(path))
+}
+
+// The response element answering each call, `None` for calls nothing answered
+fn pair_responses(
+    rpc_body: &RpcBody,
+    response_json: &str,
+) -> Vec<(String, Option<serde_json::Value>)> {
+    let response = serde_json::from_str::<serde_json::Value>(response_json).ok();
+    match rpc_body {
+        RpcBody::Single(call) => vec![(
+            call.as_ref()
+                .map(|call| call.method.clone())
+                .unwrap_or_default(),
+            response,
+        )],
+        RpcBody::Batch(calls) => {
+            let elements = match response {
+                Some(serde_json::Value::Array(elements)) => elements,
+                _ => vec![],
+            };
+            calls
+                .iter()
+                .map(|(element, call)| {
+                    let method = call
+                        .as_ref()
+                        .map(|call| call.method.clone())
+                        .unwrap_or_default();
+                    let response = element.get("id").and_then(|id| {
+                        elements
+                            .iter()
+                            .find(|response| response.get("id") == Some(id))
+                            .cloned()
+                    });
+                    (method, response)
+                })
+                .collect()
+        }
+    }
+}
+
+fn write_counters<K>(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    counters: &BTreeMap<K, u64>,
+    labels: impl Fn(&K) -> String,
+) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} counter", name);
+    for (key, value) in counters {
+        let _ = writeln!(output, "{}{{{}}} {}", name, labels(key), value);
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, exchange: &Exchange, rpc_body: &RpcBody) {
+        let websocket = exchange.http_method == WEBSOCKET_METHOD;
+        let response_json = exchange.response_json.as_deref().unwrap_or("null");
+        let status_class = match (exchange.status, &exchange.response_json) {
+            (Some(status), _) => Some(format!("{}xx", status.as_u16() / 100)),
+            (None, Some(_)) if websocket => Some("ws".to_string()),
+            _ => None,
+        };
+
+        let mut registry = self.registry.lock().unwrap();
+        let path = registry.path_label(&exchange.path);
+        // a notification answers no request
+        if websocket && exchange.request_json == "null" {
+            *registry.notifications.entry(path.clone()).or_default() += 1;
+            if let Some(response_bytes) = exchange.response_bytes {
+                *registry.response_bytes.entry(path).or_default() += response_bytes as u64;
+            }
+            return;
+        }
+        for (method, response) in pair_responses(rpc_body, response_json) {
+            let labels = (registry.method_label(method), path.clone());
+            *registry.requests.entry(labels.clone()).or_default() += 1;
+            if exchange.request_dropped {
+                *registry.dropped_requests.entry(labels.clone()).or_default() += 1;
+            }
+            if exchange.response_dropped {
+                *registry
+                    .dropped_responses
+                    .entry(labels.clone())
+                    .or_default() += 1;
+            }
+            if exchange.injected {
+                *registry.injected_errors.entry(labels.clone()).or_default() += 1;
+            }
+            if response
+                .as_ref()
+                .and_then(|response| response.get("error"))
+                .is_some()
+            {
+                *registry.rpc_errors.entry(labels.clone()).or_default() += 1;
+            }
+            if let Some(status_class) = &status_class {
+                *registry
+                    .responses
+                    .entry((labels.clone(), status_class.clone()))
+                    .or_default() += 1;
+            }
+            if let Some(latency) = exchange.latency {
+                registry.latency.entry(labels).or_default().observe(latency);
+            }
+        }
+        *registry.request_bytes.entry(path.clone()).or_default() += exchange.request_bytes as u64;
+        if let Some(response_bytes) = exchange.response_bytes {
+            *registry.response_bytes.entry(path).or_default() += response_bytes as u64;
+        }
+    }
+
+    // Renders everything in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut output = String::new();
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_requests_total",
+            "JSON-RPC calls received",
+            &registry.requests,
+            labels,
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_rpc_errors_total",
+            "JSON-RPC calls answered with an error",
+            &registry.rpc_errors,
+            labels,
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_responses_total",
+            "JSON-RPC calls answered, by http status class (ws for websocket frames)",
+            &registry.responses,
+            |(key, status)| format!("{},status=\"{}\"", labels(key), status),
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_dropped_requests_total",
+            "JSON-RPC calls dropped before being forwarded",
+            &registry.dropped_requests,
+            labels,
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_dropped_responses_total",
+            "JSON-RPC calls whose response was dropped",
+            &registry.dropped_responses,
+            labels,
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_injected_errors_total",
+            "JSON-RPC calls answered with an injected error",
+            &registry.injected_errors,
+            labels,
+        );
+
+        let name = "json_rpc_snoop_request_duration_seconds";
+        let _ = writeln!(
+            output,
+            "# HELP {} Time taken to answer JSON-RPC calls",
+            name
+        );
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+        for (key, histogram) in &registry.latency {
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    output,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name,
+                    labels(key),
+                    bound,
+                    bucket
+                );
+            }
+            let _ = writeln!(
+                output,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name,
+                labels(key),
+                histogram.count
+            );
+            let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels(key), histogram.sum);
+            let _ = writeln!(
+                output,
+                "{}_count{{{}}} {}",
+                name,
+                labels(key),
+                histogram.count
+            );
+        }
+
+        let path_label = |path: &String| format!("path=\"{}\"", escape(path));
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_request_bytes_total",
+            "Bytes of request bodies received",
+            &registry.request_bytes,
+            path_label,
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_response_bytes_total",
+            "Bytes of response bodies sent back",
+            &registry.response_bytes,
+            path_label,
+        );
+        write_counters(
+            &mut output,
+            "json_rpc_snoop_subscription_notifications_total",
+            "Subscription notifications sent over websockets",
+            &registry.notifications,
+            path_label,
+        );
+        output
+    }
+}
+
+pub async fn handle_request(
+    context: SnoopContext,
+    _request: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let body = context
+        .inner
+        .metrics
+        .as_ref()
+        .map(|metrics| metrics.render())
+        .unwrap_or_default();
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_paths_without_ids() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.path_label("/"), "/");
+        assert_eq!(registry.path_label("/eth?key=1"), "/eth");
+        assert_eq!(
+            registry.path_label("/v3/9aa3d95b3bc440fa88ea12eaa4456161"),
+            "/v3/:id"
+        );
+        assert_eq!(registry.path_label("/block/0x1f/12"), "/block/:id/:id");
+        assert_eq!(registry.path_label("/0xzz"), "/0xzz");
+    }
+
+    #[test]
+    fn caps_the_number_of_paths() {
+        let mut registry = Registry::default();
+        for i in 0..MAX_PATHS {
+            assert_eq!(registry.path_label(&format!("/p{}", i)), format!("/p{}", i));
+        }
+        assert_eq!(registry.path_label("/one-too-many"), OTHER_PATH);
+        assert_eq!(registry.path_label("/p0"), "/p0");
+    }
+
+    #[test]
+    fn caps_the_number_of_methods() {
+        let mut registry = Registry::default();
+        for i in 0..MAX_METHODS {
+            assert_eq!(registry.method_label(format!("m{}", i)), format!("m{}", i));
+        }
+        assert_eq!(
+            registry.method_label("one_too_many".to_string()),
+            OTHER_METHOD
+        );
+        assert_eq!(registry.method_label("m0".to_string()), "m0");
+    }
+
+    #[test]
+    fn counts_made_up_methods_as_other() {
+        let metrics = Metrics::default();
+        for i in 0..MAX_METHODS + 10 {
+            let request_json = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"m{}"}}"#, i);
+            let exchange = Exchange::answered(&request_json, "null");
+            metrics.record(&exchange, &RpcBody::parse(&request_json));
+        }
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"json_rpc_snoop_requests_total{method="m99",path="/"} 1"#));
+        assert!(!rendered.contains(r#"method="m100""#));
+        assert!(rendered.contains(r#"json_rpc_snoop_requests_total{method="(other)",path="/"} 10"#));
+    }
+}
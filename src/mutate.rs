@@ -0,0 +1,493 @@
+use crate::chaos::{self, Target};
+use crate::utils::RpcBody;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::Value;
+use std::str::FromStr;
+
+// values longer than this are abbreviated when describing a mutation
+const SHOWN_CHARS: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    FlipHex,
+    TruncateArray,
+    DropField,
+    Null,
+    ChangeType,
+    InvalidJson,
+}
+
+const ALL: [Mutation; 6] = [
+    Mutation::FlipHex,
+    Mutation::TruncateArray,
+    Mutation::DropField,
+    Mutation::Null,
+    Mutation::ChangeType,
+    Mutation::InvalidJson,
+];
+
+impl FromStr for Mutation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        ALL.into_iter()
+            .find(|mutation| mutation.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown mutation '{}' (expected {})",
+                    s,
+                    ALL.map(|mutation| mutation.to_string()).join(", ")
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for Mutation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mutation::FlipHex => write!(f, "flip-hex"),
+            Mutation::TruncateArray => write!(f, "truncate-array"),
+            Mutation::DropField => write!(f, "drop-field"),
+            Mutation::Null => write!(f, "null"),
+            Mutation::ChangeType => write!(f, "change-type"),
+            Mutation::InvalidJson => write!(f, "invalid-json"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MutateRule {
+    pub target: Target,
+    // odds of firing, in [0..1]
+    pub rate: f64,
+    pub mutations: Vec<Mutation>,
+}
+
+// `[TARGET:]RATE%[:MUTATION,...]`, all mutations when none are given
+pub fn parse_mutate(arg: &str) -> Result<MutateRule, String> {
+    let (target, rate, parts) = chaos::split_rate(arg)?;
+    let mutations = match parts.as_slice() {
+        [] | [""] => ALL.to_vec(),
+        [mutations] => mutations
+            .split(',')
+            .map(Mutation::from_str)
+            .collect::<Result<_, _>>()?,
+        _ => {
+            return Err(format!(
+                "Unable to parse '{}' as '[TARGET:]RATE%[:MUTATION,...]'",
+                arg
+            ))
+        }
+    };
+    Ok(MutateRule {
+        target,
+        rate,
+        mutations,
+    })
+}
+
+#[derive(Clone, Debug)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+// e.g. `[1].result.transactions[3].hash`
+fn describe_path(path: &[Step]) -> String {
+    let mut described = String::new();
+    for step in path {
+        match step {
+            Step::Key(key) if described.is_empty() => described.push_str(key),
+            Step::Key(key) => {
+                described.push('.');
+                described.push_str(key);
+            }
+            Step::Index(i) => described.push_str(&format!("[{}]", i)),
+        }
+    }
+    if described.is_empty() {
+        described.push_str("body");
+    }
+    described
+}
+
+fn show(value: &Value) -> String {
+    let shown = value.to_string();
+    match shown.char_indices().nth(SHOWN_CHARS) {
+        Some((end, _)) => format!("{}...", &shown[..end]),
+        None => shown,
+    }
+}
+
+fn get_mut<'a>(value: &'a mut Value, path: &[Step]) -> Option<&'a mut Value> {
+    path.iter().try_fold(value, |value, step| match step {
+        Step::Key(key) => value.get_mut(key.as_str()),
+        Step::Index(i) => value.get_mut(*i),
+    })
+}
+
+// every value under `path` that `accept` allows, as paths from the body
+fn candidates(
+    value: &Value,
+    path: &mut Vec<Step>,
+    accept: fn(&Value) -> bool,
+    out: &mut Vec<Vec<Step>>,
+) {
+    if accept(value) {
+        out.push(path.clone());
+    }
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                path.push(Step::Key(key.clone()));
+                candidates(child, path, accept, out);
+                path.pop();
+            }
+        }
+        Value::Array(values) => {
+            for (i, child) in values.iter().enumerate() {
+                path.push(Step::Index(i));
+                candidates(child, path, accept, out);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_hex(value: &Value) -> bool {
+    match value.as_str().and_then(|s| s.strip_prefix("0x")) {
+        Some(digits) => !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+fn accepts(mutation: Mutation) -> fn(&Value) -> bool {
+    match mutation {
+        Mutation::FlipHex => is_hex,
+        Mutation::TruncateArray => {
+            |value| matches!(value, Value::Array(values) if !values.is_empty())
+        }
+        Mutation::DropField => |value| matches!(value, Value::Object(map) if !map.is_empty()),
+        Mutation::Null => |value| !value.is_null(),
+        Mutation::ChangeType | Mutation::InvalidJson => |_| true,
+    }
+}
+
+fn change_type(value: &Value) -> Value {
+    match value {
+        // a hex quantity turns into the number it encodes, and anything too
+        // long for that (like a hash) into its number of digits
+        Value::String(s) if is_hex(value) => u64::from_str_radix(&s[2..], 16)
+            .map(Value::from)
+            .unwrap_or_else(|_| Value::from(s[2..].len())),
+        Value::String(s) => Value::from(s.len()),
+        Value::Number(n) => Value::from(n.to_string()),
+        Value::Bool(b) => Value::from(b.to_string()),
+        Value::Null => Value::from(false),
+        Value::Array(values) => Value::Object(
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| (i.to_string(), value.clone()))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Array(map.values().cloned().collect()),
+    }
+}
+
+// Changes the value at `path` in place and describes the change
+fn apply<R: Rng>(mutation: Mutation, value: &mut Value, at: &str, rng: &mut R) -> String {
+    match mutation {
+        Mutation::FlipHex => {
+            let old = value.as_str().unwrap_or_default().to_string();
+            let position = rng.gen_range(2..old.len());
+            let current = old.as_bytes()[position] as char;
+            let digits = "0123456789abcdef"
+                .chars()
+                .filter(|c| !c.eq_ignore_ascii_case(&current))
+                .collect::<Vec<_>>();
+            let mut new = old.clone();
+            new.replace_range(
+                position..position + 1,
+                &digits.choose(rng).unwrap().to_string(),
+            );
+            let old = std::mem::replace(value, Value::from(new));
+            format!("flip-hex at {}: {} -> {}", at, show(&old), show(value))
+        }
+        Mutation::TruncateArray => {
+            let values = value.as_array_mut().unwrap();
+            let (old, new) = (values.len(), rng.gen_range(0..values.len()));
+            values.truncate(new);
+            format!("truncate-array at {}: {} -> {} elements", at, old, new)
+        }
+        Mutation::DropField => {
+            let map = value.as_object_mut().unwrap();
+            let key = map
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+                .choose(rng)
+                .unwrap()
+                .clone();
+            // rebuilt rather than removed from so the other fields keep their order
+            let mut old = Value::Null;
+            *map = std::mem::take(map)
+                .into_iter()
+                .filter_map(|(name, value)| match name == key {
+                    true => {
+                        old = value;
+                        None
+                    }
+                    false => Some((name, value)),
+                })
+                .collect();
+            format!("drop-field at {}: removed {} = {}", at, key, show(&old))
+        }
+        Mutation::Null => {
+            let old = std::mem::take(value);
+            format!("null at {}: {} -> null", at, show(&old))
+        }
+        Mutation::ChangeType => {
+            let new = change_type(value);
+            let old = std::mem::replace(value, new);
+            format!("change-type at {}: {} -> {}", at, show(&old), show(value))
+        }
+        Mutation::InvalidJson => unreachable!("invalid json replaces the whole body"),
+    }
+}
+
+// Mangles a successful response with one of `mutations`, picked at random
+// among those that apply to it. Returns the body to send, the body pretty
+// printed for logging and a description of the change, or `None` if there is
+// nothing to mutate.
+pub fn mutate<R: Rng>(
+    response_json: &str,
+    mutations: &[Mutation],
+    rng: &mut R,
+) -> Option<(String, String, String)> {
+    let mut body = serde_json::from_str::<Value>(response_json).ok()?;
+    // only results are mutated, so the response still matches its call
+    let roots = match &body {
+        Value::Array(responses) => responses
+            .iter()
+            .enumerate()
+            .filter(|(_, response)| response.get("result").is_some())
+            .map(|(i, _)| vec![Step::Index(i), Step::Key("result".to_string())])
+            .collect(),
+        Value::Object(map) if map.contains_key("result") => {
+            vec![vec![Step::Key("result".to_string())]]
+        }
+        // errors are left alone
+        Value::Object(map) if map.contains_key("error") || map.contains_key("jsonrpc") => vec![],
+        // not JSON-RPC (e.g. a REST api), the whole body is fair game
+        _ => vec![vec![]],
+    };
+    if roots.is_empty() {
+        return None;
+    }
+
+    let mut mutations = mutations.to_vec();
+    mutations.shuffle(rng);
+    for mutation in mutations {
+        if mutation == Mutation::InvalidJson {
+            let sent = body.to_string();
+            if sent.len() < 2 {
+                continue;
+            }
+            let mut cut = rng.gen_range(1..sent.len());
+            while !sent.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            let description = format!("invalid-json: cut after {} of {} bytes", cut, sent.len());
+            return Some((
+                sent[..cut].to_string(),
+                sent[..cut].to_string(),
+                description,
+            ));
+        }
+
+        let mut paths = vec![];
+        for root in &roots {
+            if let Some(value) = get_mut(&mut body, root) {
+                candidates(value, &mut root.clone(), accepts(mutation), &mut paths);
+            }
+        }
+        let path = match paths.choose(rng) {
+            Some(path) => path.clone(),
+            None => continue,
+        };
+        let value = get_mut(&mut body, &path)?;
+        let description = apply(mutation, value, &describe_path(&path), rng);
+        let pretty = serde_json::to_string_pretty(&body).unwrap_or_else(|_| body.to_string());
+        return Some((body.to_string(), pretty, description));
+    }
+    None
+}
+
+// Rolls every rule that applies, in order, and mutates the response with the
+// first one that fires
+pub fn mutate_response<R: Rng>(
+    rules: &[MutateRule],
+    rpc_body: &RpcBody,
+    request_path: &str,
+    response_json: &str,
+    rng: &mut R,
+) -> Option<(String, String, String)> {
+    let rule = rules
+        .iter()
+        .filter(|rule| rule.target.matches(rpc_body, request_path))
+        .find(|rule| rng.gen::<f64>() < rule.rate)?;
+    mutate(response_json, &rule.mutations, rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::json;
+
+    // mutates `response` with `mutation`, returning the body sent and the description
+    fn mutated(response: Value, mutation: Mutation) -> Option<(String, String)> {
+        let mut rng = StdRng::seed_from_u64(1);
+        mutate(&response.to_string(), &[mutation], &mut rng)
+            .map(|(sent, _, description)| (sent, description))
+    }
+
+    fn result(sent: &str) -> Value {
+        serde_json::from_str::<Value>(sent).unwrap()["result"].take()
+    }
+
+    #[test]
+    fn parses_mutate_rules() {
+        let rule = parse_mutate("eth_call:5%").unwrap();
+        assert!(matches!(rule.target, Target::Method(_)));
+        assert_eq!(rule.rate, 0.05);
+        assert_eq!(rule.mutations, ALL.to_vec());
+        let rule = parse_mutate("10%:flip-hex,null").unwrap();
+        assert!(matches!(rule.target, Target::Any));
+        assert_eq!(rule.mutations, vec![Mutation::FlipHex, Mutation::Null]);
+        assert_eq!(parse_mutate("10%:").unwrap().mutations, ALL.to_vec());
+        assert!(parse_mutate("10%:reverse").is_err());
+        assert!(parse_mutate("10%:null:extra").is_err());
+        assert!(parse_mutate("eth_call").is_err());
+    }
+
+    #[test]
+    fn flips_a_hex_digit() {
+        let (sent, description) = mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x1234"}),
+            Mutation::FlipHex,
+        )
+        .unwrap();
+        let flipped = result(&sent);
+        let flipped = flipped.as_str().unwrap();
+        assert_eq!(flipped.len(), 6);
+        assert!(flipped.starts_with("0x"));
+        assert_eq!(
+            flipped
+                .chars()
+                .zip("0x1234".chars())
+                .filter(|(a, b)| a != b)
+                .count(),
+            1
+        );
+        assert!(description.starts_with("flip-hex at result: \"0x1234\" -> \"0x"));
+        assert!(mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x"}),
+            Mutation::FlipHex
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn shortens_long_flipped_values() {
+        let calldata = format!("0x{}", "ab".repeat(1000));
+        let (_, description) = mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": calldata}),
+            Mutation::FlipHex,
+        )
+        .unwrap();
+        assert!(description.len() < 200);
+        assert!(description.ends_with("..."));
+    }
+
+    #[test]
+    fn truncates_an_array() {
+        let (sent, description) = mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": [1, 2, 3]}),
+            Mutation::TruncateArray,
+        )
+        .unwrap();
+        let truncated = result(&sent).as_array().unwrap().clone();
+        assert!(truncated.len() < 3);
+        assert_eq!(
+            truncated[..],
+            [json!(1), json!(2), json!(3)][..truncated.len()]
+        );
+        assert!(description.starts_with("truncate-array at result: 3 -> "));
+    }
+
+    #[test]
+    fn drops_a_field() {
+        let (sent, description) = mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": {"a": 1, "b": 2}}),
+            Mutation::DropField,
+        )
+        .unwrap();
+        assert_eq!(result(&sent).as_object().unwrap().len(), 1);
+        assert!(description.starts_with("drop-field at result: removed "));
+    }
+
+    #[test]
+    fn nulls_a_value() {
+        let (sent, description) = mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"}),
+            Mutation::Null,
+        )
+        .unwrap();
+        assert_eq!(result(&sent), Value::Null);
+        assert_eq!(description, "null at result: \"0x1\" -> null");
+    }
+
+    #[test]
+    fn changes_types() {
+        assert_eq!(change_type(&json!("0x1f")), json!(31));
+        // too long for a u64, so it turns into its number of digits
+        let hash = format!("0x{}", "ab".repeat(32));
+        assert_eq!(change_type(&json!(hash)), json!(64));
+        assert_eq!(change_type(&json!("latest")), json!(6));
+        assert_eq!(change_type(&json!(12)), json!("12"));
+        assert_eq!(change_type(&json!(true)), json!("true"));
+        assert_eq!(change_type(&Value::Null), json!(false));
+        assert_eq!(change_type(&json!(["a", "b"])), json!({"0": "a", "1": "b"}));
+        assert_eq!(change_type(&json!({"a": 1})), json!([1]));
+
+        let (sent, description) = mutated(
+            json!({"jsonrpc": "2.0", "id": 1, "result": null}),
+            Mutation::ChangeType,
+        )
+        .unwrap();
+        assert_eq!(result(&sent), json!(false));
+        assert_eq!(description, "change-type at result: null -> false");
+    }
+
+    #[test]
+    fn cuts_the_json_short() {
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"});
+        let (sent, description) = mutated(response.clone(), Mutation::InvalidJson).unwrap();
+        assert!(response.to_string().starts_with(&sent));
+        assert!(sent.len() < response.to_string().len());
+        assert!(serde_json::from_str::<Value>(&sent).is_err());
+        assert!(description.starts_with("invalid-json: cut after "));
+    }
+
+    #[test]
+    fn leaves_errors_alone() {
+        let error = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "x"}});
+        for mutation in ALL {
+            assert!(mutated(error.clone(), mutation).is_none());
+        }
+    }
+}
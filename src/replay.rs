@@ -0,0 +1,541 @@
+use crate::utils::{RpcBody, RpcError, RpcErrorResponse, RpcRequest, SnoopError};
+use crate::SourceRequest;
+use hyper::{Body, Response, StatusCode};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+// One exchange from a recording, whichever format it was loaded from
+struct Recording {
+    http_method: String,
+    path: String,
+    request: String,
+    status: StatusCode,
+    response: String,
+}
+
+// Recorded responses for one request, served in order. Once they run out the
+// last one keeps being served, so polling methods like `eth_blockNumber`
+// behave the way they did while recording.
+#[derive(Debug, Default)]
+struct Responses {
+    recorded: Vec<(StatusCode, serde_json::Value)>,
+    next: usize,
+}
+
+impl Responses {
+    fn next(&mut self) -> (StatusCode, serde_json::Value) {
+        let response = self.recorded[self.next.min(self.recorded.len() - 1)].clone();
+        self.next += 1;
+        response
+    }
+}
+
+// Serves a previously recorded session (`--log-file` or `--har` output) in
+// place of an upstream. JSON-RPC calls are matched on method and params,
+// anything else on http method and path.
+#[derive(Debug)]
+pub struct Replay {
+    responses: Mutex<HashMap<String, Responses>>,
+}
+
+// Sorts object keys recursively so params that only differ in key order match
+fn canonical(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries = map.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(key, _)| *key);
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), canonical(value)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.iter().map(canonical).collect())
+        }
+        value => value.clone(),
+    }
+}
+
+fn call_key(call: &RpcRequest) -> String {
+    let params = call.params.as_ref().map(canonical).unwrap_or_default();
+    format!("rpc {} {}", call.method, params)
+}
+
+fn http_key(http_method: &str, path: &str) -> String {
+    format!("http {} {}", http_method, path)
+}
+
+fn body_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn load_ndjson(contents: &str) -> Result<Vec<Recording>, String> {
+    let mut recordings = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<serde_json::Value>(line)
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        // dropped requests never got a response worth replaying
+        let status = match record.get("status").and_then(|status| status.as_u64()) {
+            Some(status) => {
+                StatusCode::from_u16(status as u16).map_err(|e| format!("line {}: {}", i + 1, e))?
+            }
+            None => continue,
+        };
+        let text = |field: &str| record.get(field).map(body_text).unwrap_or_default();
+        recordings.push(Recording {
+            http_method: text("http_method"),
+            path: text("path"),
+            request: text("request"),
+            status,
+            response: text("response"),
+        });
+    }
+    Ok(recordings)
+}
+
+fn load_har(har: &serde_json::Value) -> Result<Vec<Recording>, String> {
+    let entries = har
+        .pointer("/log/entries")
+        .and_then(|entries| entries.as_array())
+        .ok_or_else(|| "not a HAR file: missing log.entries".to_string())?;
+    let mut recordings = vec![];
+    for entry in entries {
+        let text = |pointer: &str| {
+            entry
+                .pointer(pointer)
+                .and_then(|text| text.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let status = entry
+            .pointer("/response/status")
+            .and_then(|status| status.as_u64())
+            .and_then(|status| StatusCode::from_u16(status as u16).ok());
+        let status = match status {
+            Some(status) => status,
+            None => continue,
+        };
+        let url = text("/request/url");
+        let path = url
+            .split_once("://")
+            .and_then(|(_, rest)| rest.find('/').map(|i| rest[i..].to_string()))
+            .unwrap_or_else(|| "/".to_string());
+        recordings.push(Recording {
+            http_method: text("/request/method"),
+            path,
+            request: text("/request/postData/text"),
+            status,
+            response: text("/response/content/text"),
+        });
+    }
+    Ok(recordings)
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("Unable to load {}: {}", path.display(), e))
+    }
+
+    // a HAR file is one JSON document, a traffic log one record per line
+    fn parse(contents: &str) -> Result<Self, String> {
+        let recordings = match serde_json::from_str::<serde_json::Value>(contents) {
+            Ok(har) if har.get("log").is_some() => load_har(&har),
+            _ => load_ndjson(contents),
+        }?;
+
+        let mut responses = HashMap::<String, Responses>::new();
+        let mut record = |key: String, status: StatusCode, response: serde_json::Value| {
+            responses
+                .entry(key)
+                .or_default()
+                .recorded
+                .push((status, response));
+        };
+        for recording in recordings {
+            let response = serde_json::from_str::<serde_json::Value>(&recording.response)
+                .unwrap_or(serde_json::Value::String(recording.response));
+            match (RpcBody::parse(&recording.request), response) {
+                (RpcBody::Single(Some(call)), response) => {
+                    record(call_key(&call), recording.status, response)
+                }
+                (RpcBody::Batch(calls), serde_json::Value::Array(elements)) => {
+                    for (element, call) in calls {
+                        let response = element.get("id").and_then(|id| {
+                            elements
+                                .iter()
+                                .find(|response| response.get("id") == Some(id))
+                        });
+                        if let (Some(call), Some(response)) = (call, response) {
+                            record(call_key(&call), recording.status, response.clone());
+                        }
+                    }
+                }
+                (_, response) => record(
+                    http_key(&recording.http_method, &recording.path),
+                    recording.status,
+                    response,
+                ),
+            }
+        }
+
+        Ok(Self {
+            responses: Mutex::new(responses),
+        })
+    }
+
+    // The recorded http status is only meaningful for a call sent on its own
+    fn respond_to_call(
+        &self,
+        call: &RpcRequest,
+    ) -> Result<(StatusCode, serde_json::Value), String> {
+        let mut responses = self.responses.lock().unwrap();
+        let (status, mut response) = responses
+            .get_mut(&call_key(call))
+            .map(|responses| responses.next())
+            .ok_or_else(|| {
+                format!(
+                    "no recorded response for {} with params {}",
+                    call.method,
+                    call.params.clone().unwrap_or_default()
+                )
+            })?;
+        if let (Some(object), Some(id)) = (response.as_object_mut(), &call.id) {
+            object.insert(
+                "id".to_string(),
+                serde_json::to_value(id).expect("should serialize id"),
+            );
+        }
+        Ok((status, response))
+    }
+
+    pub fn respond(
+        &self,
+        source_request: &SourceRequest,
+        rpc_body: &RpcBody,
+    ) -> Result<(Response<Body>, String, String), SnoopError> {
+        // notifications get no answer, recorded or not
+        if !rpc_body.expects_response() {
+            let source_response = Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::empty())?;
+            return Ok((
+                source_response,
+                crate::format_response_json(&[]),
+                "replay".to_string(),
+            ));
+        }
+        let (status, response) = match rpc_body {
+            RpcBody::Single(Some(call)) => {
+                self.respond_to_call(call).map_err(SnoopError::Replay)?
+            }
+            // unmatched calls in a batch get their own error so the rest of
+            // the batch can still be answered
+            RpcBody::Batch(calls) => {
+                let elements = calls
+                    .iter()
+                    .filter_map(|(_, call)| call.as_ref())
+                    .filter(|call| !call.is_notification())
+                    .map(|call| {
+                        self.respond_to_call(call)
+                            .map(|(_, response)| response)
+                            .unwrap_or_else(|e| {
+                                serde_json::to_value(RpcErrorResponse {
+                                    id: call.id.clone().expect("notifications are skipped"),
+                                    jsonrpc: "2.0".to_string(),
+                                    error: RpcError {
+                                        code: -32603,
+                                        message: format!("Error processing response: {}", e),
+                                    },
+                                })
+                                .expect("should serialize error")
+                            })
+                    })
+                    .collect();
+                (StatusCode::OK, serde_json::Value::Array(elements))
+            }
+            RpcBody::Single(None) => {
+                let path = source_request
+                    .uri
+                    .path_and_query()
+                    .map(|path| path.as_str())
+                    .unwrap_or("/");
+                let key = http_key(source_request.method.as_str(), path);
+                let mut responses = self.responses.lock().unwrap();
+                responses
+                    .get_mut(&key)
+                    .map(|responses| responses.next())
+                    .ok_or_else(|| {
+                        SnoopError::Replay(format!(
+                            "no recorded response for {} {}",
+                            source_request.method, path
+                        ))
+                    })?
+            }
+        };
+
+        let response_bytes = match response {
+            serde_json::Value::String(text) => text.into_bytes(),
+            value => serde_json::to_vec(&value).expect("should serialize json value"),
+        };
+        let response_json = crate::format_response_json(&response_bytes);
+        let source_response = Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(response_bytes))?;
+
+        Ok((source_response, response_json, "replay".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn call(json: serde_json::Value) -> RpcRequest {
+        match RpcBody::parse(&json.to_string()) {
+            RpcBody::Single(Some(call)) => call,
+            body => panic!("expected a single call, got {:?}", body),
+        }
+    }
+
+    // a traffic log line as `--log-file` writes it
+    fn record(request: serde_json::Value, status: u16, response: serde_json::Value) -> String {
+        json!({
+            "http_method": "POST",
+            "path": "/",
+            "request": request,
+            "status": status,
+            "response": response,
+        })
+        .to_string()
+    }
+
+    fn replay(records: &[String]) -> Replay {
+        Replay::parse(&records.join("\n")).unwrap()
+    }
+
+    // only http recordings look at the request itself, not its body
+    fn post() -> SourceRequest {
+        SourceRequest {
+            method: hyper::Method::POST,
+            uri: hyper::Uri::from_static("/"),
+            headers: hyper::HeaderMap::new(),
+            body: Default::default(),
+        }
+    }
+
+    // the status and body replayed for `request`
+    fn respond(replay: &Replay, request: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let source_request = post();
+        let (response, response_json, _) = replay
+            .respond(&source_request, &RpcBody::parse(&request.to_string()))
+            .unwrap();
+        let body = serde_json::from_str(&response_json).unwrap();
+        (response.status(), body)
+    }
+
+    fn block_number(id: serde_json::Value) -> serde_json::Value {
+        json!({"jsonrpc": "2.0", "id": id, "method": "eth_blockNumber", "params": []})
+    }
+
+    #[test]
+    fn keys_calls_by_method_and_params() {
+        let a = call(
+            json!({"jsonrpc": "2.0", "id": 1, "method": "m", "params": [{"b": 1, "a": {"d": 2, "c": 3}}]}),
+        );
+        let b = call(
+            json!({"jsonrpc": "2.0", "id": "x", "method": "m", "params": [{"a": {"c": 3, "d": 2}, "b": 1}]}),
+        );
+        assert_eq!(call_key(&a), call_key(&b));
+        let c = call(json!({"jsonrpc": "2.0", "id": 1, "method": "m", "params": [{"a": 1}]}));
+        assert_ne!(call_key(&a), call_key(&c));
+        let d = call(
+            json!({"jsonrpc": "2.0", "id": 1, "method": "n", "params": [{"b": 1, "a": {"d": 2, "c": 3}}]}),
+        );
+        assert_ne!(call_key(&a), call_key(&d));
+    }
+
+    #[test]
+    fn puts_the_callers_id_in_the_response() {
+        let replay = replay(&[record(
+            block_number(json!(1)),
+            200,
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"}),
+        )]);
+        for id in [json!("abc"), json!(7), json!(null)] {
+            let (status, body) = respond(&replay, block_number(id.clone()));
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["id"], id);
+            assert_eq!(body["result"], "0x10");
+        }
+    }
+
+    #[test]
+    fn serves_recorded_responses_in_order() {
+        let replay = replay(&[
+            record(
+                block_number(json!(1)),
+                200,
+                json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"}),
+            ),
+            record(
+                block_number(json!(2)),
+                500,
+                json!({"jsonrpc": "2.0", "id": 2, "result": "0x11"}),
+            ),
+        ]);
+        let results = (0..3)
+            .map(|id| respond(&replay, block_number(json!(id))))
+            .map(|(status, body)| (status.as_u16(), body["result"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![
+                (200, json!("0x10")),
+                (500, json!("0x11")),
+                (500, json!("0x11"))
+            ]
+        );
+    }
+
+    #[test]
+    fn replays_batches_call_by_call() {
+        let replay = replay(&[record(
+            json!([block_number(json!(1)), {"jsonrpc": "2.0", "id": 2, "method": "eth_chainId"}]),
+            200,
+            json!([
+                {"jsonrpc": "2.0", "id": 2, "result": "0x1"},
+                {"jsonrpc": "2.0", "id": 1, "result": "0x10"},
+            ]),
+        )]);
+        let (status, body) = respond(
+            &replay,
+            json!([
+                {"jsonrpc": "2.0", "id": "a", "method": "eth_chainId"},
+                {"jsonrpc": "2.0", "method": "eth_chainId"},
+                {"jsonrpc": "2.0", "id": "b", "method": "eth_gasPrice"},
+                block_number(json!("c")),
+            ]),
+        );
+        assert_eq!(status, StatusCode::OK);
+        let elements = body.as_array().unwrap();
+        // the notification gets no answer and the unmatched call an error of its own
+        assert_eq!(elements.len(), 3);
+        assert_eq!(
+            elements[0],
+            json!({"jsonrpc": "2.0", "id": "a", "result": "0x1"})
+        );
+        assert_eq!(elements[1]["id"], "b");
+        assert_eq!(elements[1]["error"]["code"], -32603);
+        assert_eq!(
+            elements[2],
+            json!({"jsonrpc": "2.0", "id": "c", "result": "0x10"})
+        );
+    }
+
+    #[test]
+    fn reports_unmatched_requests() {
+        let replay = replay(&[]);
+        let source_request = post();
+        let request = block_number(json!(1)).to_string();
+        match replay.respond(&source_request, &RpcBody::parse(&request)) {
+            Err(SnoopError::Replay(e)) => assert!(e.contains("eth_blockNumber")),
+            _ => panic!("expected a replay error"),
+        }
+        match replay.respond(&source_request, &RpcBody::parse("not json")) {
+            Err(SnoopError::Replay(e)) => assert!(e.contains("POST /")),
+            _ => panic!("expected a replay error"),
+        }
+    }
+
+    #[test]
+    fn answers_notifications_with_nothing() {
+        let replay = replay(&[]);
+        let source_request = post();
+        for request in [
+            json!({"jsonrpc": "2.0", "method": "eth_blockNumber"}),
+            json!([{"jsonrpc": "2.0", "method": "eth_blockNumber"}]),
+        ] {
+            let (response, response_json, _) = replay
+                .respond(&source_request, &RpcBody::parse(&request.to_string()))
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response_json, "null");
+        }
+    }
+
+    #[test]
+    fn loads_traffic_logs() {
+        let lines = [
+            record(block_number(json!(1)), 200, json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"})),
+            // dropped requests have no status and are skipped
+            json!({"http_method": "POST", "path": "/", "request": block_number(json!(2)), "status": null})
+                .to_string(),
+            String::new(),
+            json!({"http_method": "GET", "path": "/eth/v1/node/health", "request": "", "status": 206, "response": "syncing"})
+                .to_string(),
+        ];
+        let replay = replay(&lines);
+        let responses = replay.responses.lock().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(
+            responses[&call_key(&call(block_number(json!(9))))].recorded,
+            vec![(
+                StatusCode::OK,
+                json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"})
+            )]
+        );
+        assert_eq!(
+            responses[&http_key("GET", "/eth/v1/node/health")].recorded,
+            vec![(StatusCode::PARTIAL_CONTENT, json!("syncing"))]
+        );
+        assert!(Replay::parse("{not json").is_err());
+    }
+
+    #[test]
+    fn loads_har_files() {
+        let har = json!({
+            "log": {
+                "version": "1.2",
+                "entries": [
+                    {
+                        "request": {
+                            "method": "POST",
+                            "url": "http://localhost:8545/",
+                            "postData": {"mimeType": "application/json", "text": block_number(json!(1)).to_string()},
+                        },
+                        "response": {
+                            "status": 200,
+                            "content": {"mimeType": "application/json", "text": r#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#},
+                        },
+                    },
+                    {
+                        "request": {"method": "GET", "url": "http://localhost:5052/eth/v1/node/version?x=1"},
+                        "response": {"status": 200, "content": {"text": r#"{"data":{}}"#}},
+                    },
+                ],
+            },
+        });
+        let replay = Replay::parse(&har.to_string()).unwrap();
+        let (_, body) = respond(&replay, block_number(json!(5)));
+        assert_eq!(body, json!({"jsonrpc": "2.0", "id": 5, "result": "0x10"}));
+        let responses = replay.responses.lock().unwrap();
+        assert_eq!(
+            responses[&http_key("GET", "/eth/v1/node/version?x=1")].recorded,
+            vec![(StatusCode::OK, json!({"data": {}}))]
+        );
+        assert!(Replay::parse(r#"{"log": {}}"#).is_err());
+    }
+}
@@ -0,0 +1,563 @@
+use crate::chaos::{DelayRule, ErrorRule};
+use crate::config;
+use crate::filter::Filter;
+use crate::mutate::MutateRule;
+use crate::utils::{self, Pattern, SuppressType};
+use crate::{print_event, SnoopContext};
+use clap::{App, ArgMatches};
+use serde_json::Value;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Duration};
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Flag,
+    Number,
+    List,
+}
+
+// the options behind `Settings`, in the order a reload reports them
+const RELOADABLE: &[(&str, Kind)] = &[
+    ("suppress-method", Kind::List),
+    ("suppress-path", Kind::List),
+    ("suppress-ignore-query", Kind::Flag),
+    ("only-method", Kind::List),
+    ("only-path", Kind::List),
+    ("filter", Kind::List),
+    ("drop-request-rate", Kind::Number),
+    ("drop-response-rate", Kind::Number),
+    ("fix-geth-attach", Kind::Flag),
+    ("rpc-modules-override", Kind::List),
+    ("log-headers", Kind::Flag),
+    ("slow-threshold", Kind::Number),
+    ("delay-request", Kind::List),
+    ("delay-response", Kind::List),
+    ("inject-error", Kind::List),
+    ("mutate-response", Kind::List),
+];
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// The part of the configuration that can change while the proxy is running,
+// either through a reload or the admin api. Everything else is fixed at
+// startup.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    // checked in order, the first matching rule wins
+    pub suppress_method: Option<Vec<(Pattern, (i32, SuppressType))>>,
+    pub suppress_path: Option<Vec<(Pattern, (i32, SuppressType))>>,
+    pub suppress_ignore_query: bool,
+    pub only_method: Option<Vec<(Pattern, (i32, SuppressType))>>,
+    pub only_path: Option<Vec<(Pattern, (i32, SuppressType))>>,
+    pub filters: Option<Vec<Filter>>,
+    pub override_rpc: Option<Vec<String>>,
+    pub drop_request_rate: f32,
+    pub drop_response_rate: f32,
+    pub log_headers: bool,
+    pub slow_threshold: Option<Duration>,
+    pub delay_request: Option<Vec<DelayRule>>,
+    pub delay_response: Option<Vec<DelayRule>>,
+    pub inject_error: Option<Vec<ErrorRule>>,
+    pub mutate_response: Option<Vec<MutateRule>>,
+    // the option values the settings were made from, to describe changes
+    options: Vec<(&'static str, Value)>,
+}
+
+impl Settings {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let options = RELOADABLE
+            .iter()
+            .map(|(id, kind)| {
+                let mut values = matches
+                    .get_raw(id)
+                    .into_iter()
+                    .flatten()
+                    .map(|value| value.to_string_lossy().into_owned());
+                let value = match kind {
+                    Kind::Flag => Value::from(matches.is_present(id)),
+                    Kind::Number => values
+                        .next()
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .map(Value::from)
+                        .unwrap_or(Value::Null),
+                    Kind::List => values.map(Value::from).collect(),
+                };
+                (*id, value)
+            })
+            .collect();
+
+        Self {
+            suppress_method: matches
+                .get_many("suppress-method")
+                .map(|iter| iter.cloned().collect()),
+            suppress_path: matches
+                .get_many("suppress-path")
+                .map(|iter| iter.cloned().collect()),
+            suppress_ignore_query: matches.is_present("suppress-ignore-query"),
+            only_method: matches
+                .get_many("only-method")
+                .map(|iter| iter.cloned().collect()),
+            only_path: matches
+                .get_many("only-path")
+                .map(|iter| iter.cloned().collect()),
+            filters: matches
+                .get_many("filter")
+                .map(|iter| iter.cloned().collect()),
+            drop_request_rate: *matches.get_one::<u32>("drop-request-rate").unwrap() as f32 / 100.0,
+            drop_response_rate: *matches.get_one::<u32>("drop-response-rate").unwrap() as f32
+                / 100.0,
+            override_rpc: matches
+                .values_of("rpc-modules-override")
+                .map(|values| values.into_iter().map(|s| s.to_string()).collect())
+                .or(if matches.is_present("fix-geth-attach") {
+                    Some(
+                        vec!["eth", "net", "web3"]
+                            .into_iter()
+                            .map(Into::into)
+                            .collect(),
+                    )
+                } else {
+                    None
+                }),
+            log_headers: matches.is_present("log-headers"),
+            slow_threshold: matches
+                .get_one::<u64>("slow-threshold")
+                .map(|ms| Duration::from_millis(*ms)),
+            delay_request: matches
+                .get_many("delay-request")
+                .map(|iter| iter.cloned().collect()),
+            delay_response: matches
+                .get_many("delay-response")
+                .map(|iter| iter.cloned().collect()),
+            inject_error: matches
+                .get_many("inject-error")
+                .map(|iter| iter.cloned().collect()),
+            mutate_response: matches
+                .get_many("mutate-response")
+                .map(|iter| iter.cloned().collect()),
+            options,
+        }
+    }
+
+    fn option_mut(&mut self, id: &str) -> &mut Value {
+        let (_, value) = self
+            .options
+            .iter_mut()
+            .find(|(option, _)| *option == id)
+            .expect("reloadable option");
+        value
+    }
+
+    // The options as a json object, as reported by the admin api
+    pub fn to_json(&self) -> Value {
+        self.options
+            .iter()
+            .map(|(id, value)| (id.to_string(), value.clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    // Puts a rule in front of the other `kind` ("method" or "path") rules so
+    // it takes precedence over them
+    pub fn add_suppression(&mut self, kind: &str, rule: &str) -> Result<(), String> {
+        let parsed = utils::parse_suppress(rule)?;
+        let (rules, id) = match kind {
+            "method" => (&mut self.suppress_method, "suppress-method"),
+            "path" => (&mut self.suppress_path, "suppress-path"),
+            _ => return Err(format!("Unknown suppression kind '{}'", kind)),
+        };
+        rules.get_or_insert_with(Vec::new).insert(0, parsed);
+        if let Value::Array(values) = self.option_mut(id) {
+            values.insert(0, Value::from(rule));
+        }
+        Ok(())
+    }
+
+    // Removes the `kind` rules given as `rule`, either in full or by their
+    // pattern alone. Returns how many were removed.
+    pub fn remove_suppression(&mut self, kind: &str, rule: &str) -> Result<usize, String> {
+        let id = match kind {
+            "method" => "suppress-method",
+            "path" => "suppress-path",
+            _ => return Err(format!("Unknown suppression kind '{}'", kind)),
+        };
+        let texts = match self.option_mut(id) {
+            Value::Array(values) => std::mem::take(values),
+            _ => vec![],
+        };
+        let rules = match kind {
+            "method" => self.suppress_method.take(),
+            _ => self.suppress_path.take(),
+        }
+        .unwrap_or_default();
+
+        let mut removed = 0;
+        let (texts, rules): (Vec<_>, Vec<_>) = texts
+            .into_iter()
+            .zip(rules)
+            .filter(|(text, (pattern, _))| {
+                let matched = text.as_str() == Some(rule) || pattern.to_string() == rule;
+                removed += matched as usize;
+                !matched
+            })
+            .unzip();
+        *self.option_mut(id) = Value::from(texts);
+        let rules = if rules.is_empty() { None } else { Some(rules) };
+        match kind {
+            "method" => self.suppress_method = rules,
+            _ => self.suppress_path = rules,
+        }
+        Ok(removed)
+    }
+
+    // `rate` is in percent, like `--drop-request-rate`
+    pub fn set_drop_rate(&mut self, direction: &str, rate: u32) -> Result<(), String> {
+        let (drop_rate, id) = match direction {
+            "request" => (&mut self.drop_request_rate, "drop-request-rate"),
+            "response" => (&mut self.drop_response_rate, "drop-response-rate"),
+            _ => return Err(format!("Unknown direction '{}'", direction)),
+        };
+        if rate > 100 {
+            return Err(format!("Drop rate {} is not in [0..100]", rate));
+        }
+        *drop_rate = rate as f32 / 100.0;
+        *self.option_mut(id) = Value::from(rate);
+        Ok(())
+    }
+
+    // one `option: old -> new` line per option that differs
+    pub fn diff(&self, other: &Settings) -> Vec<String> {
+        self.differences(other)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    // `diff` along with the option each line is about
+    fn differences(&self, other: &Settings) -> Vec<(&'static str, String)> {
+        let show = |value: &Value| match value {
+            Value::Null | Value::Bool(false) => "(none)".to_string(),
+            Value::Array(values) if values.is_empty() => "(none)".to_string(),
+            Value::Array(values) => values
+                .iter()
+                .map(|value| value.as_str().unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(", "),
+            value => value.to_string(),
+        };
+        self.options
+            .iter()
+            .zip(other.options.iter())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((id, old), (_, new))| (*id, format!("  {}: {} -> {}", id, show(old), show(new))))
+            .collect()
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Parses the original command line again (re-reading the config file) and
+// swaps in the resulting settings. `loaded` holds the settings of the last
+// load, anything that differs from them was changed through the admin api and
+// is reset, which the message says.
+fn reload(app: &App, args: &[OsString], loaded: &mut Settings, context: &SnoopContext) {
+    let matches = config::with_config_args(app, args.to_vec()).and_then(|args| {
+        app.clone()
+            .try_get_matches_from(args)
+            .map_err(|e| e.to_string().trim().to_string())
+    });
+    let settings = match matches {
+        Ok(matches) => Settings::from_matches(&matches),
+        Err(e) => {
+            print_event(
+                &format!("Reload failed, keeping the current settings: {}", e),
+                context,
+            );
+            return;
+        }
+    };
+
+    print_event(
+        &reload_message(loaded, &context.settings(), &settings),
+        context,
+    );
+    *loaded = settings.clone();
+    context.replace_settings(settings);
+}
+
+// What a reload from `loaded` to `reloaded` changes, given the `current`
+// settings
+fn reload_message(loaded: &Settings, current: &Settings, reloaded: &Settings) -> String {
+    let changed_at_runtime = loaded
+        .differences(current)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    let (reset, changes): (Vec<_>, Vec<_>) = current
+        .differences(reloaded)
+        .into_iter()
+        .partition(|(id, _)| changed_at_runtime.contains(id));
+    let lines = |differences: Vec<(&str, String)>| {
+        differences
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let mut message = match changes.is_empty() {
+        true if reset.is_empty() => "Reloaded settings (no changes)".to_string(),
+        true => "Reloaded settings".to_string(),
+        false => format!("Reloaded settings\n{}", lines(changes)),
+    };
+    if !reset.is_empty() {
+        message.push_str(&format!(
+            "\nReset settings changed through the admin api\n{}",
+            lines(reset)
+        ));
+    }
+    message
+}
+
+// Reloads the settings on SIGHUP and, when running from a config file,
+// whenever that file changes
+pub async fn watch(
+    app: &App<'_>,
+    args: Vec<OsString>,
+    config: Option<PathBuf>,
+    context: SnoopContext,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            print_event(&format!("Unable to listen for SIGHUP: {}", e), &context);
+            return std::future::pending().await;
+        }
+    };
+    let mut last_modified = config.as_ref().and_then(modified);
+    let mut loaded = Settings::clone(&context.settings());
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = sleep(POLL_INTERVAL), if config.is_some() => {
+                let current = config.as_ref().and_then(modified);
+                // a missing file is most likely an editor replacing it
+                if current.is_none() || current == last_modified {
+                    continue;
+                }
+            }
+        }
+        last_modified = config.as_ref().and_then(modified);
+        reload(app, &args, &mut loaded, &context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn with_method_rules(rules: &[&str]) -> Settings {
+        let mut settings = Settings {
+            suppress_method: None,
+            suppress_path: None,
+            suppress_ignore_query: false,
+            only_method: None,
+            only_path: None,
+            filters: None,
+            override_rpc: None,
+            drop_request_rate: 0.0,
+            drop_response_rate: 0.0,
+            log_headers: false,
+            slow_threshold: None,
+            delay_request: None,
+            delay_response: None,
+            inject_error: None,
+            mutate_response: None,
+            options: RELOADABLE
+                .iter()
+                .map(|(id, kind)| {
+                    let value = match kind {
+                        Kind::Flag => Value::from(false),
+                        Kind::Number => Value::Null,
+                        Kind::List => Value::Array(vec![]),
+                    };
+                    (*id, value)
+                })
+                .collect(),
+        };
+        for rule in rules.iter().rev() {
+            settings.add_suppression("method", rule).unwrap();
+        }
+        settings
+    }
+
+    // The rule texts next to the patterns they were parsed into
+    fn method_rules(settings: &mut Settings) -> Vec<(String, String)> {
+        let texts = match settings.option_mut("suppress-method") {
+            Value::Array(values) => values.clone(),
+            _ => vec![],
+        };
+        texts
+            .into_iter()
+            .map(|text| text.as_str().unwrap().to_string())
+            .zip(
+                settings
+                    .suppress_method
+                    .iter()
+                    .flatten()
+                    .map(|(pattern, _)| pattern.to_string()),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn removes_suppressions_by_rule_or_pattern() {
+        let mut settings =
+            with_method_rules(&["eth_call:2:request", "re:^debug_:5", "eth_call", "net_*"]);
+        assert_eq!(
+            settings.remove_suppression("method", "eth_call:2:request"),
+            Ok(1)
+        );
+        assert_eq!(
+            method_rules(&mut settings),
+            [
+                ("re:^debug_:5".to_string(), "re:^debug_".to_string()),
+                ("eth_call".to_string(), "eth_call".to_string()),
+                ("net_*".to_string(), "net_*".to_string()),
+            ]
+        );
+
+        let mut settings =
+            with_method_rules(&["eth_call:2:request", "re:^debug_:5", "eth_call", "net_*"]);
+        assert_eq!(settings.remove_suppression("method", "eth_call"), Ok(2));
+        assert_eq!(settings.remove_suppression("method", "re:^debug_"), Ok(1));
+        assert_eq!(
+            method_rules(&mut settings),
+            [("net_*".to_string(), "net_*".to_string())]
+        );
+        assert_eq!(settings.remove_suppression("method", "net_*"), Ok(1));
+        assert!(settings.suppress_method.is_none());
+        assert_eq!(settings.to_json()["suppress-method"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn rejects_unknown_suppression_kinds() {
+        let mut settings = with_method_rules(&["eth_call"]);
+        assert!(settings.remove_suppression("header", "eth_call").is_err());
+        assert_eq!(settings.remove_suppression("path", "eth_call"), Ok(0));
+        assert_eq!(method_rules(&mut settings).len(), 1);
+    }
+
+    fn from_args(args: &[&str]) -> Settings {
+        let matches = crate::app()
+            .try_get_matches_from(
+                std::iter::once("json_rpc_snoop")
+                    .chain(args.iter().copied())
+                    .chain(["http://localhost:8545"]),
+            )
+            .unwrap();
+        Settings::from_matches(&matches)
+    }
+
+    #[test]
+    fn diffs_the_options_that_changed() {
+        let old = from_args(&[
+            "-s",
+            "eth_chainId",
+            "--drop-request-rate",
+            "5",
+            "--log-headers",
+        ]);
+        let new = from_args(&[
+            "-s",
+            "eth_chainId",
+            "-s",
+            "re:^debug_:2",
+            "--slow-threshold",
+            "500",
+            "--log-headers",
+        ]);
+        assert_eq!(
+            old.diff(&new),
+            [
+                "  suppress-method: eth_chainId -> eth_chainId, re:^debug_:2",
+                "  drop-request-rate: 5 -> 0",
+                "  slow-threshold: (none) -> 500",
+            ]
+        );
+        assert!(new.diff(&new).is_empty());
+        assert_eq!(
+            from_args(&[]).diff(&from_args(&["-f"])),
+            ["  fix-geth-attach: (none) -> true"]
+        );
+    }
+
+    #[test]
+    fn reports_runtime_changes_a_reload_resets() {
+        let loaded = from_args(&["-s", "eth_chainId"]);
+        let mut current = loaded.clone();
+        current.set_drop_rate("response", 50).unwrap();
+        let reloaded = from_args(&["-s", "eth_call"]);
+        assert_eq!(
+            reload_message(&loaded, &current, &reloaded),
+            "Reloaded settings\n  suppress-method: eth_chainId -> eth_call\n\
+             Reset settings changed through the admin api\n  drop-response-rate: 50 -> 0"
+        );
+        assert_eq!(
+            reload_message(&loaded, &current, &loaded),
+            "Reloaded settings\n\
+             Reset settings changed through the admin api\n  drop-response-rate: 50 -> 0"
+        );
+        assert_eq!(
+            reload_message(&loaded, &loaded, &loaded),
+            "Reloaded settings (no changes)"
+        );
+    }
+
+    #[test]
+    fn reloads_the_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("json_rpc_snoop_{}_reload.toml", std::process::id()));
+        std::fs::write(&path, "suppress-method = [\"eth_chainId\"]\n").unwrap();
+        let args = [
+            "json_rpc_snoop",
+            "--config",
+            path.to_str().unwrap(),
+            "http://localhost:8545",
+        ];
+        let app = crate::app();
+        let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+        let matches = app
+            .clone()
+            .try_get_matches_from(config::with_config_args(&app, args.clone()).unwrap())
+            .unwrap();
+        let context = crate::tests::context(&["-s", "eth_chainId", "http://localhost:8545"]);
+        let mut loaded = Settings::from_matches(&matches);
+        context.replace_settings(loaded.clone());
+
+        std::fs::write(
+            &path,
+            "suppress-method = [\"eth_call:3\"]\nlog-headers = true\n",
+        )
+        .unwrap();
+        reload(&app, &args, &mut loaded, &context);
+        let settings = context.settings();
+        assert!(settings.log_headers);
+        assert_eq!(
+            settings.to_json()["suppress-method"],
+            serde_json::json!(["eth_call:3"])
+        );
+        assert!(loaded.diff(&settings).is_empty());
+
+        // a file that doesn't parse leaves the settings alone
+        std::fs::write(&path, "suppress-method = [").unwrap();
+        reload(&app, &args, &mut loaded, &context);
+        assert!(Arc::ptr_eq(&settings, &context.settings()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use crate::exchange::{body_to_json, headers_to_json, Exchange};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::sync::mpsc;
+
+// Appends one JSON record per exchange to a file (newline-delimited JSON).
+// Records are written by a thread of their own so a slow disk never holds up
+// the requests being proxied.
+#[derive(Debug)]
+pub struct TrafficLog {
+    // `None` once the log is finished
+    sender: Mutex<Option<mpsc::UnboundedSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    // the last write the thread failed, reported by the next call to `write`
+    error: Arc<Mutex<Option<std::io::Error>>>,
+    // skip exchanges that are completely suppressed on screen
+    pub honour_suppression: bool,
+}
+
+impl TrafficLog {
+    pub fn open(path: &Path, honour_suppression: bool) -> Result<Self, std::io::Error> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        let error = Arc::new(Mutex::new(None));
+        let writer_error = error.clone();
+        let writer = std::thread::spawn(move || {
+            while let Some(line) = receiver.blocking_recv() {
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    *writer_error.lock().unwrap() = Some(e);
+                }
+            }
+        });
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            error,
+            honour_suppression,
+        })
+    }
+
+    pub fn write(&self, exchange: &Exchange) -> Result<(), std::io::Error> {
+        let method = match exchange.methods.as_slice() {
+            [] => serde_json::Value::Null,
+            [method] => serde_json::Value::from(method.as_str()),
+            methods => serde_json::Value::from(methods.to_vec()),
+        };
+        let record = serde_json::json!({
+            "timestamp": exchange.timestamp.to_rfc3339(),
+            "client": exchange.client,
+            "http_method": exchange.http_method,
+            "path": exchange.path,
+            "method": method,
+            "request": body_to_json(&exchange.request_json),
+            "request_headers": headers_to_json(&exchange.request_headers),
+            "status": exchange.status.map(|status| status.as_u16()),
+            "response": exchange.response_json.as_deref().map(body_to_json),
+            "response_headers": headers_to_json(&exchange.response_headers),
+            "upstream": exchange.upstream,
+            "latency_ms": exchange.latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            "ttfb_ms": exchange.ttfb.map(|ttfb| ttfb.as_secs_f64() * 1000.0),
+            "request_dropped": exchange.request_dropped,
+            "response_dropped": exchange.response_dropped,
+            "injected": exchange.injected,
+            "mutation": exchange.mutation,
+        });
+
+        let mut line = record.to_string();
+        line.push('\n');
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send(line);
+        }
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Waits for the records still queued to be written
+    pub fn finish(&self) {
+        self.sender.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{PacketType, RpcBody};
+    use hyper::http::header::{HeaderName, HeaderValue};
+
+    fn records(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn writes_a_record_per_exchange() {
+        let path =
+            std::env::temp_dir().join(format!("json_rpc_snoop_{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let traffic_log = TrafficLog::open(&path, false).unwrap();
+        let mut exchange = Exchange::answered(
+            r#"{"jsonrpc":"2.0","id":1,"method":"engine_getPayloadV1","params":["0x1"]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x2"}"#,
+        );
+        exchange.request_headers = vec![
+            (
+                HeaderName::from_static("content-type"),
+                HeaderValue::from_static("application/json"),
+            ),
+            (
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_static("Bearer secret.token"),
+            ),
+        ];
+        traffic_log.write(&exchange).unwrap();
+        let batch =
+            r#"[{"jsonrpc":"2.0","id":1,"method":"a"},{"jsonrpc":"2.0","id":2,"method":"b"}]"#;
+        let mut dropped = Exchange::answered(batch, "");
+        dropped.status = None;
+        dropped.response_json = None;
+        dropped.request_dropped = true;
+        traffic_log.write(&dropped).unwrap();
+        traffic_log.finish();
+
+        let records = records(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        let record = &records[0];
+        assert_eq!(record["http_method"], "POST");
+        assert_eq!(record["method"], "engine_getPayloadV1");
+        assert_eq!(record["request"]["params"], serde_json::json!(["0x1"]));
+        assert_eq!(record["response"]["result"], "0x2");
+        assert_eq!(record["status"], 200);
+        assert_eq!(record["latency_ms"], 12.0);
+        assert_eq!(
+            record["request_headers"]["content-type"],
+            "application/json"
+        );
+        assert_eq!(record["request_headers"]["authorization"], "[redacted]");
+        assert!(!records[0].to_string().contains("secret.token"));
+        let record = &records[1];
+        assert_eq!(record["method"], serde_json::json!(["a", "b"]));
+        assert_eq!(record["status"], serde_json::Value::Null);
+        assert_eq!(record["response"], serde_json::Value::Null);
+        assert_eq!(record["request_dropped"], true);
+    }
+
+    #[test]
+    fn leaves_out_suppressed_exchanges_when_asked_to() {
+        for honour_suppression in [false, true] {
+            let path = std::env::temp_dir().join(format!(
+                "json_rpc_snoop_{}_{}.ndjson",
+                std::process::id(),
+                honour_suppression
+            ));
+            let _ = std::fs::remove_file(&path);
+            let mut args = vec![
+                "--log-file",
+                path.to_str().unwrap(),
+                "-s",
+                "eth_chainId",
+                "-s",
+                "eth_call:2",
+            ];
+            if honour_suppression {
+                args.push("--log-file-suppress");
+            }
+            args.push("http://localhost:8545");
+            let context = crate::tests::context(&args);
+            for method in ["eth_chainId", "eth_call", "eth_blockNumber"] {
+                let request_json = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{}"}}"#, method);
+                let exchange =
+                    Exchange::answered(&request_json, r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#);
+                crate::record_exchange(
+                    &exchange,
+                    &RpcBody::parse(&request_json),
+                    PacketType::Request,
+                    PacketType::Response,
+                    &context,
+                );
+            }
+            context.inner.traffic_log.as_ref().unwrap().finish();
+
+            let methods = records(&path)
+                .iter()
+                .map(|record| record["method"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            std::fs::remove_file(&path).unwrap();
+            // only rules that hide an exchange completely leave it out
+            match honour_suppression {
+                false => assert_eq!(methods, ["eth_chainId", "eth_call", "eth_blockNumber"]),
+                true => assert_eq!(methods, ["eth_call", "eth_blockNumber"]),
+            }
+        }
+    }
+}
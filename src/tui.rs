@@ -0,0 +1,761 @@
+use crate::exchange::{Exchange, WEBSOCKET_METHOD};
+use crate::settings::Settings;
+use crate::utils::{PacketType, RpcBody};
+use crate::{is_filtered_out, is_fully_suppressed, SnoopContext};
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{clear, cursor, style};
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+// Oldest exchanges are forgotten beyond this so a long session can't exhaust
+// memory
+const MAX_ENTRIES: usize = 10_000;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Entry {
+    // stable across evictions, unlike positions in `entries`
+    seq: u64,
+    exchange: Exchange,
+    rpc_body: RpcBody,
+    request_type: PacketType,
+    response_type: PacketType,
+}
+
+enum View {
+    List,
+    Detail { scroll: usize },
+    Rules { selected: usize },
+}
+
+struct State {
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+    // seqs of the entries shown in the list. Only exchanges recorded since the
+    // last frame are checked, everything is checked again when the search,
+    // the suppression toggles or the settings change.
+    visible: VecDeque<u64>,
+    // entries from this seq on haven't been checked yet
+    checked_seq: u64,
+    // the settings `visible` was worked out with
+    visible_settings: Option<Arc<Settings>>,
+    // `None` follows the newest exchange
+    selected: Option<u64>,
+    list_offset: usize,
+    view: View,
+    paused: bool,
+    search: String,
+    // the search being typed, if any
+    search_input: Option<String>,
+    status: String,
+    dirty: bool,
+}
+
+// Keeps the exchanges shown by `--tui`. Nothing is printed to stdout while
+// the TUI is running; the exchanges are drawn from here instead.
+pub struct Tui {
+    state: Mutex<State>,
+    // suppression rules switched off from the rules view, kept apart from
+    // `state` since suppression is checked while `state` is locked
+    disabled_rules: Mutex<HashSet<String>>,
+}
+
+impl std::fmt::Debug for Tui {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tui").finish_non_exhaustive()
+    }
+}
+
+// The name a suppression rule is toggled by, e.g. `method eth_syncing`
+pub fn rule_name(kind: &str, key: &str) -> String {
+    format!("{} {}", kind, key)
+}
+
+fn summary(exchange: &Exchange) -> String {
+    let what = match exchange.methods.len() {
+        0 => exchange.http_method.clone(),
+        1 => exchange.methods[0].clone(),
+        n => format!("batch({}) {}", n, exchange.methods.join(", ")),
+    };
+    if exchange.path.eq("/") {
+        what
+    } else {
+        format!("{} {}", what, exchange.path)
+    }
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    line.chars().take(width).collect()
+}
+
+impl Tui {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                entries: VecDeque::new(),
+                next_seq: 0,
+                visible: VecDeque::new(),
+                checked_seq: 0,
+                visible_settings: None,
+                selected: None,
+                list_offset: 0,
+                view: View::List,
+                paused: false,
+                search: String::new(),
+                search_input: None,
+                status: String::new(),
+                dirty: true,
+            }),
+            disabled_rules: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn rule_enabled(&self, rule: &str) -> bool {
+        !self.disabled_rules.lock().unwrap().contains(rule)
+    }
+
+    pub fn record(
+        &self,
+        exchange: &Exchange,
+        rpc_body: &RpcBody,
+        request_type: PacketType,
+        response_type: PacketType,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            return;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push_back(Entry {
+            seq,
+            exchange: exchange.clone(),
+            rpc_body: rpc_body.clone(),
+            request_type,
+            response_type,
+        });
+        if state.entries.len() > MAX_ENTRIES {
+            state.entries.pop_front();
+        }
+        state.dirty = true;
+    }
+
+    pub fn set_status(&self, msg: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.status = msg.lines().next().unwrap_or_default().to_string();
+        state.dirty = true;
+    }
+}
+
+impl Default for Tui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn rules(context: &SnoopContext) -> Vec<String> {
+    let settings = context.settings();
+    let mut rules = vec![];
+    for (kind, suppress) in [
+        ("method", &settings.suppress_method),
+        ("path", &settings.suppress_path),
+    ] {
+        rules.extend(
+            suppress
+                .iter()
+                .flatten()
+                .map(|(pattern, _)| rule_name(kind, &pattern.to_string())),
+        );
+    }
+    rules
+}
+
+fn is_visible(entry: &Entry, search: &str, context: &SnoopContext) -> bool {
+    if is_fully_suppressed(
+        &entry.rpc_body,
+        &entry.exchange.path,
+        entry.request_type,
+        entry.response_type,
+        context,
+    ) || is_filtered_out(&entry.rpc_body, &entry.exchange, context)
+    {
+        return false;
+    }
+    if search.is_empty() {
+        return true;
+    }
+    let search = search.to_lowercase();
+    let exchange = &entry.exchange;
+    [
+        Some(summary(exchange)),
+        Some(exchange.request_json.clone()),
+        exchange.response_json.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .any(|text| text.to_lowercase().contains(&search))
+}
+
+// seqs are handed out one after the other and only the oldest entries are
+// evicted, so an entry's position follows from its seq
+fn entry(entries: &VecDeque<Entry>, seq: u64) -> &Entry {
+    let first = entries.front().map_or(seq, |entry| entry.seq);
+    &entries[(seq - first) as usize]
+}
+
+// Makes `visible` start over, e.g. after the search changed
+fn invalidate_visible(state: &mut State) {
+    state.visible.clear();
+    state.checked_seq = 0;
+}
+
+// Brings `state.visible` up to date with the exchanges recorded since the
+// last call
+fn refresh_visible(state: &mut State, context: &SnoopContext) {
+    let settings = context.settings();
+    if !matches!(&state.visible_settings, Some(visible_settings) if Arc::ptr_eq(visible_settings, &settings))
+    {
+        state.visible_settings = Some(settings);
+        invalidate_visible(state);
+    }
+    let first = state
+        .entries
+        .front()
+        .map_or(state.next_seq, |entry| entry.seq);
+    while state.visible.front().is_some_and(|seq| *seq < first) {
+        state.visible.pop_front();
+    }
+    for seq in state.checked_seq.max(first)..state.next_seq {
+        if is_visible(entry(&state.entries, seq), &state.search, context) {
+            state.visible.push_back(seq);
+        }
+    }
+    state.checked_seq = state.next_seq;
+}
+
+// Position of the selected exchange within `visible`
+fn selected_position(selected: Option<u64>, visible: &VecDeque<u64>) -> Option<usize> {
+    let last = visible.len().checked_sub(1)?;
+    match selected {
+        Some(selected) => visible
+            .iter()
+            .position(|seq| *seq >= selected)
+            .or(Some(last)),
+        None => Some(last),
+    }
+}
+
+// Whether a response is (or, for a batch, contains) a JSON-RPC error
+fn is_rpc_error(response_json: &str) -> bool {
+    match serde_json::from_str::<serde_json::Value>(response_json) {
+        Ok(serde_json::Value::Object(response)) => response.contains_key("error"),
+        Ok(serde_json::Value::Array(responses)) => responses
+            .iter()
+            .any(|response| response.get("error").is_some()),
+        _ => false,
+    }
+}
+
+fn list_row(entry: &Entry, context: &SnoopContext) -> (String, &'static str) {
+    let exchange = &entry.exchange;
+    let websocket = exchange.http_method == WEBSOCKET_METHOD;
+    let status = match (exchange.status, exchange.request_dropped) {
+        (_, true) => "dropped".to_string(),
+        (Some(status), _) => status.as_u16().to_string(),
+        (None, _) if websocket => "ws".to_string(),
+        (None, _) => "-".to_string(),
+    };
+    let latency = exchange
+        .latency
+        .map(|latency| format!("{}ms", latency.as_millis()))
+        .unwrap_or_default();
+    let is_error = exchange.request_dropped
+        || exchange.response_dropped
+        || exchange
+            .status
+            .map(|status| !status.is_success())
+            .unwrap_or(!websocket)
+        || exchange.response_json.as_deref().is_some_and(is_rpc_error);
+    let color = if is_error {
+        context.inner.colors.red
+    } else {
+        context.inner.colors.green
+    };
+    (
+        format!(
+            "{:<12} {:>7} {:>8}  {}",
+            exchange.timestamp.format("%T%.3f"),
+            status,
+            latency,
+            summary(exchange)
+        ),
+        color,
+    )
+}
+
+fn detail_lines(entry: &Entry) -> Vec<String> {
+    let exchange = &entry.exchange;
+    let mut lines = vec![format!(
+        "REQUEST {} from {} {}",
+        exchange.timestamp.format("%b %e %T%.3f %Y"),
+        exchange.client,
+        summary(exchange)
+    )];
+    lines.extend(exchange.request_json.lines().map(str::to_string));
+    lines.push(String::new());
+    let mut header = match (exchange.status, exchange.request_dropped) {
+        (_, true) => "REQUEST DROPPED".to_string(),
+        (Some(status), _) => format!("RESPONSE (status {})", status),
+        (None, _) if exchange.http_method == WEBSOCKET_METHOD => "RESPONSE (websocket)".to_string(),
+        (None, _) => "RESPONSE".to_string(),
+    };
+    if let Some(latency) = exchange.latency {
+        header.push_str(&format!(" {}ms", latency.as_millis()));
+    }
+    if let Some(ttfb) = exchange.ttfb {
+        header.push_str(&format!(" (ttfb {}ms)", ttfb.as_millis()));
+    }
+    if let Some(upstream) = &exchange.upstream {
+        header.push_str(&format!(" from {}", upstream));
+    }
+    if exchange.response_dropped {
+        header.push_str(" [dropped]");
+    }
+    if exchange.injected {
+        header.push_str(" [injected]");
+    }
+    if let Some(mutation) = &exchange.mutation {
+        header.push_str(&format!(" [mutated: {}]", mutation));
+    }
+    lines.push(header);
+    if let Some(response_json) = &exchange.response_json {
+        lines.extend(response_json.lines().map(str::to_string));
+    }
+    lines
+}
+
+fn render(state: &mut State, context: &SnoopContext) -> String {
+    let (width, height) = termion::terminal_size().unwrap_or((80, 24));
+    let (width, height) = (width as usize, (height as usize).max(4));
+    // title, column header and footer
+    let rows = height - 3;
+    let colors = &context.inner.colors;
+    refresh_visible(state, context);
+    let visible = &state.visible;
+    let position = selected_position(state.selected, visible);
+
+    let mut title = format!(
+        "json_rpc_snoop  {} exchanges, {} shown",
+        state.entries.len(),
+        visible.len()
+    );
+    if state.paused {
+        title.push_str("  [PAUSED]");
+    }
+    if !state.search.is_empty() {
+        title.push_str(&format!("  [search: {}]", state.search));
+    }
+
+    let mut lines = vec![];
+    let footer;
+    match &mut state.view {
+        View::List => {
+            lines.push(format!(
+                "{}{}{}",
+                style::Bold,
+                truncate(
+                    &format!(
+                        "{:<12} {:>7} {:>8}  {}",
+                        "TIME", "STATUS", "LATENCY", "METHOD"
+                    ),
+                    width
+                ),
+                style::Reset
+            ));
+            if let Some(position) = position {
+                if position < state.list_offset {
+                    state.list_offset = position;
+                } else if position >= state.list_offset + rows {
+                    state.list_offset = position + 1 - rows;
+                }
+            }
+            state.list_offset = state.list_offset.min(visible.len().saturating_sub(1));
+            for (row, seq) in visible
+                .iter()
+                .enumerate()
+                .skip(state.list_offset)
+                .take(rows)
+            {
+                let (line, color) = list_row(entry(&state.entries, *seq), context);
+                let line = truncate(&line, width);
+                if Some(row) == position {
+                    lines.push(format!("{}{}{}", style::Invert, line, style::Reset));
+                } else {
+                    lines.push(format!("{}{}{}", color, line, colors.reset));
+                }
+            }
+            footer = "↑↓ select  enter view  / search  s suppression  p pause  q quit";
+        }
+        View::Detail { scroll } => {
+            let detail = match position {
+                Some(position) => detail_lines(entry(&state.entries, visible[position])),
+                None => vec!["nothing selected".to_string()],
+            };
+            *scroll = (*scroll).min(detail.len().saturating_sub(rows));
+            lines.push(String::new());
+            lines.extend(
+                detail
+                    .iter()
+                    .skip(*scroll)
+                    .take(rows)
+                    .map(|line| truncate(line, width)),
+            );
+            footer = "↑↓ pgup pgdn scroll  ←→ previous/next  esc back";
+        }
+        View::Rules { selected } => {
+            let rules = rules(context);
+            *selected = (*selected).min(rules.len().saturating_sub(1));
+            lines.push(format!("{}suppression rules{}", style::Bold, style::Reset));
+            if rules.is_empty() {
+                lines.push("no --suppress-method or --suppress-path rules given".to_string());
+            }
+            for (i, rule) in rules.iter().enumerate().take(rows) {
+                let enabled = context
+                    .inner
+                    .tui
+                    .as_ref()
+                    .map(|tui| tui.rule_enabled(rule))
+                    .unwrap_or(true);
+                let line = truncate(
+                    &format!("[{}] {}", if enabled { "x" } else { " " }, rule),
+                    width,
+                );
+                if i == *selected {
+                    lines.push(format!("{}{}{}", style::Invert, line, style::Reset));
+                } else {
+                    lines.push(line);
+                }
+            }
+            footer = "↑↓ select  space toggle  esc back";
+        }
+    }
+
+    let footer = match &state.search_input {
+        Some(input) => format!("search: {}_", input),
+        None if !state.status.is_empty() => format!("{}  |  {}", footer, state.status),
+        None => footer.to_string(),
+    };
+
+    let mut screen = format!("{}{}", clear::All, cursor::Goto(1, 1));
+    screen.push_str(&format!(
+        "{}{}{}",
+        style::Invert,
+        truncate(&format!("{:<width$}", title, width = width), width),
+        style::Reset
+    ));
+    for (row, line) in lines.iter().enumerate() {
+        screen.push_str(&format!("{}{}", cursor::Goto(1, row as u16 + 2), line));
+    }
+    screen.push_str(&format!(
+        "{}{}",
+        cursor::Goto(1, height as u16),
+        truncate(&footer, width)
+    ));
+    screen
+}
+
+// Moves the selection by `delta` rows within the visible exchanges, following
+// the newest exchange again once it reaches the bottom
+fn move_selection(state: &mut State, delta: isize, context: &SnoopContext) {
+    refresh_visible(state, context);
+    let visible = &state.visible;
+    let position = match selected_position(state.selected, visible) {
+        Some(position) => position,
+        None => return,
+    };
+    let position = (position as isize + delta).clamp(0, visible.len() as isize - 1) as usize;
+    state.selected = if position + 1 == visible.len() {
+        None
+    } else {
+        Some(visible[position])
+    };
+}
+
+// Handles a key press, returning false once the user asked to quit
+fn handle_key(state: &mut State, key: Key, context: &SnoopContext) -> bool {
+    let page = termion::terminal_size()
+        .map(|(_, height)| height as isize - 3)
+        .unwrap_or(20)
+        .max(1);
+    state.dirty = true;
+
+    if let Some(input) = &mut state.search_input {
+        match key {
+            Key::Char('\n') => {
+                state.search = input.clone();
+                state.search_input = None;
+                state.selected = None;
+                invalidate_visible(state);
+            }
+            Key::Esc => state.search_input = None,
+            Key::Backspace => {
+                input.pop();
+            }
+            Key::Ctrl('c') => return false,
+            Key::Char(c) => input.push(c),
+            _ => {}
+        }
+        return true;
+    }
+
+    match (&mut state.view, key) {
+        (_, Key::Ctrl('c')) => return false,
+        (View::List, Key::Char('q')) => return false,
+        (View::List, Key::Up | Key::Char('k')) => move_selection(state, -1, context),
+        (View::List, Key::Down | Key::Char('j')) => move_selection(state, 1, context),
+        (View::List, Key::PageUp) => move_selection(state, -page, context),
+        (View::List, Key::PageDown) => move_selection(state, page, context),
+        (View::List, Key::Home | Key::Char('g')) => move_selection(state, isize::MIN / 2, context),
+        (View::List, Key::End | Key::Char('G')) => state.selected = None,
+        (View::List, Key::Char('\n')) => {
+            // pin the selection so new exchanges don't swap what's shown
+            move_selection(state, 0, context);
+            if state.selected.is_none() {
+                state.selected = state.visible.back().copied();
+            }
+            state.view = View::Detail { scroll: 0 };
+        }
+        (View::List, Key::Char('/')) => state.search_input = Some(state.search.clone()),
+        (View::List, Key::Char('s')) => state.view = View::Rules { selected: 0 },
+        (View::List, Key::Char('p')) => state.paused = !state.paused,
+        (View::Detail { scroll }, Key::Up | Key::Char('k')) => *scroll = scroll.saturating_sub(1),
+        (View::Detail { scroll }, Key::Down | Key::Char('j')) => *scroll += 1,
+        (View::Detail { scroll }, Key::PageUp) => *scroll = scroll.saturating_sub(page as usize),
+        (View::Detail { scroll }, Key::PageDown) => *scroll += page as usize,
+        (View::Detail { scroll }, Key::Home | Key::Char('g')) => *scroll = 0,
+        (View::Detail { scroll }, Key::End | Key::Char('G')) => *scroll = usize::MAX,
+        (View::Detail { .. }, Key::Left | Key::Char('h')) => {
+            move_selection(state, -1, context);
+            state.view = View::Detail { scroll: 0 };
+        }
+        (View::Detail { .. }, Key::Right | Key::Char('l')) => {
+            move_selection(state, 1, context);
+            if state.selected.is_none() {
+                state.selected = state.visible.back().copied();
+            }
+            state.view = View::Detail { scroll: 0 };
+        }
+        (View::Rules { selected }, Key::Up | Key::Char('k')) => {
+            *selected = selected.saturating_sub(1)
+        }
+        (View::Rules { selected }, Key::Down | Key::Char('j')) => *selected += 1,
+        (View::Rules { selected }, Key::Char(' ') | Key::Char('\n')) => {
+            if let (Some(rule), Some(tui)) = (rules(context).get(*selected), &context.inner.tui) {
+                let mut disabled_rules = tui.disabled_rules.lock().unwrap();
+                if !disabled_rules.remove(rule) {
+                    disabled_rules.insert(rule.clone());
+                }
+            }
+            invalidate_visible(state);
+        }
+        (View::Detail { .. } | View::Rules { .. }, Key::Esc | Key::Backspace | Key::Char('q')) => {
+            state.view = View::List
+        }
+        _ => state.dirty = false,
+    }
+    true
+}
+
+// Takes over the terminal until the user quits
+pub async fn run(context: SnoopContext) {
+    let tui = match &context.inner.tui {
+        Some(tui) => tui,
+        None => return,
+    };
+    let mut screen = match std::io::stdout().into_raw_mode() {
+        Ok(stdout) => AlternateScreen::from(stdout),
+        Err(e) => {
+            eprintln!("Unable to switch the terminal to raw mode: {}", e);
+            return;
+        }
+    };
+    let _ = write!(screen, "{}", cursor::Hide);
+
+    // termion only offers blocking reads
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for key in std::io::stdin().keys().flatten() {
+            if sender.send(key).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    let mut size = None;
+    loop {
+        tokio::select! {
+            key = receiver.recv() => {
+                let key = match key {
+                    Some(key) => key,
+                    None => break,
+                };
+                let mut state = tui.state.lock().unwrap();
+                if !handle_key(&mut state, key, &context) {
+                    break;
+                }
+            }
+            _ = interval.tick() => {}
+        }
+
+        let mut state = tui.state.lock().unwrap();
+        let new_size = termion::terminal_size().ok();
+        if state.dirty || new_size != size {
+            size = new_size;
+            state.dirty = false;
+            let frame = render(&mut state, &context);
+            drop(state);
+            let _ = screen.write_all(frame.as_bytes());
+            let _ = screen.flush();
+        }
+    }
+
+    let _ = write!(screen, "{}", cursor::Show);
+    let _ = screen.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(context: &SnoopContext, method: &str, response_json: &str) {
+        let request_json = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"{}","params":[]}}"#,
+            method
+        );
+        let exchange = Exchange::answered(&request_json, response_json);
+        context.inner.tui.as_ref().unwrap().record(
+            &exchange,
+            &RpcBody::parse(&request_json),
+            PacketType::Request,
+            PacketType::Response,
+        );
+    }
+
+    fn visible_methods(context: &SnoopContext) -> Vec<String> {
+        let mut state = context.inner.tui.as_ref().unwrap().state.lock().unwrap();
+        refresh_visible(&mut state, context);
+        state
+            .visible
+            .iter()
+            .map(|seq| entry(&state.entries, *seq).exchange.methods.join(","))
+            .collect()
+    }
+
+    const RESULT: &str = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+
+    #[test]
+    fn keeps_the_visible_exchanges_up_to_date() {
+        let context =
+            crate::tests::context(&["--tui", "-s", "eth_chainId", "http://localhost:8545"]);
+        record(&context, "eth_chainId", RESULT);
+        record(&context, "eth_blockNumber", RESULT);
+        assert_eq!(visible_methods(&context), ["eth_blockNumber"]);
+
+        // only exchanges recorded since are checked
+        record(&context, "eth_getBalance", RESULT);
+        record(&context, "eth_chainId", RESULT);
+        assert_eq!(
+            visible_methods(&context),
+            ["eth_blockNumber", "eth_getBalance"]
+        );
+
+        let tui = context.inner.tui.as_ref().unwrap();
+        {
+            let mut state = tui.state.lock().unwrap();
+            state.view = View::Rules { selected: 0 };
+            handle_key(&mut state, Key::Char(' '), &context);
+        }
+        assert!(!tui.rule_enabled(&rule_name("method", "eth_chainId")));
+        assert_eq!(
+            visible_methods(&context),
+            [
+                "eth_chainId",
+                "eth_blockNumber",
+                "eth_getBalance",
+                "eth_chainId"
+            ]
+        );
+
+        {
+            let mut state = tui.state.lock().unwrap();
+            state.view = View::List;
+            state.search_input = Some("balance".to_string());
+            handle_key(&mut state, Key::Char('\n'), &context);
+        }
+        assert_eq!(visible_methods(&context), ["eth_getBalance"]);
+    }
+
+    #[test]
+    fn starts_over_when_the_settings_change() {
+        let context = crate::tests::context(&["--tui", "http://localhost:8545"]);
+        record(&context, "eth_chainId", RESULT);
+        record(&context, "eth_blockNumber", RESULT);
+        assert_eq!(
+            visible_methods(&context),
+            ["eth_chainId", "eth_blockNumber"]
+        );
+
+        let suppress = crate::tests::context(&["-s", "eth_chainId", "http://localhost:8545"]);
+        context.replace_settings(Settings::clone(&suppress.settings()));
+        assert_eq!(visible_methods(&context), ["eth_blockNumber"]);
+    }
+
+    #[test]
+    fn forgets_evicted_exchanges() {
+        let context = crate::tests::context(&["--tui", "http://localhost:8545"]);
+        for _ in 0..MAX_ENTRIES {
+            record(&context, "eth_chainId", RESULT);
+        }
+        assert_eq!(visible_methods(&context).len(), MAX_ENTRIES);
+        record(&context, "eth_blockNumber", RESULT);
+        let methods = visible_methods(&context);
+        assert_eq!(methods.len(), MAX_ENTRIES);
+        assert_eq!(methods.last().unwrap(), "eth_blockNumber");
+
+        let state = context.inner.tui.as_ref().unwrap().state.lock().unwrap();
+        assert_eq!(state.visible.front(), Some(&1));
+    }
+
+    #[test]
+    fn finds_the_selected_position() {
+        let visible = VecDeque::from([2, 5, 7]);
+        assert_eq!(selected_position(None, &visible), Some(2));
+        assert_eq!(selected_position(Some(5), &visible), Some(1));
+        // an exchange no longer shown selects the next one that is
+        assert_eq!(selected_position(Some(3), &visible), Some(1));
+        assert_eq!(selected_position(Some(8), &visible), Some(2));
+        assert_eq!(selected_position(None, &VecDeque::new()), None);
+    }
+
+    #[test]
+    fn detects_errors_by_their_error_member() {
+        assert!(is_rpc_error(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"nope"}}"#
+        ));
+        assert!(is_rpc_error(
+            r#"[{"jsonrpc":"2.0","id":1,"result":"0x1"},{"jsonrpc":"2.0","id":2,"error":{"code":3}}]"#
+        ));
+        assert!(!is_rpc_error(RESULT));
+        // the word appearing in a result doesn't make it an error
+        assert!(!is_rpc_error(
+            r#"{"jsonrpc":"2.0","id":1,"result":{"logs":["\"error\""],"error":null}}"#
+        ));
+        assert!(!is_rpc_error("not json"));
+    }
+}
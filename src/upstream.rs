@@ -0,0 +1,322 @@
+use crate::utils::{Endpoint, Pattern};
+use hyper::Uri;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Latency recorded for a failed attempt so least-latency steers away from it
+const FAILURE_PENALTY: Duration = Duration::from_secs(5);
+// How long a failed upstream is only tried after the healthy ones
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastLatency,
+    Fallback,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s.to_lowercase().as_str() {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-latency" => Ok(Strategy::LeastLatency),
+            "fallback" => Ok(Strategy::Fallback),
+            _ => Err(format!(
+                "Unable to parse '{}' as [round-robin|least-latency|fallback]",
+                s
+            )),
+        }
+    }
+}
+
+// How long an upstream took to answer a request, carried in the response's
+// extensions so it survives failover and batch splitting
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    // until the response headers (or the first bytes over ipc) arrived
+    pub ttfb: Duration,
+    // until the whole response body was read
+    pub total: Duration,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    // http(s) uri that forwarded requests are built against
+    pub uri: Uri,
+    pub ws_uri: Option<Uri>,
+    pub ipc_path: Option<PathBuf>,
+    // exponentially weighted moving average, `None` until first used
+    latency: Mutex<Option<Duration>>,
+    // when the last attempt failed, `None` once an attempt succeeds again
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    pub fn new(endpoint: &Endpoint) -> Self {
+        let (uri, ws_uri, ipc_path) = match endpoint {
+            // in websocket mode plain http requests still go to the same host
+            Endpoint::Uri(uri) if crate::websocket::is_websocket_uri(uri) => {
+                (crate::websocket::to_http_uri(uri), Some(uri.clone()), None)
+            }
+            Endpoint::Uri(uri) => (uri.clone(), None, None),
+            // requests are written straight to the socket so the uri only ends
+            // up in the forwarded host header
+            Endpoint::Ipc(path) => (
+                Uri::from_static("http://localhost"),
+                None,
+                Some(path.clone()),
+            ),
+        };
+        Self {
+            uri,
+            ws_uri,
+            ipc_path,
+            latency: Mutex::new(None),
+            failed_at: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        let uri = match (&self.ws_uri, &self.ipc_path) {
+            (_, Some(ipc_path)) => return ipc_path.display().to_string(),
+            (Some(ws_uri), _) => ws_uri.to_string(),
+            _ => self.uri.to_string(),
+        };
+        crate::utils::remove_trailing_slashes(&uri).to_string()
+    }
+
+    fn latency(&self) -> Duration {
+        self.latency.lock().unwrap().unwrap_or(Duration::ZERO)
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let mut average = self.latency.lock().unwrap();
+        *average = Some(match *average {
+            Some(average) => average.mul_f64(0.8) + latency.mul_f64(0.2),
+            None => latency,
+        });
+    }
+
+    pub fn record_success(&self, latency: Duration) {
+        self.record_latency(latency);
+        *self.failed_at.lock().unwrap() = None;
+    }
+
+    pub fn record_failure(&self) {
+        self.record_latency(FAILURE_PENALTY);
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    // false for a while after an attempt failed
+    fn is_healthy(&self) -> bool {
+        match *self.failed_at.lock().unwrap() {
+            Some(failed_at) => failed_at.elapsed() >= RETRY_AFTER,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    pub fn new(endpoints: &[Endpoint], strategy: Strategy) -> Self {
+        Self {
+            upstreams: endpoints.iter().map(Upstream::new).collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        self.upstreams
+            .iter()
+            .any(|upstream| upstream.ws_uri.is_some())
+    }
+
+    // The order in which upstreams should be tried for the next request; every
+    // upstream after the first is a failover candidate. Upstreams that failed
+    // recently go last, so requests don't keep waiting on a dead one but it
+    // is still tried when nothing else is left.
+    pub fn candidates(&self) -> Vec<&Upstream> {
+        let mut candidates = self.upstreams.iter().collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return candidates;
+        }
+        match self.strategy {
+            Strategy::Fallback => {}
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            Strategy::LeastLatency => {
+                candidates.sort_by_key(|upstream| upstream.latency());
+            }
+        }
+        candidates.sort_by_key(|upstream| !upstream.is_healthy());
+        candidates
+    }
+}
+
+// Sends calls whose method matches `pattern` to a dedicated set of upstreams
+#[derive(Debug)]
+pub struct Route {
+    pub pattern: Pattern,
+    pub upstreams: Upstreams,
+}
+
+#[derive(Debug)]
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    // Repeating a pattern adds failover endpoints to the same route
+    pub fn new(rules: &[(Pattern, Endpoint)], strategy: Strategy) -> Self {
+        let mut patterns = Vec::<(Pattern, Vec<Endpoint>)>::new();
+        for (pattern, endpoint) in rules {
+            match patterns
+                .iter_mut()
+                .find(|(p, _)| p.to_string() == pattern.to_string())
+            {
+                Some((_, endpoints)) => endpoints.push(endpoint.clone()),
+                None => patterns.push((pattern.clone(), vec![endpoint.clone()])),
+            }
+        }
+        Self {
+            routes: patterns
+                .into_iter()
+                .map(|(pattern, endpoints)| Route {
+                    pattern,
+                    upstreams: Upstreams::new(&endpoints, strategy),
+                })
+                .collect(),
+        }
+    }
+
+    // Index of the first route matching `method`, `None` means the default
+    // upstreams
+    pub fn find(&self, method: &str) -> Option<usize> {
+        self.routes
+            .iter()
+            .position(|route| route.pattern.matches(method))
+    }
+
+    pub fn upstreams<'a>(&'a self, route: Option<usize>, default: &'a Upstreams) -> &'a Upstreams {
+        route
+            .and_then(|i| self.routes.get(i))
+            .map(|route| &route.upstreams)
+            .unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(strategy: Strategy) -> Upstreams {
+        let endpoints = ["http://a", "http://b", "http://c"]
+            .map(|uri| crate::utils::parse_endpoint(uri).unwrap());
+        Upstreams::new(&endpoints, strategy)
+    }
+
+    fn names(candidates: Vec<&Upstream>) -> Vec<String> {
+        candidates.iter().map(|upstream| upstream.name()).collect()
+    }
+
+    #[test]
+    fn parses_strategies() {
+        assert_eq!(Strategy::from_str("Round-Robin"), Ok(Strategy::RoundRobin));
+        assert!(Strategy::from_str("random").is_err());
+    }
+
+    #[test]
+    fn fallback_skips_failed_upstreams() {
+        let upstreams = upstreams(Strategy::Fallback);
+        assert_eq!(
+            names(upstreams.candidates()),
+            ["http://a", "http://b", "http://c"]
+        );
+        upstreams.upstreams[0].record_failure();
+        assert_eq!(
+            names(upstreams.candidates()),
+            ["http://b", "http://c", "http://a"]
+        );
+        upstreams.upstreams[0].record_success(Duration::from_millis(1));
+        assert_eq!(names(upstreams.candidates())[0], "http://a");
+    }
+
+    fn routes(args: &[&str]) -> Routes {
+        let rules = args
+            .iter()
+            .map(|arg| crate::utils::parse_route(arg).unwrap())
+            .collect::<Vec<_>>();
+        Routes::new(&rules, Strategy::Fallback)
+    }
+
+    #[test]
+    fn finds_the_first_matching_route() {
+        let routes = routes(&[
+            "engine_*=http://engine",
+            "re:^(debug|trace)_=http://archive",
+            "engine_getPayload?1=http://never",
+        ]);
+        assert_eq!(routes.find("engine_newPayloadV2"), Some(0));
+        assert_eq!(routes.find("engine_getPayloadV1"), Some(0));
+        assert_eq!(routes.find("debug_traceTransaction"), Some(1));
+        assert_eq!(routes.find("trace_block"), Some(1));
+        assert_eq!(routes.find("eth_debug_x"), None);
+        assert_eq!(routes.find("eth_call"), None);
+    }
+
+    #[test]
+    fn repeated_patterns_add_failover_endpoints() {
+        let routes = routes(&[
+            "engine_*=http://a",
+            "re:^debug_=http://b",
+            "engine_*=http://c",
+        ]);
+        assert_eq!(routes.routes.len(), 2);
+        assert_eq!(
+            names(routes.routes[0].upstreams.candidates()),
+            ["http://a", "http://c"]
+        );
+        let default = upstreams(Strategy::Fallback);
+        assert_eq!(
+            names(routes.upstreams(None, &default).candidates()).len(),
+            3
+        );
+        assert_eq!(
+            names(routes.upstreams(Some(1), &default).candidates()),
+            ["http://b"]
+        );
+    }
+
+    #[test]
+    fn has_no_candidates_without_upstreams() {
+        for strategy in [
+            Strategy::Fallback,
+            Strategy::RoundRobin,
+            Strategy::LeastLatency,
+        ] {
+            assert!(Upstreams::new(&[], strategy).candidates().is_empty());
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let upstreams = upstreams(Strategy::RoundRobin);
+        let first = names(upstreams.candidates());
+        let second = names(upstreams.candidates());
+        assert_eq!(first[1], second[0]);
+    }
+}
@@ -3,6 +3,8 @@ use hyper::{
     Error as HyperError, Uri,
 };
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::{FromStr, Utf8Error};
 
 #[derive(Debug)]
@@ -10,6 +12,8 @@ pub enum SnoopError {
     HyperError(HyperError),
     HyperHttpError(HyperHttpError),
     StringConversion(Utf8Error),
+    Io(std::io::Error),
+    Replay(String),
 }
 
 impl From<HyperHttpError> for SnoopError {
@@ -24,19 +28,139 @@ impl From<HyperError> for SnoopError {
     }
 }
 
+impl From<std::io::Error> for SnoopError {
+    fn from(e: std::io::Error) -> Self {
+        SnoopError::Io(e)
+    }
+}
+
 impl From<Utf8Error> for SnoopError {
     fn from(e: Utf8Error) -> Self {
         SnoopError::StringConversion(e)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// A JSON-RPC id as allowed by the spec: `String | Number | Null`. Numbers keep
+// their original textual representation so large ids round-trip unchanged.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "serde_json::Value", into = "serde_json::Value")]
+pub enum RpcId {
+    Number(serde_json::Number),
+    String(String),
+    Null,
+}
+
+impl TryFrom<serde_json::Value> for RpcId {
+    type Error = String;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, String> {
+        match value {
+            serde_json::Value::Number(n) => Ok(RpcId::Number(n)),
+            serde_json::Value::String(s) => Ok(RpcId::String(s)),
+            serde_json::Value::Null => Ok(RpcId::Null),
+            other => Err(format!("invalid JSON-RPC id: {}", other)),
+        }
+    }
+}
+
+impl From<RpcId> for serde_json::Value {
+    fn from(id: RpcId) -> Self {
+        match id {
+            RpcId::Number(n) => serde_json::Value::Number(n),
+            RpcId::String(s) => serde_json::Value::String(s),
+            RpcId::Null => serde_json::Value::Null,
+        }
+    }
+}
+
+// Distinguishes a missing field (`None`) from an explicit `null` (`Some(Null)`)
+fn deserialize_present<'de, D>(deserializer: D) -> Result<Option<RpcId>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    RpcId::deserialize(deserializer).map(Some)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RpcRequest {
-    pub id: u64,
+    // `None` when the id is absent, which makes the request a notification
+    #[serde(
+        default,
+        deserialize_with = "deserialize_present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<RpcId>,
     pub jsonrpc: String,
     pub method: String,
-    pub params: Option<Vec<serde_json::Value>>,
+    // positional (array) or by-name (object) parameters
+    pub params: Option<serde_json::Value>,
 }
+
+impl RpcRequest {
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+}
+// A JSON-RPC request body, which is either a single call or a batch of calls.
+// Batch elements keep their raw JSON alongside the parsed call (if any) so
+// malformed elements can still be logged and forwarded untouched.
+#[derive(Clone, Debug)]
+pub enum RpcBody {
+    Single(Option<RpcRequest>),
+    Batch(Vec<(serde_json::Value, Option<RpcRequest>)>),
+}
+
+impl RpcBody {
+    pub fn parse(json: &str) -> Self {
+        match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(serde_json::Value::Array(elements)) => RpcBody::Batch(
+                elements
+                    .into_iter()
+                    .map(|element| {
+                        let call = serde_json::from_value::<RpcRequest>(element.clone()).ok();
+                        (element, call)
+                    })
+                    .collect(),
+            ),
+            Ok(value) => RpcBody::Single(serde_json::from_value::<RpcRequest>(value).ok()),
+            Err(_) => RpcBody::Single(None),
+        }
+    }
+
+    pub fn methods(&self) -> Vec<String> {
+        match self {
+            RpcBody::Single(call) => call.iter().map(|call| call.method.clone()).collect(),
+            RpcBody::Batch(calls) => calls
+                .iter()
+                .filter_map(|(_, call)| call.as_ref().map(|call| call.method.clone()))
+                .collect(),
+        }
+    }
+
+    // Notifications get no reply, so a body made up only of notifications
+    // must not wait for one from stream based upstreams.
+    pub fn expects_response(&self) -> bool {
+        match self {
+            RpcBody::Single(Some(call)) => !call.is_notification(),
+            RpcBody::Single(None) => true,
+            RpcBody::Batch(calls) => calls.iter().any(|(_, call)| {
+                call.as_ref()
+                    .map(|call| !call.is_notification())
+                    .unwrap_or(true)
+            }),
+        }
+    }
+
+    // The id to echo in a proxy-generated error for this body. Batches and
+    // unparseable bodies get `null` as the spec requires.
+    pub fn id(&self) -> RpcId {
+        match self {
+            RpcBody::Single(Some(call)) => call.id.clone().unwrap_or(RpcId::Null),
+            _ => RpcId::Null,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RpcError {
     pub code: i32,
@@ -44,16 +168,16 @@ pub struct RpcError {
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RpcErrorResponse {
-    pub id: u64,
+    pub id: RpcId,
     pub jsonrpc: String,
     pub error: RpcError,
 }
 
-impl From<(&str, SnoopError)> for RpcErrorResponse {
-    fn from(pair: (&str, SnoopError)) -> Self {
-        let (prefix, snoop_error) = pair;
+impl From<(&str, SnoopError, RpcId)> for RpcErrorResponse {
+    fn from(triple: (&str, SnoopError, RpcId)) -> Self {
+        let (prefix, snoop_error, id) = triple;
         Self {
-            id: 1,
+            id,
             jsonrpc: "2.0".to_string(),
             error: RpcError {
                 code: -32603,
@@ -67,6 +191,10 @@ impl From<(&str, SnoopError)> for RpcErrorResponse {
                     SnoopError::StringConversion(e) => {
                         format!("{}: error converting to Utf-8: {:?}", prefix, e)
                     }
+                    SnoopError::Io(e) => {
+                        format!("{}: encountered io error: {:?}", prefix, e)
+                    }
+                    SnoopError::Replay(e) => format!("{}: {}", prefix, e),
                 },
             },
         }
@@ -88,17 +216,50 @@ pub enum SuppressType {
     All,
 }
 
-impl ToString for PacketType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for PacketType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PacketType::Request => "REQUEST".to_string(),
-            PacketType::Response => "RESPONSE".to_string(),
-            PacketType::RequestDropped(_wait) => {
-                format!("DROPPED REQUEST")
-            }
-            PacketType::ResponseDropped(_wait) => {
-                format!("DROPPED RESPONSE")
-            }
+            PacketType::Request => write!(f, "REQUEST"),
+            PacketType::Response => write!(f, "RESPONSE"),
+            PacketType::RequestDropped(_wait) => write!(f, "DROPPED REQUEST"),
+            PacketType::ResponseDropped(_wait) => write!(f, "DROPPED RESPONSE"),
+        }
+    }
+}
+
+// What a suppression rule matches against: a glob where `*` and `?` are
+// wildcards (so plain text matches exactly), or a regex when given with the
+// `re:` prefix
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Glob(String),
+    Regex(regex::Regex),
+}
+
+impl Pattern {
+    pub fn matches(&self, s: &str) -> bool {
+        match self {
+            Pattern::Glob(pattern) => glob_match(pattern, s),
+            Pattern::Regex(regex) => regex.is_match(s),
+        }
+    }
+}
+
+// a regex when prefixed with `re:`, a glob otherwise
+pub fn parse_pattern(s: &str) -> Result<Pattern, String> {
+    match s.strip_prefix("re:") {
+        Some(regex) => regex::Regex::new(regex)
+            .map(Pattern::Regex)
+            .map_err(|e| format!("Unable to parse '{}' as a regex: {}", regex, e)),
+        None => Ok(Pattern::Glob(s.to_string())),
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Pattern::Glob(pattern) => write!(f, "{}", pattern),
+            Pattern::Regex(regex) => write!(f, "re:{}", regex),
         }
     }
 }
@@ -129,7 +290,7 @@ impl PacketType {
 // remove trailing slashes from a str
 pub fn remove_trailing_slashes(s: &str) -> &str {
     match s.char_indices().next_back() {
-        Some((i, chr)) if chr == '/' => remove_trailing_slashes(&s[..i]),
+        Some((i, '/')) => remove_trailing_slashes(&s[..i]),
         _ => s,
     }
 }
@@ -138,7 +299,7 @@ pub fn trim_json(json: &str, limit: i32) -> String {
     let limit = limit as usize;
     let mut result = String::new();
     if limit > 0 {
-        let lines = json.split("\n").collect::<Vec<_>>();
+        let lines = json.split('\n').collect::<Vec<_>>();
         let len = lines.len();
         if len <= limit {
             result = json.to_string();
@@ -147,7 +308,7 @@ pub fn trim_json(json: &str, limit: i32) -> String {
             for (i, line) in lines.into_iter().enumerate() {
                 if i < limit / 2 {
                     result.push_str(line);
-                    result.push_str("\n");
+                    result.push('\n');
                 } else if len - i > limit / 2 {
                     dots = true;
                 } else {
@@ -156,7 +317,7 @@ pub fn trim_json(json: &str, limit: i32) -> String {
                         dots = false;
                     }
                     result.push_str(line);
-                    result.push_str("\n");
+                    result.push('\n');
                 }
             }
         }
@@ -168,19 +329,134 @@ pub fn parse_uri(s: &str) -> Result<Uri, InvalidUri> {
     remove_trailing_slashes(s).parse::<Uri>()
 }
 
-pub fn parse_suppress(arg: &str) -> Result<(String, (i32, SuppressType)), String> {
+// Where an incoming request came from
+#[derive(Clone, Debug)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    Ipc,
+}
+
+impl std::fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddr::Tcp(address) => write!(f, "{}", address),
+            ClientAddr::Ipc => write!(f, "ipc"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    Uri(Uri),
+    Ipc(PathBuf),
+}
+
+// Accepts a uri, an `ipc://` uri or a bare filesystem path to an ipc socket
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, String> {
+    if let Some(path) = s.strip_prefix("ipc://") {
+        Ok(Endpoint::Ipc(PathBuf::from(path)))
+    } else if s.starts_with('/') || s.starts_with('.') || s.ends_with(".ipc") {
+        Ok(Endpoint::Ipc(PathBuf::from(s)))
+    } else {
+        parse_uri(s)
+            .map(Endpoint::Uri)
+            .map_err(|e| format!("Unable to parse '{}' as uri: {}", s, e))
+    }
+}
+
+// Matches `s` against a glob `pattern` where `*` matches any run of characters
+// and `?` matches exactly one character
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // position of the last `*` seen and the input position it was tried at
+    let mut backtrack = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    i = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+pub fn parse_route(arg: &str) -> Result<(Pattern, Endpoint), String> {
+    match arg.split_once('=') {
+        Some((pattern, endpoint)) if !pattern.is_empty() => {
+            Ok((parse_pattern(pattern)?, parse_endpoint(endpoint)?))
+        }
+        _ => Err(format!(
+            "Unable to parse argument '{}' as 'METHOD_PATTERN=RPC_ENDPOINT'",
+            arg
+        )),
+    }
+}
+
+pub fn parse_file_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("Unable to parse '{}' as an octal file mode", s))
+}
+
+fn parse_lines(s: &str) -> Result<i32, String> {
+    if s.is_empty() {
+        Ok(-1)
+    } else {
+        s.parse()
+            .map_err(|e| format!("Unable to parse '{}' as LINES: {}", s, e))
+    }
+}
+
+pub fn parse_suppress(arg: &str) -> Result<(Pattern, (i32, SuppressType)), String> {
+    // a regex may contain colons itself, so LINES and TYPE are only split off
+    // the end when they parse as such
+    if let Some(rest) = arg.strip_prefix("re:") {
+        let parts = rest.rsplitn(3, ':').collect::<Vec<_>>();
+        let (regex, lines, suppress_type) = match parts.as_slice() {
+            [suppress_type, lines, regex]
+                if SuppressType::from_str(suppress_type).is_ok() && parse_lines(lines).is_ok() =>
+            {
+                (
+                    *regex,
+                    parse_lines(lines)?,
+                    SuppressType::from_str(suppress_type)?,
+                )
+            }
+            [lines, ..] if parse_lines(lines).is_ok() && parts.len() > 1 => (
+                &rest[..rest.len() - lines.len() - 1],
+                parse_lines(lines)?,
+                SuppressType::All,
+            ),
+            _ => (rest, -1, SuppressType::All),
+        };
+        let regex = regex::Regex::new(regex)
+            .map_err(|e| format!("Unable to parse '{}' as a regex: {}", regex, e))?;
+        return Ok((Pattern::Regex(regex), (lines, suppress_type)));
+    }
+
     let mut suppress = arg.to_string();
     let mut lines = -1;
     let mut suppress_type = SuppressType::All;
-    for (i, s) in arg.split(":").enumerate() {
+    for (i, s) in arg.split(':').enumerate() {
         match i {
             0 => suppress = s.to_string(),
-            1 if !s.is_empty() => {
-                lines = s
-                    .parse()
-                    .map_err(|e| format!("Unable to parse '{}' as LINES: {}", s, e))?
-            }
-            1 => {}
+            1 => lines = parse_lines(s)?,
             2 => suppress_type = SuppressType::from_str(s)?,
             i if i > 2 => {
                 return Err(format!(
@@ -192,5 +468,291 @@ pub fn parse_suppress(arg: &str) -> Result<(String, (i32, SuppressType)), String
         }
     }
 
-    Ok((suppress, (lines, suppress_type)))
+    Ok((Pattern::Glob(suppress), (lines, suppress_type)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(json: &str) -> RpcRequest {
+        match RpcBody::parse(json) {
+            RpcBody::Single(Some(call)) => call,
+            body => panic!("expected a single call, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn parses_by_name_params() {
+        let call = single(r#"{"jsonrpc":"2.0","id":1,"method":"m","params":{"a":1}}"#);
+        assert_eq!(call.method, "m");
+        assert_eq!(call.params, Some(serde_json::json!({"a": 1})));
+    }
+
+    #[test]
+    fn parses_ids() {
+        let id = |json: &str| single(json).id;
+        assert_eq!(
+            id(r#"{"jsonrpc":"2.0","id":"a","method":"m"}"#),
+            Some(RpcId::String("a".to_string()))
+        );
+        assert_eq!(
+            id(r#"{"jsonrpc":"2.0","id":null,"method":"m"}"#),
+            Some(RpcId::Null)
+        );
+        assert_eq!(id(r#"{"jsonrpc":"2.0","method":"m"}"#), None);
+        // large ids keep their exact digits
+        let big = r#"{"jsonrpc":"2.0","id":123456789012345678901234567890,"method":"m"}"#;
+        assert_eq!(
+            serde_json::to_string(&single(big).id).unwrap(),
+            "123456789012345678901234567890"
+        );
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        let ipc = |s: &str| match parse_endpoint(s) {
+            Ok(Endpoint::Ipc(path)) => path,
+            other => panic!("expected an ipc endpoint for {}, got {:?}", s, other),
+        };
+        assert_eq!(ipc("ipc:///tmp/geth.ipc"), PathBuf::from("/tmp/geth.ipc"));
+        assert_eq!(
+            ipc("/var/run/node.sock"),
+            PathBuf::from("/var/run/node.sock")
+        );
+        assert_eq!(ipc("./geth.ipc"), PathBuf::from("./geth.ipc"));
+        assert_eq!(ipc("geth.ipc"), PathBuf::from("geth.ipc"));
+
+        for uri in [
+            "http://localhost:8545",
+            "https://node.example/key",
+            "ws://127.0.0.1:8546",
+        ] {
+            match parse_endpoint(uri) {
+                Ok(Endpoint::Uri(parsed)) => {
+                    assert_eq!(parsed.to_string().trim_end_matches('/'), uri)
+                }
+                other => panic!("expected a uri for {}, got {:?}", uri, other),
+            }
+        }
+        assert!(parse_endpoint("http://local host").is_err());
+    }
+
+    #[test]
+    fn parses_routes() {
+        let (pattern, endpoint) = parse_route("engine_*=http://localhost:8551").unwrap();
+        assert!(matches!(pattern, Pattern::Glob(glob) if glob == "engine_*"));
+        assert!(matches!(endpoint, Endpoint::Uri(uri) if uri.port_u16() == Some(8551)));
+        let (pattern, _) = parse_route("re:^(debug|trace)_=/tmp/archive.ipc").unwrap();
+        assert!(pattern.matches("trace_block"));
+        assert!(!pattern.matches("eth_call"));
+        assert!(parse_route("=http://localhost:8551").is_err());
+        assert!(parse_route("engine_*").is_err());
+        assert!(parse_route("re:(=http://localhost:8551").is_err());
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("eth_call", "eth_call"));
+        assert!(!glob_match("eth_call", "eth_callMany"));
+        assert!(glob_match("eth_*", "eth_getLogs"));
+        assert!(glob_match("eth_*", "eth_"));
+        assert!(glob_match("*Block*", "eth_getBlockByNumber"));
+        assert!(glob_match("eth_get?ogs", "eth_getLogs"));
+        assert!(!glob_match("eth_get?ogs", "eth_getogs"));
+        // `*` has to backtrack past an earlier partial match
+        assert!(glob_match("*_by*", "eth_get_block_byNumber"));
+        assert!(!glob_match("net_*", "eth_net_version"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn parses_suppression_globs() {
+        let (pattern, (lines, suppress_type)) = parse_suppress("eth_*:3:request").unwrap();
+        assert!(matches!(pattern, Pattern::Glob(glob) if glob == "eth_*"));
+        assert_eq!((lines, suppress_type), (3, SuppressType::Request));
+        let (_, (lines, suppress_type)) = parse_suppress("/metrics").unwrap();
+        assert_eq!((lines, suppress_type), (-1, SuppressType::All));
+        let (_, (lines, suppress_type)) = parse_suppress("eth_call::response").unwrap();
+        assert_eq!((lines, suppress_type), (-1, SuppressType::Response));
+
+        assert!(parse_suppress("eth_call:x").is_err());
+        assert!(parse_suppress("eth_call:1:sometimes").is_err());
+        assert!(parse_suppress("eth_call:1:all:extra").is_err());
+    }
+
+    #[test]
+    fn parses_suppression_regexes() {
+        let regex = |arg: &str| match parse_suppress(arg) {
+            Ok((Pattern::Regex(regex), options)) => (regex.to_string(), options),
+            other => panic!("expected a regex for {}, got {:?}", arg, other),
+        };
+        assert_eq!(
+            regex("re:^eth_(call|estimateGas)$"),
+            (
+                "^eth_(call|estimateGas)$".to_string(),
+                (-1, SuppressType::All)
+            )
+        );
+        assert_eq!(
+            regex("re:^debug_:5"),
+            ("^debug_".to_string(), (5, SuppressType::All))
+        );
+        assert_eq!(
+            regex("re:^debug_:5:response"),
+            ("^debug_".to_string(), (5, SuppressType::Response))
+        );
+        // colons that don't end in LINES or TYPE belong to the regex
+        assert_eq!(
+            regex("re:^/a:b$"),
+            ("^/a:b$".to_string(), (-1, SuppressType::All))
+        );
+        assert!(parse_suppress("re:eth_(").is_err());
+    }
+
+    // what `suppress_log` makes of the request and the response of a call
+    fn suppressed(args: &[&str], method: Option<&str>, path: &str) -> [Option<(i32, String)>; 2] {
+        let args = args
+            .iter()
+            .copied()
+            .chain(["http://localhost:8545"])
+            .collect::<Vec<_>>();
+        let context = crate::tests::context(&args);
+        [PacketType::Request, PacketType::Response].map(|message_type| {
+            crate::suppress_log(
+                message_type,
+                method,
+                path,
+                PacketType::Request,
+                PacketType::Response,
+                &context,
+            )
+        })
+    }
+
+    fn hidden() -> Option<(i32, String)> {
+        Some((-1, String::new()))
+    }
+
+    #[test]
+    fn only_shows_matching_calls() {
+        let only = ["--only-method", "eth_call", "--only-method", "re:^debug_"];
+        assert_eq!(suppressed(&only, Some("eth_call"), "/"), [None, None]);
+        assert_eq!(
+            suppressed(&only, Some("debug_traceCall"), "/"),
+            [None, None]
+        );
+        assert_eq!(
+            suppressed(&only, Some("eth_chainId"), "/"),
+            [hidden(), hidden()]
+        );
+        // elements that aren't calls have no method to match
+        assert_eq!(suppressed(&only, None, "/"), [hidden(), hidden()]);
+
+        let only = ["--only-path", "/engine"];
+        assert_eq!(suppressed(&only, Some("eth_call"), "/engine"), [None, None]);
+        assert_eq!(
+            suppressed(&only, Some("eth_call"), "/"),
+            [hidden(), hidden()]
+        );
+    }
+
+    #[test]
+    fn applies_lines_and_type_of_only_rules() {
+        let only = ["--only-method", "eth_call:5:response"];
+        assert_eq!(
+            suppressed(&only, Some("eth_call"), "/"),
+            [hidden(), Some((5, "[method eth_call]".to_string()))]
+        );
+        // without LINES everything of the shown type is printed
+        let only = ["--only-method", "eth_call:-1:request"];
+        assert_eq!(suppressed(&only, Some("eth_call"), "/"), [None, hidden()]);
+        // a call must match both kinds of rules and the tighter limit wins
+        let only = ["--only-method", "eth_*:10", "--only-path", "/rpc:3"];
+        assert_eq!(
+            suppressed(&only, Some("eth_call"), "/rpc"),
+            [Some((3, "/rpc".to_string())), Some((3, "/rpc".to_string()))]
+        );
+        assert_eq!(
+            suppressed(&only, Some("eth_call"), "/"),
+            [hidden(), hidden()]
+        );
+        assert_eq!(
+            suppressed(&only, Some("net_version"), "/rpc"),
+            [hidden(), hidden()]
+        );
+    }
+
+    #[test]
+    fn suppresses_within_only_rules() {
+        let args = [
+            "--only-method",
+            "eth_*:5",
+            "-s",
+            "eth_chainId",
+            "-s",
+            "eth_call:2:response",
+        ];
+        assert_eq!(
+            suppressed(&args, Some("eth_chainId"), "/"),
+            [
+                Some((-1, "[method eth_chainId]".to_string())),
+                Some((-1, "[method eth_chainId]".to_string()))
+            ]
+        );
+        assert_eq!(
+            suppressed(&args, Some("eth_call"), "/"),
+            [
+                Some((5, "[method eth_call]".to_string())),
+                Some((2, "[method eth_call]".to_string()))
+            ]
+        );
+        assert_eq!(
+            suppressed(&args, Some("net_version"), "/"),
+            [hidden(), hidden()]
+        );
+        let args = [
+            "--only-path",
+            "/engine",
+            "--suppress-path",
+            "/engine:1:request",
+        ];
+        assert_eq!(
+            suppressed(&args, Some("engine_newPayloadV2"), "/engine"),
+            [Some((1, "/engine".to_string())), None]
+        );
+    }
+
+    #[test]
+    fn shows_dropped_messages_whatever_the_rules() {
+        let context = crate::tests::context(&[
+            "--only-method",
+            "eth_call",
+            "-s",
+            "eth_chainId",
+            "http://localhost:8545",
+        ]);
+        for method in ["eth_chainId", "net_version"] {
+            assert_eq!(
+                crate::suppress_log(
+                    PacketType::Request,
+                    Some(method),
+                    "/",
+                    PacketType::RequestDropped(12.0),
+                    PacketType::Response,
+                    &context,
+                ),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_object_ids() {
+        assert!(matches!(
+            RpcBody::parse(r#"{"jsonrpc":"2.0","id":{},"method":"m"}"#),
+            RpcBody::Single(None)
+        ));
+    }
 }
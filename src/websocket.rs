@@ -0,0 +1,703 @@
+use crate::exchange::{Exchange, WEBSOCKET_METHOD};
+use crate::utils::{ClientAddr, PacketType, RpcBody, RpcId};
+use crate::{
+    delay_tag, filtered_calls, get_random_packet_type, log_json, log_request, log_response,
+    print_event, record_exchange, sample_delay, suppress_log, SnoopContext,
+};
+use chrono::Local;
+use futures_util::{Sink, SinkExt, StreamExt};
+use hyper::http::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+// headers that belong to the client's handshake with us and must not be
+// replayed on the handshake with the upstream
+const HANDSHAKE_HEADERS: [&str; 8] = [
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "accept-encoding",
+    "content-length",
+];
+
+// how many requests a connection keeps waiting for a response to, the oldest
+// are forgotten first when a client keeps sending calls that are never answered
+const MAX_PENDING: usize = 1024;
+
+pub fn is_upgrade_request(request: &Request<Body>) -> bool {
+    let header_contains = |name: header::HeaderName, token: &str| {
+        request
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    header_contains(header::CONNECTION, "upgrade") && header_contains(header::UPGRADE, "websocket")
+}
+
+// Maps a ws(s) uri onto its http(s) equivalent
+pub fn to_http_uri(uri: &Uri) -> Uri {
+    let scheme = match uri.scheme_str() {
+        Some("wss") => "https",
+        _ => "http",
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = Some(scheme.parse().expect("should be valid scheme"));
+    Uri::from_parts(parts).expect("should be valid uri")
+}
+
+pub fn is_websocket_uri(uri: &Uri) -> bool {
+    matches!(uri.scheme_str(), Some("ws") | Some("wss"))
+}
+
+// Returns the stream along with the subprotocol the upstream picked, if any
+async fn connect_upstream(
+    ws_uri: &Uri,
+    request_path: &str,
+    source_request: &Request<Body>,
+    context: &SnoopContext,
+) -> Result<
+    (
+        WebSocketStream<MaybeTlsStream<TcpStream>>,
+        Option<HeaderValue>,
+    ),
+    tungstenite::Error,
+> {
+    let mut dest_uri = crate::utils::remove_trailing_slashes(&ws_uri.to_string()).to_string();
+    if !request_path.eq("/") {
+        dest_uri.push_str(request_path);
+    }
+    let mut upstream_request = dest_uri.into_client_request()?;
+    for (key, value) in source_request.headers() {
+        if !HANDSHAKE_HEADERS.contains(&key.as_str()) {
+            upstream_request
+                .headers_mut()
+                .insert(key.clone(), value.clone());
+        }
+    }
+    if let Some(jwt_secret) = &context.inner.jwt_secret {
+        upstream_request.headers_mut().insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", crate::jwt::mint(jwt_secret)))
+                .expect("should be valid header"),
+        );
+    }
+    let (upstream, response) = tokio_tungstenite::connect_async(upstream_request).await?;
+    let protocol = response
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .cloned();
+    Ok((upstream, protocol))
+}
+
+// Completes the handshake with the upstream first so that a failure can be
+// reported to the client as a regular http error instead of a dead socket.
+pub async fn handle_upgrade(
+    context: SnoopContext,
+    address: ClientAddr,
+    mut source_request: Request<Body>,
+    // the exchange number the chaos decisions about its frames derive from
+    sequence: u64,
+) -> Response<Body> {
+    let mut request_path = source_request.uri().path().to_string();
+    if let Some(query) = source_request.uri().query() {
+        request_path.push('?');
+        request_path.push_str(query);
+    }
+
+    let key = match source_request.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) => key.clone(),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("missing sec-websocket-key header"))
+                .unwrap();
+        }
+    };
+
+    let mut upstream = None;
+    let mut last_error = String::new();
+    let candidates = context.inner.upstreams.candidates();
+    for (candidate, ws_uri) in candidates
+        .iter()
+        .filter_map(|candidate| Some((candidate, candidate.ws_uri.as_ref()?)))
+    {
+        if !last_error.is_empty() {
+            print_event(
+                &format!(
+                    "UPSTREAM failed ({}), failing over to {}",
+                    last_error, ws_uri
+                ),
+                &context,
+            );
+        }
+        let start = Instant::now();
+        match connect_upstream(ws_uri, &request_path, &source_request, &context).await {
+            Ok((stream, protocol)) => {
+                candidate.record_success(start.elapsed());
+                upstream = Some((stream, protocol, candidate.name()));
+                break;
+            }
+            Err(e) => {
+                candidate.record_failure();
+                last_error = e.to_string();
+            }
+        }
+    }
+    let (upstream, protocol, upstream_name) = match upstream {
+        Some(connected) => connected,
+        None => {
+            print_event(
+                &format!(
+                    "WEBSOCKET FAILED {} upstream error: {}",
+                    request_path, last_error
+                ),
+                &context,
+            );
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::from(format!(
+                    "upstream websocket error: {}",
+                    last_error
+                )))
+                .unwrap();
+        }
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(&mut source_request).await {
+            Ok(upgraded) => {
+                let client = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                print_event(
+                    &format!(
+                        "WEBSOCKET OPENED {} from {} to {}",
+                        request_path, address, upstream_name
+                    ),
+                    &context,
+                );
+                relay(
+                    client,
+                    upstream,
+                    &address,
+                    &request_path,
+                    &upstream_name,
+                    sequence,
+                    &context,
+                )
+                .await;
+                print_event(
+                    &format!("WEBSOCKET CLOSED {} from {}", request_path, address),
+                    &context,
+                );
+            }
+            Err(e) => print_event(
+                &format!("WEBSOCKET FAILED {} upgrade error: {}", request_path, e),
+                &context,
+            ),
+        }
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, HeaderValue::from_static("Upgrade"))
+        .header(header::UPGRADE, HeaderValue::from_static("websocket"))
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        );
+    // the client asked the upstream for it, so it expects to hear the choice
+    if let Some(protocol) = protocol {
+        response = response.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+fn id_key(id: &RpcId) -> String {
+    serde_json::to_string(id).expect("should serialize id")
+}
+
+// A request frame forwarded to the upstream, waiting for its response
+struct SentRequest {
+    rpc_body: RpcBody,
+    // the exchange to record once the response arrives
+    exchange: Exchange,
+    start: Instant,
+    // the tags to log the request with once the response arrives, when
+    // filters hold its logging back until then
+    deferred_tags: Option<Vec<String>>,
+}
+
+// The exchange for a frame, without its response. Frames that don't answer a
+// request (e.g. subscription notifications) have a `null` request.
+fn frame_exchange(
+    address: &ClientAddr,
+    request_path: &str,
+    upstream_name: &str,
+    rpc_body: &RpcBody,
+    request_json: &str,
+    request_bytes: usize,
+) -> Exchange {
+    Exchange {
+        timestamp: Local::now(),
+        client: address.to_string(),
+        http_method: WEBSOCKET_METHOD.to_string(),
+        path: request_path.to_string(),
+        methods: rpc_body.methods(),
+        request_headers: vec![],
+        request_json: request_json.to_string(),
+        request_bytes,
+        status: None,
+        response_headers: vec![],
+        response_json: None,
+        response_bytes: None,
+        upstream: Some(upstream_name.to_string()),
+        latency: None,
+        ttfb: None,
+        request_dropped: false,
+        response_dropped: false,
+        injected: false,
+        mutation: None,
+    }
+}
+
+// Per-connection bookkeeping so responses can be paired with their requests
+// and subscription notifications with the `eth_subscribe` that created them.
+#[derive(Default)]
+struct Subscriptions {
+    // request id -> when it was tracked and the request it belongs to
+    pending: HashMap<String, (u64, Arc<SentRequest>)>,
+    // pending request ids, oldest first
+    order: VecDeque<(u64, String)>,
+    tracked: u64,
+    // subscription id -> description of the `eth_subscribe` call
+    active: HashMap<String, String>,
+}
+
+impl Subscriptions {
+    fn track_request(&mut self, request: SentRequest) {
+        let request = Arc::new(request);
+        let ids = match &request.rpc_body {
+            RpcBody::Single(Some(call)) => call.id.iter().collect::<Vec<_>>(),
+            RpcBody::Batch(calls) => calls
+                .iter()
+                .filter_map(|(_, call)| call.as_ref().and_then(|call| call.id.as_ref()))
+                .collect(),
+            RpcBody::Single(None) => vec![],
+        };
+        let keys = ids.into_iter().map(id_key).collect::<Vec<_>>();
+        for key in keys {
+            self.tracked += 1;
+            self.pending
+                .insert(key.clone(), (self.tracked, request.clone()));
+            self.order.push_back((self.tracked, key));
+        }
+        // responses may never come (e.g. the upstream dropped the call), so
+        // forget the oldest requests rather than grow without bound
+        while self.order.len() > MAX_PENDING {
+            if let Some((tracked, key)) = self.order.pop_front() {
+                // unless the id was reused by a later request
+                if matches!(self.pending.get(&key), Some((t, _)) if *t == tracked) {
+                    self.pending.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Finds and forgets the request a response (or batch of responses)
+    // answers, registering or removing subscriptions along the way.
+    fn take_request(&mut self, response: &serde_json::Value) -> Option<Arc<SentRequest>> {
+        let first = match response {
+            serde_json::Value::Array(elements) => elements.first()?,
+            element => element,
+        };
+        let key = first.get("id").map(|id| id.to_string())?;
+        let (_, request) = self.pending.get(&key)?.clone();
+
+        let calls = match &request.rpc_body {
+            RpcBody::Single(Some(call)) => vec![call],
+            RpcBody::Batch(calls) => calls.iter().filter_map(|(_, call)| call.as_ref()).collect(),
+            RpcBody::Single(None) => vec![],
+        };
+        let responses = match response {
+            serde_json::Value::Array(elements) => elements.iter().collect::<Vec<_>>(),
+            element => vec![element],
+        };
+        for call in calls {
+            let id = match call.id.as_ref() {
+                Some(id) => id_key(id),
+                None => continue,
+            };
+            self.pending.remove(&id);
+            let result = responses
+                .iter()
+                .find(|response| response.get("id").map(|id| id.to_string()) == Some(id.clone()))
+                .and_then(|response| response.get("result"));
+            let param = |i: usize| {
+                call.params
+                    .as_ref()
+                    .and_then(|params| params.get(i))
+                    .cloned()
+            };
+            match (call.method.as_str(), result) {
+                ("eth_subscribe", Some(serde_json::Value::String(subscription))) => {
+                    let kind = param(0)
+                        .and_then(|kind| kind.as_str().map(str::to_string))
+                        .unwrap_or_else(|| "unknown".to_string());
+                    self.active.insert(
+                        subscription.clone(),
+                        format!("{} via eth_subscribe id {}", kind, id),
+                    );
+                }
+                ("eth_unsubscribe", Some(serde_json::Value::Bool(true))) => {
+                    if let Some(serde_json::Value::String(subscription)) = param(0) {
+                        self.active.remove(&subscription);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(request)
+    }
+
+    fn describe(&self, notification: &serde_json::Value) -> Option<String> {
+        let subscription = notification.get("params")?.get("subscription")?.as_str()?;
+        Some(match self.active.get(subscription) {
+            Some(origin) => format!("[subscription {} {}]", subscription, origin),
+            None => format!("[subscription {}]", subscription),
+        })
+    }
+}
+
+fn pretty_print(text: &str) -> String {
+    jsonxf::pretty_print(text).unwrap_or_else(|_| text.to_string())
+}
+
+// Sends the frames of one direction in order, each after its delay. Only the
+// frames behind a delayed one are held up, as on a slow link, not the frames
+// going the other way.
+async fn write_frames<S>(
+    mut sink: S,
+    mut frames: mpsc::UnboundedReceiver<(Message, Option<Duration>)>,
+) where
+    S: Sink<Message> + Unpin,
+{
+    while let Some((message, delay)) = frames.recv().await {
+        if let Some(delay) = delay {
+            sleep(delay).await;
+        }
+        if sink.send(message).await.is_err() {
+            break;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn relay<C, U>(
+    client: WebSocketStream<C>,
+    upstream: WebSocketStream<U>,
+    address: &ClientAddr,
+    request_path: &str,
+    upstream_name: &str,
+    sequence: u64,
+    context: &SnoopContext,
+) where
+    C: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    U: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (client_sink, mut client) = client.split();
+    let (upstream_sink, mut upstream) = upstream.split();
+    let (to_client, client_frames) = mpsc::unbounded_channel();
+    let (to_upstream, upstream_frames) = mpsc::unbounded_channel();
+    let mut subscriptions = Subscriptions::default();
+    // each direction numbers its own frames, so the chaos decisions about them
+    // don't depend on how the two directions interleave
+    let (mut requests, mut responses) = (0, 0);
+    // reads and logs the frames of both directions, the writers send them on
+    let read_frames = async move {
+        loop {
+            tokio::select! {
+                message = client.next() => {
+                    let message = match message {
+                        Some(Ok(message)) => message,
+                        _ => break,
+                    };
+                    if let Message::Text(text) = &message {
+                        let request_json = pretty_print(text);
+                        let rpc_body = RpcBody::parse(&request_json);
+                        let rng = &mut context.exchange_rng(sequence, Some((PacketType::Request, requests)));
+                        requests += 1;
+                        let request_type = get_random_packet_type(PacketType::Request, rng, context);
+                        let delay = match request_type {
+                            PacketType::RequestDropped(_) => None,
+                            _ => sample_delay(PacketType::Request, &rpc_body, request_path, rng, context),
+                        };
+                        let mut exchange = frame_exchange(
+                            address,
+                            request_path,
+                            upstream_name,
+                            &rpc_body,
+                            &request_json,
+                            text.len(),
+                        );
+                        exchange.request_dropped = matches!(request_type, PacketType::RequestDropped(_));
+                        // filters may look at the response, so the request is logged along with it
+                        let defer_request_log = context.settings().filters.is_some()
+                            && !exchange.request_dropped
+                            && rpc_body.expects_response();
+                        if !defer_request_log {
+                            log_request(
+                                &rpc_body,
+                                &request_json,
+                                &[],
+                                &filtered_calls(&rpc_body, &exchange, context),
+                                request_path,
+                                request_type,
+                                PacketType::Response,
+                                &delay_tag(delay),
+                                context,
+                            );
+                        }
+                        if let PacketType::RequestDropped(_) = request_type {
+                            record_exchange(&exchange, &rpc_body, request_type, PacketType::Response, context);
+                            continue;
+                        }
+                        if rpc_body.expects_response() {
+                            subscriptions.track_request(SentRequest {
+                                rpc_body,
+                                exchange,
+                                // when it will have been forwarded
+                                start: Instant::now() + delay.unwrap_or_default(),
+                                deferred_tags: defer_request_log.then(|| delay_tag(delay)),
+                            });
+                        } else {
+                            record_exchange(&exchange, &rpc_body, request_type, PacketType::Response, context);
+                        }
+                        if to_upstream.send((message, delay)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let closing = message.is_close();
+                    if to_upstream.send((message, None)).is_err() || closing {
+                        break;
+                    }
+                }
+                message = upstream.next() => {
+                    let message = match message {
+                        Some(Ok(message)) => message,
+                        _ => break,
+                    };
+                    if let Message::Text(text) = &message {
+                        let response_json = pretty_print(text);
+                        let rng = &mut context.exchange_rng(sequence, Some((PacketType::Response, responses)));
+                        responses += 1;
+                        let response_type = get_random_packet_type(PacketType::Response, rng, context);
+                        let value = serde_json::from_str::<serde_json::Value>(text).ok();
+                        let notification = value.as_ref().filter(|value| {
+                            value.get("method").and_then(|method| method.as_str()) == Some("eth_subscription")
+                        });
+                        let mut delay = None;
+                        if let Some(notification) = notification {
+                            let rpc_body = RpcBody::parse(&response_json);
+                            let mut exchange =
+                                frame_exchange(address, request_path, upstream_name, &rpc_body, "null", 0);
+                            exchange.response_json = Some(response_json.clone());
+                            exchange.response_bytes = Some(text.len());
+                            exchange.response_dropped = matches!(response_type, PacketType::ResponseDropped(_));
+                            let hidden = filtered_calls(&rpc_body, &exchange, context);
+                            let lines = if !hidden.is_empty() && hidden.iter().all(|hidden| *hidden) {
+                                Some(-1)
+                            } else {
+                                suppress_log(
+                                    PacketType::Response,
+                                    Some("eth_subscription"),
+                                    request_path,
+                                    PacketType::Request,
+                                    response_type,
+                                    context,
+                                )
+                                .map(|(lines, _)| lines)
+                            };
+                            let msg = format!(
+                                "from {} {}",
+                                upstream_name,
+                                subscriptions
+                                    .describe(notification)
+                                    .unwrap_or_else(|| "[subscription]".to_string())
+                            );
+                            if !exchange.response_dropped {
+                                delay = sample_delay(PacketType::Response, &rpc_body, request_path, rng, context);
+                            }
+                            let msg = delay_tag(delay)
+                                .iter()
+                                .fold(msg, |msg, tag| format!("{} {}", msg, tag));
+                            log_json(&response_json, &[], response_type, &msg, None, lines, context);
+                            record_exchange(&exchange, &rpc_body, PacketType::Request, response_type, context);
+                        } else {
+                            let sent = value
+                                .as_ref()
+                                .and_then(|value| subscriptions.take_request(value));
+                            let (rpc_body, mut exchange) = match &sent {
+                                Some(sent) => {
+                                    let mut exchange = sent.exchange.clone();
+                                    exchange.latency = Some(sent.start.elapsed());
+                                    exchange.ttfb = exchange.latency;
+                                    (&sent.rpc_body, exchange)
+                                }
+                                // answers a request we no longer know about
+                                None => (
+                                    &RpcBody::Single(None),
+                                    frame_exchange(
+                                        address,
+                                        request_path,
+                                        upstream_name,
+                                        &RpcBody::Single(None),
+                                        "null",
+                                        0,
+                                    ),
+                                ),
+                            };
+                            exchange.response_json = Some(response_json.clone());
+                            exchange.response_bytes = Some(text.len());
+                            exchange.response_dropped = matches!(response_type, PacketType::ResponseDropped(_));
+                            if !exchange.response_dropped {
+                                delay = sample_delay(PacketType::Response, rpc_body, request_path, rng, context);
+                            }
+                            let hidden = filtered_calls(rpc_body, &exchange, context);
+                            if let Some(tags) = sent.as_ref().and_then(|sent| sent.deferred_tags.as_ref()) {
+                                log_request(
+                                    rpc_body,
+                                    &exchange.request_json,
+                                    &[],
+                                    &hidden,
+                                    request_path,
+                                    PacketType::Request,
+                                    response_type,
+                                    tags,
+                                    context,
+                                );
+                            }
+                            log_response(
+                                rpc_body,
+                                &response_json,
+                                &[],
+                                &hidden,
+                                None,
+                                None,
+                                Some(upstream_name),
+                                request_path,
+                                PacketType::Request,
+                                response_type,
+                                &delay_tag(delay),
+                                context,
+                            );
+                            record_exchange(&exchange, rpc_body, PacketType::Request, response_type, context);
+                        }
+                        if let PacketType::ResponseDropped(_) = response_type {
+                            continue;
+                        }
+                        if to_client.send((message, delay)).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let closing = message.is_close();
+                    if to_client.send((message, None)).is_err() || closing {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+    tokio::join!(
+        read_frames,
+        write_frames(client_sink, client_frames),
+        write_frames(upstream_sink, upstream_frames),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(id: u64, method: &str) -> SentRequest {
+        let request_json = format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":["newHeads"]}}"#,
+            id, method
+        );
+        let rpc_body = RpcBody::parse(&request_json);
+        let exchange = frame_exchange(
+            &ClientAddr::Ipc,
+            "/",
+            "ws://upstream",
+            &rpc_body,
+            &request_json,
+            request_json.len(),
+        );
+        SentRequest {
+            rpc_body,
+            exchange,
+            start: Instant::now(),
+            deferred_tags: None,
+        }
+    }
+
+    fn response(id: u64, result: &str) -> serde_json::Value {
+        serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result })
+    }
+
+    #[test]
+    fn pairs_responses_and_subscriptions() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.track_request(call(1, "eth_subscribe"));
+        assert!(subscriptions.take_request(&response(1, "0xabc")).is_some());
+        assert!(subscriptions.take_request(&response(1, "0xabc")).is_none());
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": { "subscription": "0xabc", "result": {} },
+        });
+        assert_eq!(
+            subscriptions.describe(&notification).unwrap(),
+            "[subscription 0xabc newHeads via eth_subscribe id 1]"
+        );
+    }
+
+    #[test]
+    fn forgets_the_oldest_unanswered_requests() {
+        let mut subscriptions = Subscriptions::default();
+        for id in 0..MAX_PENDING as u64 + 10 {
+            subscriptions.track_request(call(id, "eth_blockNumber"));
+        }
+        assert_eq!(subscriptions.pending.len(), MAX_PENDING);
+        assert!(subscriptions.take_request(&response(0, "0x1")).is_none());
+        assert!(subscriptions.take_request(&response(10, "0x1")).is_some());
+    }
+
+    #[test]
+    fn keeps_a_reused_id() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.track_request(call(0, "eth_blockNumber"));
+        for _ in 0..MAX_PENDING {
+            subscriptions.track_request(call(1, "eth_blockNumber"));
+        }
+        assert!(subscriptions.take_request(&response(1, "0x1")).is_some());
+    }
+}